@@ -1,13 +1,122 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Nat, Principal};
+use chrono::{Datelike, Months, NaiveDate};
+use ic_cdk::api::management_canister::http_request as outcall;
+#[cfg(not(test))]
 use ic_cdk::api::time;
+use ic_certified_map::{labeled, labeled_hash, leaf_hash, AsHashTree, Hash, RbTree};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use ic_stable_structures::{
+    BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, StableLog, Storable,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow, cell::RefCell, collections::HashMap, ops::Bound, thread::LocalKey, time::Duration,
+};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type AuditLog = StableLog<AuditEntry, Memory, Memory>;
+type EventLog = StableLog<FleetEvent, Memory, Memory>;
+type NotificationConfigCell = Cell<NotificationConfig, Memory>;
+type HoursOfServiceCell = Cell<HoursOfServiceRules, Memory>;
+type PaymentConfigCell = Cell<PaymentConfig, Memory>;
+type RollupRebuildCell = Cell<RollupRebuild, Memory>;
+
+const MAX_PAGE_SIZE: u64 = 100;
+const MAX_AUDIT_SCAN: u64 = 10_000; // audit entries read per page before returning a cursor
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const WEBHOOK_OUTCALL_CYCLES: u128 = 100_000_000_000;
+const WEBHOOK_DELIVERY_LEASE_SECS: u64 = 300;
+const WEBHOOK_BATCH_SIZE: usize = 20;
+const WEBHOOK_DELIVERY_INTERVAL_SECS: u64 = 60;
+const NO_SHOW_GRACE_SECS: u64 = 30 * 60;
+const MAINTENANCE_REMINDER_WINDOW_SECS: u64 = 3 * 24 * 60 * 60;
+const EXPIRY_WARNING_WINDOW_SECS: u64 = 30 * 24 * 60 * 60;
+const TRIP_TRACE_RETENTION_SECS: u64 = 365 * 24 * 60 * 60;
+const DAY_SECS: u64 = 24 * 60 * 60;
+const TRIP_PROPOSAL_TTL_SECS: u64 = 15 * 60;
+const LEDGER_DEDUP_WINDOW_SECS: u64 = DAY_SECS; // ICRC-1 default transaction window
+const WEEK_SECS: u64 = 7 * DAY_SECS;
+const YEAR_SECS: u64 = 365 * DAY_SECS;
+const MAX_USEFUL_LIFE_YEARS: u64 = 100;
+const MAX_HOURS_REPORT_DAYS: u64 = 366;
+const DASHBOARD_DRIVER_COUNT: usize = 5;
+const MAX_IMPORT_ROWS: usize = 500;
+const SNAPSHOT_VERSION: u32 = 2;
+const MAX_CSV_ROWS: u64 = 1000;
+const CERTIFIED_LABEL: &[u8] = b"fleet";
+const CERTIFIED_COLLECTIONS: [&str; 3] = ["vehicles", "bookings", "drivers"];
+const EARTH_RADIUS_KM: f64 = 6371.0;
+const STATION_CORRIDOR_KM: f64 = 10.0; // how far off the route a stop may be
+const RANGE_RESERVE: f64 = 0.1; // share of the range kept in reserve between stops
+const MAX_ROUTE_STOPS: usize = 10;
+const MAX_MANIFEST_ITEMS: usize = 50;
+const ROLLUP_REBUILD_BATCH_SIZE: usize = 500; // records replayed per timer tick
+const CERTIFY_BATCH_SIZE: usize = 500; // records certified per timer tick after an upgrade
+const MAX_TARIFF_SURCHARGES: usize = 10;
+const MAX_NAME_LENGTH: usize = 64; // longest accepted name, number, hash, route endpoint or stop
+const MAX_TEXT_LENGTH: usize = 128; // longest accepted location, description, reason or contact details
+
+// Documents a vehicle must hold, valid at a trip's start time, to be booked
+const MANDATORY_DOCUMENTS: [DocumentKind; 4] = [
+    DocumentKind::Registration,
+    DocumentKind::Insurance,
+    DocumentKind::Inspection,
+    DocumentKind::EmissionCertificate,
+];
+
+// Background jobs run on canister timers: (name, interval in seconds, job)
+const BACKGROUND_JOBS: [(&str, u64, BackgroundJob); 4] = [
+    ("mark_no_show_bookings", 15 * 60, mark_no_show_bookings),
+    ("check_maintenance", 60 * 60, check_maintenance),
+    (
+        "warn_expiring_documents",
+        24 * 60 * 60,
+        warn_expiring_documents,
+    ),
+    ("compact_telemetry", 24 * 60 * 60, compact_telemetry),
+];
+
+// A background job gets the current time and its previous run time, and
+// returns a short summary of what it did
+type BackgroundJob = fn(u64, Option<u64>) -> String;
+
+// Stores that snapshots export and restore, in restore order; restoring the
+// last one finishes the restore
+const SNAPSHOT_STORES: [SnapshotStore; 30] = [
+    SnapshotStore::Drivers,
+    SnapshotStore::Vehicles,
+    SnapshotStore::Bookings,
+    SnapshotStore::FuelConsumptions,
+    SnapshotStore::Maintenances,
+    SnapshotStore::EmergencyAssistances,
+    SnapshotStore::Routes,
+    SnapshotStore::Trips,
+    SnapshotStore::Users,
+    SnapshotStore::Documents,
+    SnapshotStore::Shifts,
+    SnapshotStore::LeaveRequests,
+    SnapshotStore::Proposals,
+    SnapshotStore::Customers,
+    SnapshotStore::Tariffs,
+    SnapshotStore::Invoices,
+    SnapshotStore::Payments,
+    SnapshotStore::Departments,
+    SnapshotStore::ChargingSessions,
+    SnapshotStore::Stations,
+    SnapshotStore::Manifests,
+    SnapshotStore::Subscriptions,
+    SnapshotStore::Notifications,
+    SnapshotStore::JobStatuses,
+    SnapshotStore::NotificationConfig,
+    SnapshotStore::HoursOfServiceRules,
+    SnapshotStore::PaymentConfig,
+    SnapshotStore::AuditLog,
+    SnapshotStore::EventLog,
+    SnapshotStore::Counters,
+];
 
 // Roles Enumeration
 #[derive(
@@ -39,7 +148,8 @@ struct Driver {
     name: String,
     license_number: String,
     contact_info: String,
-    points: u64,
+    license_expiry: Option<u64>,
+    points: u64, // penalty points on the driver's record; fewer is better
     created_at: u64,
 }
 
@@ -52,21 +162,119 @@ struct Vehicle {
     capacity: u64,
     status: VehicleStatus,
     location: String,
+    department_id: Option<u64>, // department owning the vehicle
+    acquisition: Option<VehicleAcquisition>,
+    profile: Option<VehicleProfile>,
+    state_of_charge: Option<u64>, // battery percentage, electric and hybrid vehicles only
+    state_of_charge_at: Option<u64>, // when the state of charge was read
     created_at: u64,
 }
 
+// Vehicle acquisition struct, depreciated on a straight line over the useful life
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct VehicleAcquisition {
+    cost: u64,
+    acquired_at: u64,
+    residual_value: u64, // expected value at the end of the useful life
+    useful_life_years: u64,
+}
+
+// Vehicle profile struct, describing what a vehicle is and can carry
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct VehicleProfile {
+    category: VehicleCategory,
+    energy_type: EnergyType,
+    passenger_seats: u64,
+    cargo_capacity_kg: u64,
+    cargo_volume_m3: f64,
+    license_class: LicenseClass, // licence class needed to drive the vehicle
+    equipment: Vec<Equipment>,
+    rated_range_km: Option<f64>,      // range on a full tank or charge
+    connector: Option<ConnectorType>, // charging connector, electric vehicles only
+}
+
+// Vehicle Category Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum VehicleCategory {
+    #[default]
+    Sedan,
+    Suv,
+    Van,
+    Truck,
+    Bus,
+    Motorcycle,
+}
+
+// Energy Type Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum EnergyType {
+    #[default]
+    Petrol,
+    Diesel,
+    Hybrid,
+    Electric,
+    Lpg,
+}
+
+// Licence Class Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum LicenseClass {
+    A, // motorcycles
+    #[default]
+    B, // cars and light vans
+    C1, // medium goods vehicles
+    C, // heavy goods vehicles
+    D1, // minibuses
+    D, // buses
+}
+
+// Equipment Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum Equipment {
+    #[default]
+    Refrigerated,
+    WheelchairLift,
+    TailLift,
+    TowBar,
+}
+
+// Connector Type Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum ConnectorType {
+    Type1,
+    #[default]
+    Type2,
+    Ccs1,
+    Ccs2,
+    Chademo,
+    Nacs,
+}
+
 // Booking struct
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Booking {
     id: u64,
     vehicle_id: u64,
-    // user_id: u64,
+    requester: Option<Principal>, // principal that made the booking
     driver_id: u64,
     from_location: String,
     to_location: String,
     start_time: u64,
     end_time: u64,
-    status: String, // "pending", "approved", "completed"
+    route_id: Option<u64>,         // planned route, if any
+    department_id: Option<u64>,    // department the booking is charged to
+    status: String, // "pending", "approved", "in_progress", "completed", "cancelled", "no_show"
+    warnings: Option<Vec<String>>, // rule violations accepted when the booking was made
     created_at: u64,
 }
 
@@ -76,9 +284,25 @@ struct FuelConsumption {
     id: u64,
     vehicle_id: u64,
     amount: f64,
+    cost: Option<u64>,
     date: u64,
 }
 
+// Charging Session struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ChargingSession {
+    id: u64,
+    vehicle_id: u64,
+    energy_kwh: f64,
+    charger_location: String,
+    start_soc: u64, // battery percentage when plugged in
+    end_soc: u64,   // battery percentage when unplugged
+    cost: Option<u64>,
+    start_time: u64,
+    end_time: u64,
+    created_at: u64,
+}
+
 // Maintenance struct
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Maintenance {
@@ -86,7 +310,8 @@ struct Maintenance {
     vehicle_id: u64,
     description: String,
     scheduled_date: u64,
-    status: String, // "pending", "completed"
+    status: String, // "pending", "overdue", "completed"
+    cost: Option<u64>,
     created_at: u64,
 }
 
@@ -98,6 +323,8 @@ struct EmergencyAssistance {
     description: String,
     location: String,
     status: String, // "pending", "resolved"
+    cost: Option<u64>,
+    resolved_at: Option<u64>,
     created_at: u64,
 }
 
@@ -110,247 +337,6594 @@ struct Route {
     optimized_route: String,
     distance: f64,
     time_estimate: u64,
+    stops: Option<Vec<RouteStop>>, // refuelling or charging stops, in order
 }
 
-// Implementing the Storable trait for the structs
-impl Storable for Driver {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Route stop struct, a planned refuelling or charging stop
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RouteStop {
+    station_id: u64,
+    coordinates: Coordinates,
+    at_km: f64, // distance from the start of the route
+    energy_type: EnergyType,
+    price: u64, // per litre or kWh at the time of planning
 }
 
-impl BoundedStorable for Driver {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Station struct, a fuel station or EV charger in the catalog
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Station {
+    id: u64,
+    name: String,
+    location: String,
+    coordinates: Coordinates,
+    energy_types: Vec<EnergyType>, // fuels sold, or Electric for chargers
+    connectors: Vec<ConnectorType>,
+    price: u64, // per litre or kWh
+    created_at: u64,
 }
 
-impl Storable for Vehicle {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Coordinates struct, in decimal degrees
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct Coordinates {
+    latitude: f64,
+    longitude: f64,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Trip struct recording what actually happened on a booking
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Trip {
+    id: u64,
+    booking_id: u64,
+    vehicle_id: u64,
+    driver_id: u64,
+    planned_route_id: Option<u64>,
+    route_taken: String,
+    start_time: u64,
+    end_time: Option<u64>,
+    start_odometer: f64,
+    end_odometer: Option<f64>,
+    distance: f64,
+    distance_deviation: Option<f64>, // distance driven minus planned route distance
+    fuel_used: f64,
+    start_delay: i64,       // actual start minus booked start_time, in nanoseconds
+    end_delay: Option<i64>, // actual end minus booked end_time, in nanoseconds
+    status: String,         // "in_progress", "completed"
+    created_at: u64,
 }
 
-impl BoundedStorable for Vehicle {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// User account struct binding a principal to a role
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UserAccount {
+    id: u64,
+    principal: Principal,
+    role: UserRole,
+    driver_id: Option<u64>, // driver record a Driver-role principal acts for
+    created_at: u64,
 }
 
-impl Storable for Booking {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Principal wrapper used as a stable map key
+#[derive(candid::CandidType, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+struct PrincipalKey(Principal);
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Audit entry struct recording a state change
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AuditEntry {
+    seq: u64,
+    caller: Principal,
+    endpoint: String,
+    entity_type: String,
+    entity_id: u64,
+    before: Option<String>, // JSON snapshot before the change
+    after: Option<String>,  // JSON snapshot after the change
+    timestamp: u64,
 }
 
-impl BoundedStorable for Booking {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Domain event kinds published on the change feed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum FleetEventKind {
+    VehicleCreated(Vehicle),
+    BookingStatusChanged {
+        booking: Booking,
+        previous_status: Option<String>,
+    },
+    MaintenanceScheduled(Maintenance),
+    EmergencyRaised(EmergencyAssistance),
+    FuelRecorded(FuelConsumption),
+    ChargingRecorded(ChargingSession),
 }
 
-impl Storable for FuelConsumption {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Fleet event struct, sequenced by its position in the change feed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FleetEvent {
+    seq: u64,
+    timestamp: u64,
+    kind: FleetEventKind,
 }
 
-impl BoundedStorable for FuelConsumption {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Topics that notification subscribers can follow
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum NotificationTopic {
+    EmergencyRaised,
+    MaintenanceScheduled,
+    MaintenanceDue,
+    MaintenanceOverdue,
+    DocumentExpiring,
+    BudgetExceeded,
 }
 
-impl Storable for Maintenance {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Subscriber of a notification, either everyone holding a role or a single principal
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum SubscriptionTarget {
+    Role(UserRole),
+    Principal(Principal),
 }
 
-impl BoundedStorable for Maintenance {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Delivery Status Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug,
+)]
+enum DeliveryStatus {
+    #[default]
+    Pending,
+    Delivered,
+    Failed,
 }
 
-impl Storable for EmergencyAssistance {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Notification config struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NotificationConfig {
+    webhook_url: String, // deliveries stay queued while this is empty
+    max_attempts: u32,
+    base_backoff_secs: u64,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            webhook_url: String::new(),
+            max_attempts: 5,
+            base_backoff_secs: 60,
+        }
     }
 }
 
-impl BoundedStorable for EmergencyAssistance {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Subscription struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Subscription {
+    id: u64,
+    target: SubscriptionTarget,
+    topics: Vec<NotificationTopic>,
+    created_at: u64,
 }
 
-impl Storable for Route {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Notification struct tracking a queued webhook delivery
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Notification {
+    id: u64,
+    subscription_id: u64,
+    recipient: SubscriptionTarget,
+    topic: NotificationTopic,
+    message: String,
+    status: DeliveryStatus,
+    attempts: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+    delivered_at: Option<u64>,
+    created_at: u64,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Vehicle Document Kind Enumeration
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum DocumentKind {
+    Registration,
+    Insurance,
+    Inspection,
+    EmissionCertificate,
 }
 
-impl BoundedStorable for Route {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Vehicle document struct, e.g. an insurance policy or inspection certificate
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VehicleDocument {
+    id: u64,
+    vehicle_id: u64,
+    kind: DocumentKind,
+    reference_number: String,
+    issuer: String,
+    cost: u64, // premium or fee, in the smallest currency unit
+    issued_at: u64,
+    expires_at: u64,
+    created_at: u64,
 }
 
-// Thread-local storage for memory managers and data structures
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+// Compliance report struct listing what a vehicle lacks
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VehicleCompliance {
+    vehicle_id: u64,
+    registration_number: String,
+    missing: Vec<DocumentKind>,       // never provided
+    expired: Vec<DocumentKind>,       // provided, but all expired by the checked time
+    not_yet_valid: Vec<DocumentKind>, // provided, but none issued yet at the checked time
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+// Rule Enforcement Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug,
+)]
+enum RuleEnforcement {
+    #[default]
+    Reject,
+    Warn,
+}
 
-    static DRIVER_STORAGE: RefCell<StableBTreeMap<u64, Driver, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+// Hours-of-service rules limiting how long a driver may drive
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct HoursOfServiceRules {
+    max_daily_driving_secs: u64,  // within any rolling 24 hours
+    max_weekly_driving_secs: u64, // within any rolling 7 days
+    min_rest_secs: u64,           // between two bookings of the same driver
+    enforcement: RuleEnforcement,
+}
 
-    static VEHICLE_STORAGE: RefCell<StableBTreeMap<u64, Vehicle, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-    ));
+impl Default for HoursOfServiceRules {
+    fn default() -> Self {
+        Self {
+            max_daily_driving_secs: 9 * 60 * 60,
+            max_weekly_driving_secs: 56 * 60 * 60,
+            min_rest_secs: 11 * 60 * 60,
+            enforcement: RuleEnforcement::Reject,
+        }
+    }
+}
 
-    static BOOKING_STORAGE: RefCell<StableBTreeMap<u64, Booking, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-    ));
+// Driving time within a single day of a driver hours report
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DailyDrivingTime {
+    day_start: u64,
+    driving_secs: u64,
+}
 
-    static FUEL_CONSUMPTION_STORAGE: RefCell<StableBTreeMap<u64, FuelConsumption, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
-    ));
+// Driver hours report struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DriverHoursReport {
+    driver_id: u64,
+    period: ReportPeriod,
+    driving_secs: u64,
+    booking_count: u64,
+    daily: Vec<DailyDrivingTime>,
+    days_over_daily_limit: u64,
+}
 
-    static MAINTENANCE_STORAGE: RefCell<StableBTreeMap<u64, Maintenance, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
-    ));
+// Leave Kind Enumeration
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum LeaveKind {
+    DayOff,
+    Leave,
+}
 
-    static EMERGENCY_ASSISTANCE_STORAGE: RefCell<StableBTreeMap<u64, EmergencyAssistance, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
-    ));
+// Leave Status Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug,
+)]
+enum LeaveStatus {
+    #[default]
+    Pending,
+    Approved,
+    Rejected,
+}
 
-    static ROUTE_STORAGE: RefCell<StableBTreeMap<u64, Route, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
-    ));
+// Driver shift struct, a window the driver is scheduled to work
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DriverShift {
+    id: u64,
+    driver_id: u64,
+    start_time: u64,
+    end_time: u64,
+    created_at: u64,
 }
 
-// Payload structs
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct DriverPayload {
-    name: String,
-    license_number: String,
-    contact_info: String,
+// Leave request struct for days off and leave, approved by a manager
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LeaveRequest {
+    id: u64,
+    driver_id: u64,
+    kind: LeaveKind,
+    start_time: u64,
+    end_time: u64,
+    reason: String,
+    status: LeaveStatus,
+    requested_by: Principal,
+    reviewed_by: Option<Principal>,
+    reviewed_at: Option<u64>,
+    created_at: u64,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct VehiclePayload {
-    registration_number: String,
-    model: String,
-    capacity: u64,
-    location: String,
+// Proposal Status Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug,
+)]
+enum ProposalStatus {
+    #[default]
+    Proposed,
+    Confirmed,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct BookingPayload {
+// Trip proposal struct, a vehicle and driver picked for a trip request
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TripProposal {
+    id: u64,
+    requester: Principal,
     vehicle_id: u64,
-    // user_id: u64,
     driver_id: u64,
     from_location: String,
     to_location: String,
     start_time: u64,
     end_time: u64,
+    score: f64,
+    status: ProposalStatus,
+    booking_id: Option<u64>,
+    expires_at: u64,
+    created_at: u64,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct FuelConsumptionPayload {
-    vehicle_id: u64,
-    amount: f64,
-    date: u64,
+// Customer struct, the profile of a principal requesting trips
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Customer {
+    id: u64,
+    principal: Principal,
+    name: String,
+    contact_info: String,
+    created_at: u64,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct MaintenancePayload {
-    vehicle_id: u64,
-    description: String,
-    scheduled_date: u64,
+// Surcharge Kind Enumeration, a flat fee or a percentage of the base, distance
+// and time charges
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum SurchargeKind {
+    Flat(u64),
+    Percent(u64),
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct EmergencyAssistancePayload {
-    vehicle_id: u64,
-    description: String,
-    location: String,
+// Surcharge struct, an extra charge applied to every booking under a tariff
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Surcharge {
+    name: String,
+    kind: SurchargeKind,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct RoutePayload {
-    from_location: String,
-    to_location: String,
+// Tariff struct pricing the bookings of a vehicle model; amounts are in the
+// smallest currency unit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Tariff {
+    id: u64,
+    vehicle_model: String,
+    base_fee: u64,
+    per_km: u64,
+    per_hour: u64, // charged for every started hour
+    surcharges: Vec<Surcharge>,
+    created_at: u64,
+    updated_at: Option<u64>,
 }
 
-// Message enum
-#[derive(candid::CandidType, Deserialize, Serialize)]
+// Line item struct of a quote or an invoice
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LineItem {
+    description: String,
+    quantity: f64,
+    unit_price: u64,
+    amount: u64,
+}
+
+// Quote struct, the estimated price of a booking
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Quote {
+    vehicle_id: u64,
+    tariff_id: u64,
+    distance: f64,
+    billed_hours: u64,
+    line_items: Vec<LineItem>,
+    total: u64,
+}
+
+// Invoice struct, issued once when a booking completes and never changed afterwards
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Invoice {
+    id: u64,
+    booking_id: u64,
+    trip_id: u64,
+    customer: Option<Principal>, // requester of the booking
+    vehicle_id: u64,
+    tariff_id: u64,
+    line_items: Vec<LineItem>,
+    total: u64,
+    issued_at: u64,
+}
+
+// Payment config struct; bookings are not paid for while no ledger is set
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PaymentConfig {
+    ledger_canister_id: Option<Principal>, // ICRC-1/ICRC-2 ledger the tokens live on
+    deposit: u64,                          // security deposit, in the token's smallest unit
+    free_cancellation_secs: u64, // cancelling at least this long before start_time is free
+    late_cancellation_fee_percent: u64, // share of the deposit kept on later cancellations
+}
+
+impl Default for PaymentConfig {
+    fn default() -> Self {
+        Self {
+            ledger_canister_id: None,
+            deposit: 0,
+            free_cancellation_secs: DAY_SECS,
+            late_cancellation_fee_percent: 50,
+        }
+    }
+}
+
+// Payment Status Enumeration
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug,
+)]
+enum PaymentStatus {
+    #[default]
+    Processing, // a ledger transfer is in flight
+    DepositHeld,
+    Settled,
+    Failed, // the last settlement attempt failed; see last_error
+}
+
+// Cargo manifest struct listing the load of a booking
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CargoManifest {
+    booking_id: u64,
+    items: Vec<CargoItem>,
+    total_weight_kg: f64,
+    total_volume_m3: f64,
+    created_at: u64,
+}
+
+// Cargo item struct, one line of a manifest
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CargoItem {
+    id: u64, // position in the manifest, from 1
+    description: String,
+    quantity: u64,
+    weight_kg: f64, // for the whole quantity
+    volume_m3: f64, // for the whole quantity
+    hazardous: bool,
+    hazard_class: Option<String>, // e.g. UN class "3" for flammable liquids
+    pickup_stop: String,
+    dropoff_stop: String,
+    delivery: Option<ProofOfDelivery>,
+}
+
+// Proof of delivery struct for a cargo item
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProofOfDelivery {
+    recipient: String,
+    evidence: Option<String>, // e.g. a hash of the signature or photo
+    recorded_by: Principal,
+    delivered_at: u64,
+}
+
+// Booking payment struct tracking the deposit and settlement of a booking
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BookingPayment {
+    booking_id: u64,
+    payer: Principal,
+    deposit: u64,
+    amount_due: Option<u64>, // what the fleet keeps, known once the booking ends
+    collected: u64,          // taken from the payer so far
+    refunded: u64,           // returned to the payer so far
+    fees_paid: Option<u64>,  // ledger fees the canister paid on refunds
+    status: PaymentStatus,
+    ledger_blocks: Vec<u64>,
+    last_error: Option<String>,
+    created_at_time: Option<u64>, // of the transfer in flight or last failed
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Department struct, a cost centre that owns vehicles and is charged for bookings
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Department {
+    id: u64,
+    name: String,
+    cost_centre: String,
+    monthly_budget: u64,               // 0 means no budget
+    budget_alerted_month: Option<u32>, // yyyymm of the last over-budget alert
+    created_at: u64,
+}
+
+// Department cost report struct for one calendar month
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DepartmentCostReport {
+    department_id: u64,
+    period: ReportPeriod,
+    fuel_cost: u64,
+    charging_cost: u64,
+    maintenance_cost: u64,
+    trip_cost: u64, // invoices of the bookings charged to the department
+    total: u64,
+    monthly_budget: u64,
+    over_budget: bool,
+}
+
+// Total cost of ownership struct of a vehicle over its life so far
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VehicleTco {
+    vehicle_id: u64,
+    registration_number: String,
+    depreciation: u64,
+    fuel_cost: u64,
+    charging_cost: u64,
+    maintenance_cost: u64,
+    insurance_cost: u64,
+    incident_cost: u64, // resolved emergencies
+    total: u64,
+    kilometres: f64,
+    utilized_hours: f64,
+    cost_per_km: Option<f64>,   // none until the vehicle has been driven
+    cost_per_hour: Option<f64>, // none until the vehicle has been driven
+}
+
+// Vehicle utilization struct, booked hours against the hours in a period
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VehicleUtilization {
+    vehicle_id: u64,
+    registration_number: String,
+    driven_hours: f64, // on trips started within the period
+    utilization_percent: f64,
+}
+
+// Vehicle status count struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VehicleStatusCount {
+    status: VehicleStatus,
+    count: u64,
+}
+
+// Fleet dashboard struct with the KPIs of a period
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FleetDashboard {
+    period: ReportPeriod,
+    utilization: Vec<VehicleUtilization>,
+    fleet_utilization_percent: f64,
+    vehicles_by_status: Vec<VehicleStatusCount>, // current
+    pending_bookings: u64,
+    completed_bookings: u64,
+    open_emergencies: u64,            // current
+    overdue_maintenance: u64,         // current
+    average_fuel_per_km: Option<f64>, // none when no trip was driven
+    top_drivers: Vec<Driver>,         // fewest penalty points first
+    bottom_drivers: Vec<Driver>,      // most penalty points first, none of the top drivers
+}
+
+// Rollup Scope Enumeration, the kind of entity a rollup aggregates for
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum RollupScope {
+    Vehicle,
+    Driver,
+    Department,
+}
+
+// Rollup Metric Enumeration
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum RollupMetric {
+    FuelLitres,
+    FuelCost,
+    MaintenanceCost,
+    TripCost, // invoiced
+    Distance,
+    DrivenHours,
+    EnergyKwh,
+    ChargingCost,
+    InsuranceCost,     // premiums of insurance documents, when issued
+    IncidentCost,      // when the emergency was resolved
+    OpenBookings,      // pending or approved, by start time
+    CompletedBookings, // by start time
+}
+
+// Rollup Granularity Enumeration
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum RollupGranularity {
+    Day,
+    Week, // starting on Monday (UTC)
+    Month,
+}
+
+// Rollup key struct, encoded big-endian in a fixed size so that the buckets of
+// one series sit next to each other in time order
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct RollupKey {
+    scope: u8,
+    entity_id: u64,
+    metric: u8,
+    granularity: u8,
+    bucket_start: u64,
+}
+
+impl RollupKey {
+    fn new(
+        scope: RollupScope,
+        entity_id: u64,
+        metric: RollupMetric,
+        granularity: RollupGranularity,
+        bucket_start: u64,
+    ) -> Self {
+        Self {
+            scope: scope as u8,
+            entity_id,
+            metric: metric as u8,
+            granularity: granularity as u8,
+            bucket_start,
+        }
+    }
+}
+
+// Rollup value struct, the running total and count of a bucket
+#[derive(Clone, Copy, Default)]
+struct RollupValue {
+    total: f64,
+    count: u64,
+}
+
+// Rollup point struct, one bucket of a rollup series
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RollupPoint {
+    bucket_start: u64,
+    total: f64,
+    count: u64,
+}
+
+// Rollup Rebuild Stage Enumeration, in the order a rebuild replays the stores
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug,
+)]
+enum RollupRebuildStage {
+    Clear,
+    Fuel,
+    Charging,
+    Maintenance,
+    Trips,
+    Documents,
+    Incidents,
+    Invoices,
+    Bookings,
+}
+
+// Rollup rebuild struct, the progress of a rebuild running in batches on a timer
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct RollupRebuild {
+    stage: Option<RollupRebuildStage>, // none when no rebuild is running
+    next_id: u64,                      // first record of the stage still to replay
+    replayed: u64,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+}
+
+// Import Format Enumeration
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum ImportFormat {
+    Csv,  // a header line, then one record per line
+    Json, // an array of payload objects
+}
+
+// Import row error struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ImportRowError {
+    row: u64,
+    message: String,
+}
+
+// Bulk import report struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BulkImportReport {
+    rows: u64,
+    valid: u64,
+    imported: Vec<u64>, // IDs created; empty on a dry run or a rejected all-or-nothing import
+    errors: Vec<ImportRowError>,
+    dry_run: bool,
+}
+
+// Snapshot Store Enumeration
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum SnapshotStore {
+    Drivers,
+    Vehicles,
+    Bookings,
+    FuelConsumptions,
+    Maintenances,
+    EmergencyAssistances,
+    Routes,
+    Trips,
+    Users,
+    Documents,
+    Shifts,
+    LeaveRequests,
+    Proposals,
+    Customers,
+    Tariffs,
+    Invoices,
+    Payments,
+    Departments,
+    ChargingSessions,
+    Stations,
+    Manifests,
+    Subscriptions,
+    Notifications,
+    JobStatuses,
+    NotificationConfig,
+    HoursOfServiceRules,
+    PaymentConfig,
+    AuditLog,
+    EventLog,
+    Counters, // the ID counter
+}
+
+// Snapshot chunk struct, a page of one store's records
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SnapshotChunk {
+    version: u32,
+    store: SnapshotStore,
+    data: Vec<u8>, // Candid-encoded (key, record) pairs, (index, entry) pairs of a log, or a value
+    records: u64,
+    checksum: String,      // hex SHA-256 of the version, store and data
+    next: Option<Vec<u8>>, // start_after of the next chunk; none at the end of the store
+}
+
+// Background job name wrapper used as a stable map key
+#[derive(candid::CandidType, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+struct JobName(String);
+
+// Job status struct reporting on a background job
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct JobStatus {
+    name: String,
+    interval_secs: u64,
+    runs: u64,
+    last_run: Option<u64>,
+    last_outcome: Option<String>,
+}
+
+// Implementing the Storable trait for the structs
+impl Storable for Driver {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Driver {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Vehicle {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Vehicle {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Booking {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Booking {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for FuelConsumption {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FuelConsumption {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ChargingSession {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ChargingSession {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Station {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Station {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Maintenance {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Maintenance {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for EmergencyAssistance {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EmergencyAssistance {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Route {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Route {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for UserAccount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for UserAccount {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(&bytes))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for FleetEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for NotificationConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Subscription {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Subscription {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for VehicleDocument {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for VehicleDocument {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for HoursOfServiceRules {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for RollupRebuild {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for DriverShift {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DriverShift {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for LeaveRequest {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LeaveRequest {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for TripProposal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TripProposal {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Customer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Customer {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Tariff {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Tariff {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Invoice {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Invoice {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PaymentConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for BookingPayment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BookingPayment {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CargoManifest {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CargoManifest {
+    const MAX_SIZE: u32 = 32 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Department {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Department {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RollupKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(Self::MAX_SIZE as usize);
+        bytes.push(self.scope);
+        bytes.extend_from_slice(&self.entity_id.to_be_bytes());
+        bytes.push(self.metric);
+        bytes.push(self.granularity);
+        bytes.extend_from_slice(&self.bucket_start.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let u64_at = |at: usize| u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+        Self {
+            scope: bytes[0],
+            entity_id: u64_at(1),
+            metric: bytes[9],
+            granularity: bytes[10],
+            bucket_start: u64_at(11),
+        }
+    }
+}
+
+impl BoundedStorable for RollupKey {
+    const MAX_SIZE: u32 = 19;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for RollupValue {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(Self::MAX_SIZE as usize);
+        bytes.extend_from_slice(&self.total.to_be_bytes());
+        bytes.extend_from_slice(&self.count.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            total: f64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            count: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for RollupValue {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for JobName {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        JobName(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for JobName {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for JobStatus {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for JobStatus {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Trip {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Trip {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Thread-local storage for memory managers and data structures
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static DRIVER_STORAGE: RefCell<StableBTreeMap<u64, Driver, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    static VEHICLE_STORAGE: RefCell<StableBTreeMap<u64, Vehicle, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    static BOOKING_STORAGE: RefCell<StableBTreeMap<u64, Booking, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static FUEL_CONSUMPTION_STORAGE: RefCell<StableBTreeMap<u64, FuelConsumption, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    static MAINTENANCE_STORAGE: RefCell<StableBTreeMap<u64, Maintenance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    static EMERGENCY_ASSISTANCE_STORAGE: RefCell<StableBTreeMap<u64, EmergencyAssistance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    static ROUTE_STORAGE: RefCell<StableBTreeMap<u64, Route, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    static TRIP_STORAGE: RefCell<StableBTreeMap<u64, Trip, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // Booking ID -> ID of the trip driven for it
+    static TRIP_INDEX: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    static USER_STORAGE: RefCell<StableBTreeMap<PrincipalKey, UserAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static AUDIT_LOG: RefCell<AuditLog> = RefCell::new(
+        AuditLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+        .expect("Cannot create the audit log")
+    );
+
+    static EVENT_LOG: RefCell<EventLog> = RefCell::new(
+        EventLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+        .expect("Cannot create the event log")
+    );
+
+    static NOTIFICATION_CONFIG: RefCell<NotificationConfigCell> = RefCell::new(
+        NotificationConfigCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+            NotificationConfig::default(),
+        )
+        .expect("Cannot create the notification config")
+    );
+
+    static SUBSCRIPTION_STORAGE: RefCell<StableBTreeMap<u64, Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    static NOTIFICATION_STORAGE: RefCell<StableBTreeMap<u64, Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static JOB_STATUS_STORAGE: RefCell<StableBTreeMap<JobName, JobStatus, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static DOCUMENT_STORAGE: RefCell<StableBTreeMap<u64, VehicleDocument, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    static HOURS_OF_SERVICE_RULES: RefCell<HoursOfServiceCell> = RefCell::new(
+        HoursOfServiceCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            HoursOfServiceRules::default(),
+        )
+        .expect("Cannot create the hours-of-service rules")
+    );
+
+    static SHIFT_STORAGE: RefCell<StableBTreeMap<u64, DriverShift, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    static LEAVE_STORAGE: RefCell<StableBTreeMap<u64, LeaveRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    static PROPOSAL_STORAGE: RefCell<StableBTreeMap<u64, TripProposal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    static CUSTOMER_STORAGE: RefCell<StableBTreeMap<u64, Customer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static TARIFF_STORAGE: RefCell<StableBTreeMap<u64, Tariff, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    static INVOICE_STORAGE: RefCell<StableBTreeMap<u64, Invoice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    static PAYMENT_CONFIG: RefCell<PaymentConfigCell> = RefCell::new(
+        PaymentConfigCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+            PaymentConfig::default(),
+        )
+        .expect("Cannot create the payment config")
+    );
+
+    static PAYMENT_STORAGE: RefCell<StableBTreeMap<u64, BookingPayment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    static DEPARTMENT_STORAGE: RefCell<StableBTreeMap<u64, Department, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    static ROLLUP_STORAGE: RefCell<StableBTreeMap<RollupKey, RollupValue, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    static CHARGING_STORAGE: RefCell<StableBTreeMap<u64, ChargingSession, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static STATION_STORAGE: RefCell<StableBTreeMap<u64, Station, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    // Keyed by booking ID
+    static MANIFEST_STORAGE: RefCell<StableBTreeMap<u64, CargoManifest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    static ROLLUP_REBUILD: RefCell<RollupRebuildCell> = RefCell::new(
+        RollupRebuildCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+            RollupRebuild::default(),
+        )
+        .expect("Cannot create the rollup rebuild state")
+    );
+
+    // Rebuilt from the stores after an upgrade
+    static CERTIFIED_TREE: RefCell<RbTree<String, Hash>> =
+        const { RefCell::new(RbTree::new()) };
+
+    // Collection and first ID the certified tree's rebuild goes on from; none
+    // once the tree is complete
+    static CERTIFICATION_REBUILD: RefCell<Option<(usize, u64)>> = const { RefCell::new(None) };
+}
+
+// Payload structs
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DriverPayload {
+    name: String,
+    license_number: String,
+    contact_info: String,
+    license_expiry: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct VehiclePayload {
+    registration_number: String,
+    model: String,
+    capacity: u64,
+    location: String,
+    department_id: Option<u64>,
+    acquisition: Option<VehicleAcquisition>,
+    profile: Option<VehicleProfile>,
+}
+
+// Filter for availability searches; every criterion given must match, and
+// vehicles without a profile only match an empty filter
+#[derive(candid::CandidType, Clone, Default, Deserialize, Serialize)]
+struct VehicleFilter {
+    category: Option<VehicleCategory>,
+    energy_type: Option<EnergyType>,
+    min_passenger_seats: Option<u64>,
+    min_cargo_capacity_kg: Option<u64>,
+    license_class: Option<LicenseClass>,
+    equipment: Vec<Equipment>, // all of these must be fitted
+    location: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct BookingPayload {
+    vehicle_id: u64,
+    driver_id: u64,
+    from_location: String,
+    to_location: String,
+    start_time: u64,
+    end_time: u64,
+    route_id: Option<u64>,
+    department_id: Option<u64>, // defaults to the vehicle's department
+    manifest: Option<Vec<CargoItemPayload>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CargoItemPayload {
+    description: String,
+    quantity: u64,
+    weight_kg: f64,
+    volume_m3: f64,
+    hazardous: bool,
+    hazard_class: Option<String>,
+    pickup_stop: String,
+    dropoff_stop: String,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DeliveryPayload {
+    booking_id: u64,
+    item_id: u64,
+    recipient: String,
+    evidence: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ChargingSessionPayload {
+    vehicle_id: u64,
+    energy_kwh: f64,
+    charger_location: String,
+    start_soc: u64,
+    end_soc: u64,
+    cost: Option<u64>,
+    start_time: u64,
+    end_time: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FuelConsumptionPayload {
+    vehicle_id: u64,
+    amount: f64,
+    cost: Option<u64>,
+    date: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MaintenancePayload {
+    vehicle_id: u64,
+    description: String,
+    scheduled_date: u64,
+    cost: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct EmergencyAssistancePayload {
+    vehicle_id: u64,
+    description: String,
+    location: String,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct RoutePayload {
+    from_location: String,
+    to_location: String,
+    from_coordinates: Option<Coordinates>,
+    to_coordinates: Option<Coordinates>,
+    vehicle_id: Option<u64>, // plan refuelling or charging stops for this vehicle
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct StationPayload {
+    name: String,
+    location: String,
+    coordinates: Coordinates,
+    energy_types: Vec<EnergyType>,
+    connectors: Vec<ConnectorType>,
+    price: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct RoleAssignmentPayload {
+    principal: Principal,
+    role: UserRole,
+    driver_id: Option<u64>, // only for the Driver role
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct AuditLogFilter {
+    entity_type: Option<String>,
+    entity_id: Option<u64>,
+    caller: Option<Principal>,
+    from: Option<u64>,
+    to: Option<u64>,
+    before_seq: Option<u64>, // continue below this sequence number; None starts at the newest
+    limit: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct AuditLogPage {
+    entries: Vec<AuditEntry>,
+    next_seq: Option<u64>, // pass as 'before_seq' for the next page; None once the log is exhausted
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CsvExportFilter {
+    from: Option<u64>,
+    to: Option<u64>,
+    vehicle_id: Option<u64>,
+    driver_id: Option<u64>,   // bookings only
+    start_after: Option<u64>, // ID of the last record already exported
+    limit: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CsvPage {
+    csv: String, // the header row is only on the first page, so pages concatenate
+    rows: u64,
+    next_start_after: Option<u64>, // none on the last page
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct GatewayRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct GatewayResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct Certification {
+    key: String,          // e.g. "vehicles/42"
+    certificate: Vec<u8>, // the subnet's certificate over the canister's certified data
+    witness: Vec<u8>,     // CBOR hash tree proving the record's hash under "fleet"
+    record: Vec<u8>,      // the Candid message whose SHA-256 is the record's leaf
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CertifiedVehicle {
+    vehicle: Vehicle,
+    certification: Certification,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CertifiedBooking {
+    booking: Booking,
+    certification: Certification,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CertifiedDriver {
+    driver: Driver,
+    certification: Certification,
+}
+
+#[derive(Serialize)]
+struct HttpPage<T> {
+    items: Vec<T>,
+    offset: u64,
+    limit: u64,
+    total: u64,
+    next_offset: Option<u64>, // none on the last page
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct EventPage {
+    events: Vec<FleetEvent>,
+    next_seq: u64, // pass back to get_events_since to continue
+}
+
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize)]
+struct ReportPeriod {
+    start: u64,
+    end: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DriverShiftPayload {
+    driver_id: u64,
+    start_time: u64,
+    end_time: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct LeaveRequestPayload {
+    driver_id: u64,
+    kind: LeaveKind,
+    start_time: u64,
+    end_time: u64,
+    reason: String,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DepartmentPayload {
+    name: String,
+    cost_centre: String,
+    monthly_budget: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CustomerPayload {
+    name: String,
+    contact_info: String,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct TariffPayload {
+    vehicle_model: String,
+    base_fee: u64,
+    per_km: u64,
+    per_hour: u64,
+    surcharges: Vec<Surcharge>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct QuotePayload {
+    vehicle_id: u64,
+    route_id: u64,
+    start_time: u64,
+    end_time: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct TripRequestPayload {
+    from_location: String,
+    to_location: String,
+    start_time: u64,
+    end_time: u64,
+    passenger_count: u64,
+    cargo_count: u64, // kilograms, checked against the profile's cargo capacity
+    requirements: Option<VehicleFilter>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct VehicleDocumentPayload {
+    vehicle_id: u64,
+    kind: DocumentKind,
+    reference_number: String,
+    issuer: String,
+    cost: u64,
+    issued_at: u64,
+    expires_at: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct BulkImportPayload {
+    format: ImportFormat,
+    data: String,   // at most MAX_IMPORT_ROWS records; CSV chunks repeat the header
+    first_row: u64, // number of the chunk's first record in the file
+    dry_run: bool,
+    all_or_nothing: bool,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct SubscriptionPayload {
+    target: SubscriptionTarget,
+    topics: Vec<NotificationTopic>, // empty means every topic
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct StartTripPayload {
+    booking_id: u64,
+    start_odometer: f64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CompleteTripPayload {
+    booking_id: u64,
+    end_odometer: f64,
+    fuel_used: f64,
+    route_taken: String,
+}
+
+// Message enum
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
 enum Message {
     Success(String),
     Error(String),
     NotFound(String),
     InvalidPayload(String),
+    Unauthorized(String),
+}
+
+impl Message {
+    // Helper function to get the text a message carries, whatever its kind
+    fn text(self) -> String {
+        match self {
+            Message::Success(text)
+            | Message::Error(text)
+            | Message::NotFound(text)
+            | Message::InvalidPayload(text)
+            | Message::Unauthorized(text) => text,
+        }
+    }
+}
+
+// Function to create a new driver
+#[ic_cdk::update]
+fn create_driver(payload: DriverPayload) -> Result<Driver, Message> {
+    validate_driver(&payload)?;
+    let driver = insert_driver("create_driver", payload);
+    Ok(driver)
+}
+
+
+// Function to get all drivers
+#[ic_cdk::query]
+fn get_drivers() -> Result<Vec<Driver>, Message> {
+    DRIVER_STORAGE.with(|storage| {
+        let drivers: Vec<Driver> = storage
+            .borrow()
+            .iter()
+            .map(|(_, driver)| driver.clone())
+            .collect();
+        if drivers.is_empty() {
+            Err(Message::NotFound("No drivers found".to_string()))
+        } else {
+            Ok(drivers)
+        }
+    })
+}
+
+// Function to get a driver by ID
+#[ic_cdk::query]
+fn get_driver_by_id(id: u64) -> Result<Driver, Message> {
+    DRIVER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Driver not found".to_string()))
+    })
+}
+
+// Function to create a new vehicle
+#[ic_cdk::update]
+fn create_vehicle(payload: VehiclePayload) -> Result<Vehicle, Message> {
+    validate_vehicle(&payload)?;
+    Ok(insert_vehicle("create_vehicle", payload))
+}
+
+// Function to get all vehicles
+#[ic_cdk::query]
+fn get_vehicles() -> Result<Vec<Vehicle>, Message> {
+    VEHICLE_STORAGE.with(|storage| {
+        let vehicles: Vec<Vehicle> = storage
+            .borrow()
+            .iter()
+            .map(|(_, vehicle)| vehicle.clone())
+            .collect();
+        if vehicles.is_empty() {
+            Err(Message::NotFound("No vehicles found".to_string()))
+        } else {
+            Ok(vehicles)
+        }
+    })
+}
+
+// Function to get a vehicle by ID
+#[ic_cdk::query]
+fn get_vehicle_by_id(id: u64) -> Result<Vehicle, Message> {
+    VEHICLE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Vehicle not found".to_string()))
+    })
+}
+
+// Function to create a new booking, collecting its security deposit when
+// payments are configured
+#[ic_cdk::update]
+async fn create_booking(payload: BookingPayload) -> Result<Booking, Message> {
+    if caller() == Principal::anonymous() {
+        return Err(Message::Unauthorized(
+            "Anonymous callers cannot make bookings.".to_string(),
+        ));
+    }
+
+    // Validate the booking payload
+    if payload.from_location.is_empty()
+        || payload.to_location.is_empty()
+        || payload.end_time <= payload.start_time
+    {
+        return Err(Message::InvalidPayload("Ensure 'from_location', 'to_location', and valid 'start_time' and 'end_time' are provided.".to_string()));
+    }
+    if payload.from_location.len() > MAX_NAME_LENGTH || payload.to_location.len() > MAX_NAME_LENGTH
+    {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'from_location' and 'to_location' are at most {} bytes.",
+            MAX_NAME_LENGTH
+        )));
+    }
+
+    // Validate the driver ID
+    let driver = DRIVER_STORAGE.with(|storage| storage.borrow().get(&payload.driver_id));
+
+    if driver.is_none() {
+        return Err(Message::NotFound("Driver not found".to_string()));
+    }
+
+    // Validate the vehicle ID
+    let vehicle = VEHICLE_STORAGE.with(|storage| storage.borrow().get(&payload.vehicle_id));
+
+    if vehicle.is_none() {
+        return Err(Message::NotFound("Vehicle not found".to_string()));
+    }
+
+    let vehicle = vehicle.unwrap();
+    if vehicle.status != VehicleStatus::Available {
+        return Err(Message::InvalidPayload(
+            "Vehicle is not available.".to_string(),
+        ));
+    }
+
+    // Validate the vehicle's documents for the trip; documents that were never
+    // provided only warn, so vehicles can be booked while their paperwork is filed
+    let mut warnings = Vec::new();
+    let compliance = check_vehicle_compliance(&vehicle, payload.start_time);
+    if !compliance.expired.is_empty() || !compliance.not_yet_valid.is_empty() {
+        return Err(Message::InvalidPayload(format!(
+            "Vehicle is not compliant at 'start_time': expired {:?}, not yet valid {:?}.",
+            compliance.expired, compliance.not_yet_valid
+        )));
+    }
+    if !compliance.missing.is_empty() {
+        warnings.push(format!(
+            "Vehicle has no {:?} on record.",
+            compliance.missing
+        ));
+    }
+
+    // Validate the cargo against the vehicle's capacity, if a manifest was given
+    if let Some(items) = &payload.manifest {
+        validate_manifest(&vehicle, items)?;
+    }
+
+    // Validate the planned route, if one was given
+    let route = match payload.route_id {
+        Some(route_id) => Some(
+            ROUTE_STORAGE
+                .with(|storage| storage.borrow().get(&route_id))
+                .ok_or(Message::NotFound("Route not found".to_string()))?,
+        ),
+        None => None,
+    };
+
+    // Validate the department charged, if one was given
+    if let Some(department_id) = payload.department_id {
+        if !DEPARTMENT_STORAGE.with(|storage| storage.borrow().contains_key(&department_id)) {
+            return Err(Message::NotFound("Department not found".to_string()));
+        }
+    }
+
+    // Validate the driver's calendar
+    if let Err(reason) =
+        check_driver_calendar(payload.driver_id, payload.start_time, payload.end_time)
+    {
+        return Err(Message::InvalidPayload(reason));
+    }
+
+    // Validate the driver's hours of service
+    let violations =
+        check_hours_of_service(payload.driver_id, payload.start_time, payload.end_time)?;
+    if !violations.is_empty() {
+        let rules = HOURS_OF_SERVICE_RULES.with(|cell| cell.borrow().get().clone());
+        if rules.enforcement == RuleEnforcement::Reject {
+            return Err(Message::InvalidPayload(violations.join(" ")));
+        }
+        warnings.extend(violations);
+    }
+
+    // Warn when the planned route is longer than an electric vehicle's remaining range
+    if let (Some(route), Some(range)) = (&route, estimated_range_km(&vehicle)) {
+        if route.distance > range {
+            warnings.push(format!(
+                "Route distance of {:.1} km exceeds the vehicle's estimated remaining range of {:.1} km.",
+                route.distance, range
+            ));
+        }
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let booking = Booking {
+        id,
+        vehicle_id: payload.vehicle_id,
+        requester: Some(caller()),
+        driver_id: payload.driver_id,
+        from_location: payload.from_location,
+        to_location: payload.to_location,
+        start_time: payload.start_time,
+        end_time: payload.end_time,
+        route_id: payload.route_id,
+        department_id: payload.department_id.or(vehicle.department_id),
+        status: "pending".to_string(),
+        warnings: (!warnings.is_empty()).then_some(warnings),
+        created_at: current_time(),
+    };
+
+    // Update vehicle status to Booked
+    let vehicle_before = snapshot(&vehicle);
+    let updated_vehicle = Vehicle {
+        status: VehicleStatus::Booked,
+        ..vehicle
+    };
+    VEHICLE_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(vehicle.id, updated_vehicle.clone())
+    });
+    certify("vehicles", vehicle.id, &updated_vehicle);
+
+    BOOKING_STORAGE.with(|storage| storage.borrow_mut().insert(id, booking.clone()));
+    certify("bookings", id, &booking);
+    rollup_booking_change(&booking, None);
+    record_audit(
+        "create_booking",
+        "booking",
+        id,
+        None,
+        Some(snapshot(&booking)),
+    );
+    if let Some(items) = payload.manifest {
+        let manifest = build_manifest(id, items);
+        MANIFEST_STORAGE.with(|storage| storage.borrow_mut().insert(id, manifest.clone()));
+        record_audit(
+            "create_booking",
+            "cargo_manifest",
+            id,
+            None,
+            Some(snapshot(&manifest)),
+        );
+    }
+    emit_event(FleetEventKind::BookingStatusChanged {
+        booking: booking.clone(),
+        previous_status: None,
+    });
+    record_audit(
+        "create_booking",
+        "vehicle",
+        updated_vehicle.id,
+        Some(vehicle_before),
+        Some(snapshot(&updated_vehicle)),
+    );
+
+    match payment_ledger() {
+        Some(ledger) => collect_deposit(&ledger, booking, caller()).await,
+        None => Ok(booking),
+    }
+}
+
+// Function to get all bookings; plain users only see their own
+#[ic_cdk::query]
+fn get_bookings() -> Result<Vec<Booking>, Message> {
+    BOOKING_STORAGE.with(|storage| {
+        let bookings: Vec<Booking> = storage
+            .borrow()
+            .iter()
+            .map(|(_, booking)| booking.clone())
+            .filter(can_access_booking)
+            .collect();
+        if bookings.is_empty() {
+            Err(Message::NotFound("No bookings found".to_string()))
+        } else {
+            Ok(bookings)
+        }
+    })
+}
+
+// Function to get a booking by ID
+#[ic_cdk::query]
+fn get_booking_by_id(id: u64) -> Result<Booking, Message> {
+    let booking = BOOKING_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Booking not found".to_string()))
+    })?;
+    if !can_access_booking(&booking) {
+        return Err(Message::Unauthorized(
+            "Caller is not allowed to view this booking.".to_string(),
+        ));
+    }
+    Ok(booking)
+}
+
+// Function to get the bookings requested by the caller
+#[ic_cdk::query]
+fn get_my_bookings() -> Result<Vec<Booking>, Message> {
+    let caller = caller();
+    BOOKING_STORAGE.with(|storage| {
+        let bookings: Vec<Booking> = storage
+            .borrow()
+            .iter()
+            .map(|(_, booking)| booking)
+            .filter(|booking| booking.requester == Some(caller))
+            .collect();
+        if bookings.is_empty() {
+            Err(Message::NotFound("No bookings found".to_string()))
+        } else {
+            Ok(bookings)
+        }
+    })
+}
+
+// Function to cancel a booking that has not started, refunding its deposit
+// under the cancellation policy; plain users may only cancel their own
+#[ic_cdk::update]
+async fn cancel_booking(id: u64) -> Result<Booking, Message> {
+    let booking = BOOKING_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Booking not found".to_string()))?;
+
+    if !can_access_booking(&booking) {
+        return Err(Message::Unauthorized(
+            "Caller is not allowed to cancel this booking.".to_string(),
+        ));
+    }
+    if booking.status != "pending" && booking.status != "approved" {
+        return Err(Message::InvalidPayload(format!(
+            "Booking cannot be cancelled while '{}'.",
+            booking.status
+        )));
+    }
+    if payment_in_flight(id) {
+        return Err(Message::InvalidPayload(
+            "The booking's payment is being processed; try again shortly.".to_string(),
+        ));
+    }
+
+    let cancelled = close_booking("cancel_booking", booking, "cancelled");
+
+    // Keep the late cancellation fee out of the deposit and refund the rest
+    if let Some(payment) = PAYMENT_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        let config = PAYMENT_CONFIG.with(|cell| cell.borrow().get().clone());
+        let free_until = cancelled
+            .start_time
+            .saturating_sub(config.free_cancellation_secs * NANOS_PER_SECOND);
+        let fee = if current_time() <= free_until {
+            0
+        } else {
+            (payment.deposit as u128 * config.late_cancellation_fee_percent as u128 / 100) as u64
+        };
+        let payment = set_amount_due("cancel_booking", payment, fee);
+        if let Some(ledger) = payment_ledger() {
+            // A failed refund stays on the payment record for retry_payment_settlement
+            settle_payment(&ledger, "cancel_booking", payment).await;
+        }
+    }
+    Ok(cancelled)
+}
+
+// Helper function to tell whether the caller may see and cancel a booking:
+// admins and managers may handle any booking, everyone else only their own,
+// and anonymous callers none
+fn can_access_booking(booking: &Booking) -> bool {
+    let caller = caller();
+    matches!(caller_role(), UserRole::Admin | UserRole::Manager)
+        || (caller != Principal::anonymous() && booking.requester == Some(caller))
+}
+
+// Helper function to end a booking that never started, releasing its vehicle
+fn close_booking(endpoint: &str, booking: Booking, status: &str) -> Booking {
+    if let Some(vehicle) = VEHICLE_STORAGE.with(|storage| storage.borrow().get(&booking.vehicle_id))
+    {
+        if vehicle.status == VehicleStatus::Booked {
+            let vehicle_before = snapshot(&vehicle);
+            let updated_vehicle = Vehicle {
+                status: VehicleStatus::Available,
+                ..vehicle
+            };
+            VEHICLE_STORAGE.with(|storage| {
+                storage
+                    .borrow_mut()
+                    .insert(updated_vehicle.id, updated_vehicle.clone())
+            });
+            certify("vehicles", updated_vehicle.id, &updated_vehicle);
+            record_audit(
+                endpoint,
+                "vehicle",
+                updated_vehicle.id,
+                Some(vehicle_before),
+                Some(snapshot(&updated_vehicle)),
+            );
+        }
+    }
+
+    let booking_before = snapshot(&booking);
+    let previous_status = booking.status.clone();
+    let closed = Booking {
+        status: status.to_string(),
+        ..booking
+    };
+    BOOKING_STORAGE.with(|storage| storage.borrow_mut().insert(closed.id, closed.clone()));
+    certify("bookings", closed.id, &closed);
+    rollup_booking_change(&closed, Some(&previous_status));
+    record_audit(
+        endpoint,
+        "booking",
+        closed.id,
+        Some(booking_before),
+        Some(snapshot(&closed)),
+    );
+    emit_event(FleetEventKind::BookingStatusChanged {
+        booking: closed.clone(),
+        previous_status: Some(previous_status),
+    });
+    closed
+}
+
+// Function to record fuel consumption
+#[ic_cdk::update]
+fn record_fuel_consumption(payload: FuelConsumptionPayload) -> Result<FuelConsumption, Message> {
+    // Validate the fuel consumption payload
+    if !payload.amount.is_finite() || payload.amount <= 0.0 {
+        return Err(Message::InvalidPayload(
+            "Ensure 'amount' is a finite number greater than zero.".to_string(),
+        ));
+    }
+
+    // Validate the vehicle ID
+    let vehicle = VEHICLE_STORAGE.with(|storage| storage.borrow().get(&payload.vehicle_id));
+
+    if vehicle.is_none() {
+        return Err(Message::NotFound("Vehicle not found".to_string()));
+    }
+
+    // Update the fuel consumption record
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let fuel_consumption = FuelConsumption {
+        id,
+        vehicle_id: payload.vehicle_id,
+        amount: payload.amount,
+        cost: payload.cost,
+        date: payload.date,
+    };
+
+    FUEL_CONSUMPTION_STORAGE
+        .with(|storage| storage.borrow_mut().insert(id, fuel_consumption.clone()));
+    let department_id = vehicle.as_ref().and_then(|vehicle| vehicle.department_id);
+    rollup_write(RollupRebuildStage::Fuel, id, || {
+        rollup_fuel(&fuel_consumption, department_id)
+    });
+    record_audit(
+        "record_fuel_consumption",
+        "fuel_consumption",
+        id,
+        None,
+        Some(snapshot(&fuel_consumption)),
+    );
+    emit_event(FleetEventKind::FuelRecorded(fuel_consumption.clone()));
+    if let Some(department_id) = department_id {
+        check_department_budget(department_id, fuel_consumption.date);
+    }
+    Ok(fuel_consumption)
+}
+
+// Function to record a charging session of an electric or hybrid vehicle,
+// updating its state of charge
+#[ic_cdk::update]
+fn record_charging_session(payload: ChargingSessionPayload) -> Result<ChargingSession, Message> {
+    if !payload.energy_kwh.is_finite()
+        || payload.energy_kwh <= 0.0
+        || payload.charger_location.is_empty()
+        || payload.start_soc > payload.end_soc
+        || payload.end_soc > 100
+        || payload.end_time <= payload.start_time
+    {
+        return Err(Message::InvalidPayload(
+            "Ensure 'energy_kwh' is greater than zero, 'charger_location' is provided, 'start_soc' does not exceed 'end_soc', 'end_soc' is at most 100, and 'end_time' is after 'start_time'.".to_string(),
+        ));
+    }
+
+    let vehicle = VEHICLE_STORAGE
+        .with(|storage| storage.borrow().get(&payload.vehicle_id))
+        .ok_or(Message::NotFound("Vehicle not found".to_string()))?;
+    if !can_charge(&vehicle) {
+        return Err(Message::InvalidPayload(
+            "Only electric or hybrid vehicles can record charging sessions.".to_string(),
+        ));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let session = ChargingSession {
+        id,
+        vehicle_id: payload.vehicle_id,
+        energy_kwh: payload.energy_kwh,
+        charger_location: payload.charger_location,
+        start_soc: payload.start_soc,
+        end_soc: payload.end_soc,
+        cost: payload.cost,
+        start_time: payload.start_time,
+        end_time: payload.end_time,
+        created_at: current_time(),
+    };
+
+    CHARGING_STORAGE.with(|storage| storage.borrow_mut().insert(id, session.clone()));
+    rollup_write(RollupRebuildStage::Charging, id, || {
+        rollup_charging(&session, vehicle.department_id)
+    });
+    record_audit(
+        "record_charging_session",
+        "charging_session",
+        id,
+        None,
+        Some(snapshot(&session)),
+    );
+
+    // Only a newer reading moves the state of charge
+    if vehicle
+        .state_of_charge_at
+        .is_none_or(|read_at| read_at <= session.end_time)
+    {
+        store_state_of_charge(
+            "record_charging_session",
+            vehicle.clone(),
+            session.end_soc,
+            session.end_time,
+        );
+    }
+
+    emit_event(FleetEventKind::ChargingRecorded(session.clone()));
+    if let Some(department_id) = vehicle.department_id {
+        check_department_budget(department_id, session.start_time);
+    }
+    Ok(session)
+}
+
+// Function to get the charging sessions of a vehicle
+#[ic_cdk::query]
+fn get_charging_sessions(vehicle_id: u64) -> Result<Vec<ChargingSession>, Message> {
+    let sessions: Vec<ChargingSession> = CHARGING_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, session)| session)
+            .filter(|session| session.vehicle_id == vehicle_id)
+            .collect()
+    });
+    if sessions.is_empty() {
+        Err(Message::NotFound("No charging sessions found".to_string()))
+    } else {
+        Ok(sessions)
+    }
+}
+
+// Function to report a vehicle's current state of charge, e.g. from telematics
+// or a driver's reading at the end of a trip
+#[ic_cdk::update]
+fn set_state_of_charge(vehicle_id: u64, state_of_charge: u64) -> Result<Vehicle, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    if state_of_charge > 100 {
+        return Err(Message::InvalidPayload(
+            "Ensure 'state_of_charge' is at most 100.".to_string(),
+        ));
+    }
+
+    let vehicle = VEHICLE_STORAGE
+        .with(|storage| storage.borrow().get(&vehicle_id))
+        .ok_or(Message::NotFound("Vehicle not found".to_string()))?;
+    if !can_charge(&vehicle) {
+        return Err(Message::InvalidPayload(
+            "Only electric or hybrid vehicles have a state of charge.".to_string(),
+        ));
+    }
+    Ok(store_state_of_charge(
+        "set_state_of_charge",
+        vehicle,
+        state_of_charge,
+        current_time(),
+    ))
+}
+
+// Helper function to store a vehicle's new state of charge and when it was read
+fn store_state_of_charge(
+    endpoint: &str,
+    vehicle: Vehicle,
+    state_of_charge: u64,
+    read_at: u64,
+) -> Vehicle {
+    let before = snapshot(&vehicle);
+    let updated = Vehicle {
+        state_of_charge: Some(state_of_charge),
+        state_of_charge_at: Some(read_at),
+        ..vehicle
+    };
+    VEHICLE_STORAGE.with(|storage| storage.borrow_mut().insert(updated.id, updated.clone()));
+    certify("vehicles", updated.id, &updated);
+    record_audit(
+        endpoint,
+        "vehicle",
+        updated.id,
+        Some(before),
+        Some(snapshot(&updated)),
+    );
+    updated
+}
+
+// Helper function to tell whether a vehicle has a battery that can be charged
+fn can_charge(vehicle: &Vehicle) -> bool {
+    vehicle.profile.as_ref().is_some_and(|profile| {
+        matches!(
+            profile.energy_type,
+            EnergyType::Electric | EnergyType::Hybrid
+        )
+    })
+}
+
+// Helper function to estimate how far an electric vehicle can still go, from
+// its rated range and current state of charge
+fn estimated_range_km(vehicle: &Vehicle) -> Option<f64> {
+    let profile = vehicle.profile.as_ref()?;
+    if profile.energy_type != EnergyType::Electric {
+        return None;
+    }
+    Some(profile.rated_range_km? * vehicle.state_of_charge? as f64 / 100.0)
+}
+
+// Function to get all fuel consumption records
+#[ic_cdk::query]
+fn get_fuel_consumptions() -> Result<Vec<FuelConsumption>, Message> {
+    FUEL_CONSUMPTION_STORAGE.with(|storage| {
+        let records: Vec<FuelConsumption> = storage
+            .borrow()
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err(Message::NotFound(
+                "No fuel consumption records found".to_string(),
+            ))
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to get a fuel consumption record by ID
+#[ic_cdk::query]
+fn get_fuel_consumption_by_id(id: u64) -> Result<FuelConsumption, Message> {
+    FUEL_CONSUMPTION_STORAGE.with(|storage| {
+        storage.borrow().get(&id).ok_or(Message::NotFound(
+            "Fuel consumption record not found".to_string(),
+        ))
+    })
+}
+
+// Function to schedule maintenance
+#[ic_cdk::update]
+fn schedule_maintenance(payload: MaintenancePayload) -> Result<Maintenance, Message> {
+    // Validate the maintenance payload
+    if payload.description.is_empty() || payload.scheduled_date <= current_time() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'description' and valid 'scheduled_date' are provided.".to_string(),
+        ));
+    }
+
+    // Validate the vehicle ID
+    let vehicle = VEHICLE_STORAGE.with(|storage| storage.borrow().get(&payload.vehicle_id));
+
+    if vehicle.is_none() {
+        return Err(Message::NotFound("Vehicle not found".to_string()));
+    }
+
+    // Update the maintenance record
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let maintenance = Maintenance {
+        id,
+        vehicle_id: payload.vehicle_id,
+        description: payload.description,
+        scheduled_date: payload.scheduled_date,
+        status: "pending".to_string(),
+        cost: payload.cost,
+        created_at: current_time(),
+    };
+
+    MAINTENANCE_STORAGE.with(|storage| storage.borrow_mut().insert(id, maintenance.clone()));
+    let department_id = vehicle.as_ref().and_then(|vehicle| vehicle.department_id);
+    rollup_write(RollupRebuildStage::Maintenance, id, || {
+        rollup_maintenance(&maintenance, department_id)
+    });
+    record_audit(
+        "schedule_maintenance",
+        "maintenance",
+        id,
+        None,
+        Some(snapshot(&maintenance)),
+    );
+    emit_event(FleetEventKind::MaintenanceScheduled(maintenance.clone()));
+    if let Some(department_id) = department_id {
+        check_department_budget(department_id, maintenance.scheduled_date);
+    }
+    Ok(maintenance)
+}
+
+// Function to get all maintenance records
+#[ic_cdk::query]
+fn get_maintenances() -> Result<Vec<Maintenance>, Message> {
+    MAINTENANCE_STORAGE.with(|storage| {
+        let records: Vec<Maintenance> = storage
+            .borrow()
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err(Message::NotFound(
+                "No maintenance records found".to_string(),
+            ))
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to get a maintenance record by ID
+#[ic_cdk::query]
+fn get_maintenance_by_id(id: u64) -> Result<Maintenance, Message> {
+    MAINTENANCE_STORAGE.with(|storage| {
+        storage.borrow().get(&id).ok_or(Message::NotFound(
+            "Maintenance record not found".to_string(),
+        ))
+    })
+}
+
+// Function to request emergency assistance
+#[ic_cdk::update]
+fn request_emergency_assistance(
+    payload: EmergencyAssistancePayload,
+) -> Result<EmergencyAssistance, Message> {
+    // Validate the emergency assistance payload
+    if payload.description.is_empty() || payload.location.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'description' and 'location' are provided.".to_string(),
+        ));
+    }
+
+    // Validate the vehicle ID
+    let vehicle = VEHICLE_STORAGE.with(|storage| storage.borrow().get(&payload.vehicle_id));
+
+    if vehicle.is_none() {
+        return Err(Message::NotFound("Vehicle not found".to_string()));
+    }
+
+    // Update the emergency assistance record
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let assistance = EmergencyAssistance {
+        id,
+        vehicle_id: payload.vehicle_id,
+        description: payload.description,
+        location: payload.location,
+        status: "pending".to_string(),
+        cost: None,
+        resolved_at: None,
+        created_at: current_time(),
+    };
+
+    EMERGENCY_ASSISTANCE_STORAGE
+        .with(|storage| storage.borrow_mut().insert(id, assistance.clone()));
+    record_audit(
+        "request_emergency_assistance",
+        "emergency_assistance",
+        id,
+        None,
+        Some(snapshot(&assistance)),
+    );
+    emit_event(FleetEventKind::EmergencyRaised(assistance.clone()));
+    Ok(assistance)
+}
+
+// Function to get all emergency assistance records
+#[ic_cdk::query]
+fn get_emergency_assistances() -> Result<Vec<EmergencyAssistance>, Message> {
+    EMERGENCY_ASSISTANCE_STORAGE.with(|storage| {
+        let records: Vec<EmergencyAssistance> = storage
+            .borrow()
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err(Message::NotFound(
+                "No emergency assistance records found".to_string(),
+            ))
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to get an emergency assistance record by ID
+#[ic_cdk::query]
+fn get_emergency_assistance_by_id(id: u64) -> Result<EmergencyAssistance, Message> {
+    EMERGENCY_ASSISTANCE_STORAGE.with(|storage| {
+        storage.borrow().get(&id).ok_or(Message::NotFound(
+            "Emergency assistance record not found".to_string(),
+        ))
+    })
+}
+
+// Function to resolve an emergency, recording what the incident cost
+#[ic_cdk::update]
+fn resolve_emergency_assistance(id: u64, cost: u64) -> Result<EmergencyAssistance, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let assistance = EMERGENCY_ASSISTANCE_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound(
+            "Emergency assistance record not found".to_string(),
+        ))?;
+    if assistance.status == "resolved" {
+        return Err(Message::InvalidPayload(
+            "Emergency has already been resolved.".to_string(),
+        ));
+    }
+
+    let before = snapshot(&assistance);
+    let resolved = EmergencyAssistance {
+        status: "resolved".to_string(),
+        cost: Some(cost),
+        resolved_at: Some(current_time()),
+        ..assistance
+    };
+    EMERGENCY_ASSISTANCE_STORAGE.with(|storage| storage.borrow_mut().insert(id, resolved.clone()));
+    rollup_write(RollupRebuildStage::Incidents, id, || {
+        rollup_incident(&resolved)
+    });
+    record_audit(
+        "resolve_emergency_assistance",
+        "emergency_assistance",
+        id,
+        Some(before),
+        Some(snapshot(&resolved)),
+    );
+    Ok(resolved)
+}
+
+// Function to create an optimized route
+#[ic_cdk::update]
+fn create_route(payload: RoutePayload) -> Result<Route, Message> {
+    // Validate the route payload
+    if payload.from_location.is_empty() || payload.to_location.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'from_location' and 'to_location' are provided.".to_string(),
+        ));
+    }
+    if payload.from_location.len() > MAX_NAME_LENGTH || payload.to_location.len() > MAX_NAME_LENGTH
+    {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'from_location' and 'to_location' are at most {} bytes.",
+            MAX_NAME_LENGTH
+        )));
+    }
+    for coordinates in [payload.from_coordinates, payload.to_coordinates]
+        .into_iter()
+        .flatten()
+    {
+        validate_coordinates(&coordinates)?;
+    }
+
+    // Plan refuelling or charging stops, if a vehicle was given
+    let stops = match payload.vehicle_id {
+        Some(vehicle_id) => {
+            let (Some(from), Some(to)) = (payload.from_coordinates, payload.to_coordinates) else {
+                return Err(Message::InvalidPayload(
+                    "Ensure 'from_coordinates' and 'to_coordinates' are provided to plan stops."
+                        .to_string(),
+                ));
+            };
+            let vehicle = VEHICLE_STORAGE
+                .with(|storage| storage.borrow().get(&vehicle_id))
+                .ok_or(Message::NotFound("Vehicle not found".to_string()))?;
+            Some(plan_stops(&vehicle, from, to)?)
+        }
+        None => None,
+    };
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    // Dummy data for route optimization
+    let optimized_route = format!(
+        "Optimized route from {} to {}",
+        payload.from_location, payload.to_location
+    );
+    let distance = match (payload.from_coordinates, payload.to_coordinates) {
+        (Some(from), Some(to)) => flat_distance_km(from, to),
+        _ => 100.0, // Dummy distance
+    };
+    let time_estimate = 3600; // Dummy time estimate in seconds
+
+    let route = Route {
+        id,
+        from_location: payload.from_location,
+        to_location: payload.to_location,
+        optimized_route,
+        distance,
+        time_estimate,
+        stops,
+    };
+
+    ROUTE_STORAGE.with(|storage| storage.borrow_mut().insert(id, route.clone()));
+    record_audit("create_route", "route", id, None, Some(snapshot(&route)));
+    Ok(route)
+}
+
+// Function to add a fuel station or EV charger to the catalog
+#[ic_cdk::update]
+fn create_station(payload: StationPayload) -> Result<Station, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    validate_station(&payload)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let station = Station {
+        id,
+        name: payload.name,
+        location: payload.location,
+        coordinates: payload.coordinates,
+        energy_types: payload.energy_types,
+        connectors: payload.connectors,
+        price: payload.price,
+        created_at: current_time(),
+    };
+    STATION_STORAGE.with(|storage| storage.borrow_mut().insert(id, station.clone()));
+    record_audit(
+        "create_station",
+        "station",
+        id,
+        None,
+        Some(snapshot(&station)),
+    );
+    Ok(station)
+}
+
+// Function to update a station in the catalog, e.g. when its price changes
+#[ic_cdk::update]
+fn update_station(id: u64, payload: StationPayload) -> Result<Station, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    validate_station(&payload)?;
+
+    let station = STATION_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Station not found".to_string()))?;
+    let before = snapshot(&station);
+    let updated = Station {
+        name: payload.name,
+        location: payload.location,
+        coordinates: payload.coordinates,
+        energy_types: payload.energy_types,
+        connectors: payload.connectors,
+        price: payload.price,
+        ..station
+    };
+    STATION_STORAGE.with(|storage| storage.borrow_mut().insert(id, updated.clone()));
+    record_audit(
+        "update_station",
+        "station",
+        id,
+        Some(before),
+        Some(snapshot(&updated)),
+    );
+    Ok(updated)
+}
+
+// Function to get the stations in the catalog, optionally only those offering
+// an energy type
+#[ic_cdk::query]
+fn get_stations(energy_type: Option<EnergyType>) -> Result<Vec<Station>, Message> {
+    let stations: Vec<Station> = STATION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, station)| station)
+            .filter(|station| {
+                energy_type.is_none_or(|energy_type| station.energy_types.contains(&energy_type))
+            })
+            .collect()
+    });
+    if stations.is_empty() {
+        Err(Message::NotFound("No stations found".to_string()))
+    } else {
+        Ok(stations)
+    }
+}
+
+// Helper function to validate a station payload
+fn validate_station(payload: &StationPayload) -> Result<(), Message> {
+    if payload.name.is_empty() || payload.location.is_empty() || payload.energy_types.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name', 'location' and 'energy_types' are provided.".to_string(),
+        ));
+    }
+    if payload.name.len() > MAX_NAME_LENGTH || payload.location.len() > MAX_TEXT_LENGTH {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'name' is at most {} bytes and 'location' at most {}.",
+            MAX_NAME_LENGTH, MAX_TEXT_LENGTH
+        )));
+    }
+    if has_duplicates(&payload.energy_types) || has_duplicates(&payload.connectors) {
+        return Err(Message::InvalidPayload(
+            "Ensure 'energy_types' and 'connectors' list each kind once.".to_string(),
+        ));
+    }
+    if payload.energy_types.contains(&EnergyType::Electric) && payload.connectors.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure chargers list their 'connectors'.".to_string(),
+        ));
+    }
+    validate_coordinates(&payload.coordinates)
+}
+
+// Helper function to check whether a list names any kind twice
+fn has_duplicates<T: PartialEq>(items: &[T]) -> bool {
+    items
+        .iter()
+        .enumerate()
+        .any(|(index, item)| items[..index].contains(item))
+}
+
+// Helper function to validate coordinates
+fn validate_coordinates(coordinates: &Coordinates) -> Result<(), Message> {
+    if !(-90.0..=90.0).contains(&coordinates.latitude)
+        || !(-180.0..=180.0).contains(&coordinates.longitude)
+    {
+        return Err(Message::InvalidPayload(
+            "Ensure 'latitude' is within ±90 and 'longitude' within ±180.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Helper function to plan refuelling or charging stops along a straight route so
+// that no leg exceeds the vehicle's range, less a reserve; each stop is the
+// farthest suitable station still in reach
+fn plan_stops(
+    vehicle: &Vehicle,
+    from: Coordinates,
+    to: Coordinates,
+) -> Result<Vec<RouteStop>, Message> {
+    let profile = vehicle.profile.as_ref().ok_or(Message::InvalidPayload(
+        "Vehicle has no profile to plan stops with.".to_string(),
+    ))?;
+    let full_range = profile.rated_range_km.ok_or(Message::InvalidPayload(
+        "Vehicle has no 'rated_range_km' to plan stops with.".to_string(),
+    ))?;
+    let energy_type = match profile.energy_type {
+        EnergyType::Hybrid => EnergyType::Petrol,
+        energy_type => energy_type,
+    };
+
+    // Project the suitable stations onto the route, keeping those near it
+    let (route_x, route_y) = flat_offset_km(from, to, to);
+    let length = (route_x * route_x + route_y * route_y).sqrt();
+    let mut candidates: Vec<(f64, Station)> = STATION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, station)| station)
+            .filter(|station| {
+                station.energy_types.contains(&energy_type)
+                    && (energy_type != EnergyType::Electric
+                        || profile
+                            .connector
+                            .is_none_or(|connector| station.connectors.contains(&connector)))
+            })
+            .filter_map(|station| {
+                let (x, y) = flat_offset_km(from, station.coordinates, to);
+                let along = (x * route_x + y * route_y) / length;
+                let across = (x * route_y - y * route_x).abs() / length;
+                (along > 0.0 && along < length && across <= STATION_CORRIDOR_KM)
+                    .then_some((along, station))
+            })
+            .collect()
+    });
+    candidates.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+    let usable = |range: f64| range * (1.0 - RANGE_RESERVE);
+    let mut position = 0.0;
+    let mut reach = usable(estimated_range_km(vehicle).unwrap_or(full_range));
+    let mut stops = Vec::new();
+    while position + reach < length {
+        let (along, station) = candidates
+            .iter()
+            .rfind(|(along, _)| *along > position && *along <= position + reach)
+            .ok_or(Message::InvalidPayload(format!(
+                "No suitable station within {:.0} km after {:.0} km of the route.",
+                reach, position
+            )))?;
+        stops.push(RouteStop {
+            station_id: station.id,
+            coordinates: station.coordinates,
+            at_km: *along,
+            energy_type,
+            price: station.price,
+        });
+        if stops.len() > MAX_ROUTE_STOPS {
+            return Err(Message::InvalidPayload(format!(
+                "The route needs more than {} stops.",
+                MAX_ROUTE_STOPS
+            )));
+        }
+        position = *along;
+        reach = usable(full_range);
+    }
+    Ok(stops)
+}
+
+// Helper function to get the straight-line distance between two points
+fn flat_distance_km(from: Coordinates, to: Coordinates) -> f64 {
+    let (x, y) = flat_offset_km(from, to, to);
+    (x * x + y * y).sqrt()
+}
+
+// Helper function to get a point's east and north offsets in km from an origin,
+// on an equirectangular projection centred on the route from the origin to 'to';
+// longitudes are compared the short way round, across ±180° if need be
+fn flat_offset_km(origin: Coordinates, point: Coordinates, to: Coordinates) -> (f64, f64) {
+    let mean_latitude = ((origin.latitude + to.latitude) / 2.0).to_radians();
+    let east = (point.longitude - origin.longitude + 180.0).rem_euclid(360.0) - 180.0;
+    let x = east.to_radians() * mean_latitude.cos();
+    let y = (point.latitude - origin.latitude).to_radians();
+    (x * EARTH_RADIUS_KM, y * EARTH_RADIUS_KM)
+}
+
+// Function to get the cargo manifest of a booking
+#[ic_cdk::query]
+fn get_booking_manifest(booking_id: u64) -> Result<CargoManifest, Message> {
+    get_booking_by_id(booking_id)?;
+    MANIFEST_STORAGE
+        .with(|storage| storage.borrow().get(&booking_id))
+        .ok_or(Message::NotFound("Cargo manifest not found".to_string()))
+}
+
+// Function to record proof of delivery for a cargo item once its booking is
+// under way or completed
+#[ic_cdk::update]
+fn record_delivery(payload: DeliveryPayload) -> Result<CargoManifest, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager, UserRole::Driver])?;
+    if payload.recipient.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'recipient' is provided.".to_string(),
+        ));
+    }
+    if payload.recipient.len() > MAX_NAME_LENGTH
+        || payload
+            .evidence
+            .as_ref()
+            .is_some_and(|evidence| evidence.len() > MAX_NAME_LENGTH)
+    {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'recipient' and 'evidence' are at most {} bytes.",
+            MAX_NAME_LENGTH
+        )));
+    }
+
+    let booking = BOOKING_STORAGE
+        .with(|storage| storage.borrow().get(&payload.booking_id))
+        .ok_or(Message::NotFound("Booking not found".to_string()))?;
+    if booking.status != "in_progress" && booking.status != "completed" {
+        return Err(Message::InvalidPayload(format!(
+            "Deliveries cannot be recorded while the booking is '{}'.",
+            booking.status
+        )));
+    }
+    require_driver_access(booking.driver_id)?;
+
+    let manifest = MANIFEST_STORAGE
+        .with(|storage| storage.borrow().get(&payload.booking_id))
+        .ok_or(Message::NotFound("Cargo manifest not found".to_string()))?;
+    let before = snapshot(&manifest);
+    let mut updated = manifest;
+    let item = updated
+        .items
+        .iter_mut()
+        .find(|item| item.id == payload.item_id)
+        .ok_or(Message::NotFound("Cargo item not found".to_string()))?;
+    if item.delivery.is_some() {
+        return Err(Message::InvalidPayload(
+            "Cargo item has already been delivered.".to_string(),
+        ));
+    }
+    item.delivery = Some(ProofOfDelivery {
+        recipient: payload.recipient,
+        evidence: payload.evidence,
+        recorded_by: caller(),
+        delivered_at: current_time(),
+    });
+
+    MANIFEST_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(payload.booking_id, updated.clone())
+    });
+    record_audit(
+        "record_delivery",
+        "cargo_manifest",
+        payload.booking_id,
+        Some(before),
+        Some(snapshot(&updated)),
+    );
+    Ok(updated)
+}
+
+// Helper function to validate a manifest's items and check the total load
+// against the vehicle's cargo weight and volume capacity
+fn validate_manifest(vehicle: &Vehicle, items: &[CargoItemPayload]) -> Result<(), Message> {
+    if items.is_empty() || items.len() > MAX_MANIFEST_ITEMS {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure the manifest has between 1 and {} items.",
+            MAX_MANIFEST_ITEMS
+        )));
+    }
+    for (index, item) in items.iter().enumerate() {
+        if item.description.is_empty()
+            || item.quantity == 0
+            || !item.weight_kg.is_finite()
+            || item.weight_kg <= 0.0
+            || !item.volume_m3.is_finite()
+            || item.volume_m3 < 0.0
+            || item.pickup_stop.is_empty()
+            || item.dropoff_stop.is_empty()
+        {
+            return Err(Message::InvalidPayload(format!(
+                "Item {}: ensure 'description', 'quantity', a positive 'weight_kg', a non-negative 'volume_m3', 'pickup_stop' and 'dropoff_stop' are provided.",
+                index + 1
+            )));
+        }
+        if item.description.len() > MAX_TEXT_LENGTH
+            || item.pickup_stop.len() > MAX_NAME_LENGTH
+            || item.dropoff_stop.len() > MAX_NAME_LENGTH
+            || item
+                .hazard_class
+                .as_ref()
+                .is_some_and(|class| class.len() > MAX_NAME_LENGTH)
+        {
+            return Err(Message::InvalidPayload(format!(
+                "Item {}: ensure 'description' is at most {} bytes and 'hazard_class', 'pickup_stop' and 'dropoff_stop' at most {}.",
+                index + 1,
+                MAX_TEXT_LENGTH,
+                MAX_NAME_LENGTH
+            )));
+        }
+        if item.pickup_stop == item.dropoff_stop {
+            return Err(Message::InvalidPayload(format!(
+                "Item {}: 'pickup_stop' and 'dropoff_stop' must differ.",
+                index + 1
+            )));
+        }
+        if item.hazardous
+            && item
+                .hazard_class
+                .as_ref()
+                .is_none_or(|class| class.is_empty())
+        {
+            return Err(Message::InvalidPayload(format!(
+                "Item {}: hazardous items need a 'hazard_class'.",
+                index + 1
+            )));
+        }
+    }
+
+    let profile = vehicle.profile.as_ref().ok_or(Message::InvalidPayload(
+        "Vehicle has no profile with its cargo capacity.".to_string(),
+    ))?;
+    let weight: f64 = items.iter().map(|item| item.weight_kg).sum();
+    let volume: f64 = items.iter().map(|item| item.volume_m3).sum();
+    if weight > profile.cargo_capacity_kg as f64 {
+        return Err(Message::InvalidPayload(format!(
+            "Cargo of {:.1} kg exceeds the vehicle's payload of {} kg.",
+            weight, profile.cargo_capacity_kg
+        )));
+    }
+    if volume > profile.cargo_volume_m3 {
+        return Err(Message::InvalidPayload(format!(
+            "Cargo of {:.2} m³ exceeds the vehicle's volume of {:.2} m³.",
+            volume, profile.cargo_volume_m3
+        )));
+    }
+    Ok(())
+}
+
+// Helper function to build the manifest of a booking from validated items
+fn build_manifest(booking_id: u64, items: Vec<CargoItemPayload>) -> CargoManifest {
+    let items: Vec<CargoItem> = items
+        .into_iter()
+        .zip(1..)
+        .map(|(item, id)| CargoItem {
+            id,
+            description: item.description,
+            quantity: item.quantity,
+            weight_kg: item.weight_kg,
+            volume_m3: item.volume_m3,
+            hazardous: item.hazardous,
+            hazard_class: item.hazard_class,
+            pickup_stop: item.pickup_stop,
+            dropoff_stop: item.dropoff_stop,
+            delivery: None,
+        })
+        .collect();
+    CargoManifest {
+        booking_id,
+        total_weight_kg: items.iter().map(|item| item.weight_kg).sum(),
+        total_volume_m3: items.iter().map(|item| item.volume_m3).sum(),
+        items,
+        created_at: current_time(),
+    }
+}
+
+// Function to get all routes
+#[ic_cdk::query]
+fn get_routes() -> Result<Vec<Route>, Message> {
+    ROUTE_STORAGE.with(|storage| {
+        let routes: Vec<Route> = storage
+            .borrow()
+            .iter()
+            .map(|(_, route)| route.clone())
+            .collect();
+        if routes.is_empty() {
+            Err(Message::NotFound("No routes found".to_string()))
+        } else {
+            Ok(routes)
+        }
+    })
+}
+
+// Function to get a route by ID
+#[ic_cdk::query]
+fn get_route_by_id(id: u64) -> Result<Route, Message> {
+    ROUTE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Route not found".to_string()))
+    })
+}
+
+// Function to start a booking, opening a trip record
+#[ic_cdk::update]
+fn start_booking(payload: StartTripPayload) -> Result<Trip, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager, UserRole::Driver])?;
+    if !payload.start_odometer.is_finite() || payload.start_odometer < 0.0 {
+        return Err(Message::InvalidPayload(
+            "Ensure 'start_odometer' is a finite, non-negative number.".to_string(),
+        ));
+    }
+
+    let booking = BOOKING_STORAGE
+        .with(|storage| storage.borrow().get(&payload.booking_id))
+        .ok_or(Message::NotFound("Booking not found".to_string()))?;
+    require_driver_access(booking.driver_id)?;
+
+    if booking.status != "pending" && booking.status != "approved" {
+        return Err(Message::InvalidPayload(format!(
+            "Booking cannot be started while '{}'.",
+            booking.status
+        )));
+    }
+    if payment_in_flight(booking.id) {
+        return Err(Message::InvalidPayload(
+            "The booking's deposit is still being collected.".to_string(),
+        ));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let now = current_time();
+    let trip = Trip {
+        id,
+        booking_id: booking.id,
+        vehicle_id: booking.vehicle_id,
+        driver_id: booking.driver_id,
+        planned_route_id: booking.route_id,
+        route_taken: String::new(),
+        start_time: now,
+        end_time: None,
+        start_odometer: payload.start_odometer,
+        end_odometer: None,
+        distance: 0.0,
+        distance_deviation: None,
+        fuel_used: 0.0,
+        start_delay: now as i64 - booking.start_time as i64,
+        end_delay: None,
+        status: "in_progress".to_string(),
+        created_at: now,
+    };
+
+    let booking_before = snapshot(&booking);
+    let previous_status = booking.status.clone();
+    let updated_booking = Booking {
+        status: "in_progress".to_string(),
+        ..booking
+    };
+    BOOKING_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(updated_booking.id, updated_booking.clone())
+    });
+    certify("bookings", updated_booking.id, &updated_booking);
+    rollup_booking_change(&updated_booking, Some(&previous_status));
+
+    TRIP_STORAGE.with(|storage| storage.borrow_mut().insert(id, trip.clone()));
+    TRIP_INDEX.with(|index| index.borrow_mut().insert(trip.booking_id, id));
+    record_audit(
+        "start_booking",
+        "booking",
+        updated_booking.id,
+        Some(booking_before),
+        Some(snapshot(&updated_booking)),
+    );
+    record_audit("start_booking", "trip", id, None, Some(snapshot(&trip)));
+    emit_event(FleetEventKind::BookingStatusChanged {
+        booking: updated_booking,
+        previous_status: Some(previous_status),
+    });
+    Ok(trip)
+}
+
+// Function to complete a booking, closing its trip record and settling its payment
+#[ic_cdk::update]
+async fn complete_booking(payload: CompleteTripPayload) -> Result<Trip, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager, UserRole::Driver])?;
+    if !payload.fuel_used.is_finite() || payload.fuel_used < 0.0 || payload.route_taken.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'route_taken' is provided and 'fuel_used' is a finite, non-negative number."
+                .to_string(),
+        ));
+    }
+
+    let booking = BOOKING_STORAGE
+        .with(|storage| storage.borrow().get(&payload.booking_id))
+        .ok_or(Message::NotFound("Booking not found".to_string()))?;
+    require_driver_access(booking.driver_id)?;
+
+    if booking.status != "in_progress" {
+        return Err(Message::InvalidPayload(
+            "Booking has not been started.".to_string(),
+        ));
+    }
+
+    let trip = find_open_trip(booking.id).ok_or(Message::NotFound("Trip not found".to_string()))?;
+
+    if !payload.end_odometer.is_finite() || payload.end_odometer < trip.start_odometer {
+        return Err(Message::InvalidPayload(
+            "Ensure 'end_odometer' is finite and not below the trip's 'start_odometer'."
+                .to_string(),
+        ));
+    }
+
+    let now = current_time();
+    let distance = payload.end_odometer - trip.start_odometer;
+    let distance_deviation = trip
+        .planned_route_id
+        .and_then(|route_id| ROUTE_STORAGE.with(|storage| storage.borrow().get(&route_id)))
+        .map(|route| distance - route.distance);
+
+    let trip_before = snapshot(&trip);
+    let completed_trip = Trip {
+        route_taken: payload.route_taken,
+        end_time: Some(now),
+        end_odometer: Some(payload.end_odometer),
+        distance,
+        distance_deviation,
+        fuel_used: payload.fuel_used,
+        end_delay: Some(now as i64 - booking.end_time as i64),
+        status: "completed".to_string(),
+        ..trip
+    };
+
+    // Release the vehicle once the trip is over
+    if let Some(vehicle) = VEHICLE_STORAGE.with(|storage| storage.borrow().get(&booking.vehicle_id))
+    {
+        let vehicle_before = snapshot(&vehicle);
+        let updated_vehicle = Vehicle {
+            status: VehicleStatus::Available,
+            ..vehicle
+        };
+        VEHICLE_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(updated_vehicle.id, updated_vehicle.clone())
+        });
+        certify("vehicles", updated_vehicle.id, &updated_vehicle);
+        record_audit(
+            "complete_booking",
+            "vehicle",
+            updated_vehicle.id,
+            Some(vehicle_before),
+            Some(snapshot(&updated_vehicle)),
+        );
+    }
+
+    let booking_before = snapshot(&booking);
+    let updated_booking = Booking {
+        status: "completed".to_string(),
+        ..booking
+    };
+    BOOKING_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(updated_booking.id, updated_booking.clone())
+    });
+    certify("bookings", updated_booking.id, &updated_booking);
+
+    TRIP_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(completed_trip.id, completed_trip.clone())
+    });
+    rollup_write(RollupRebuildStage::Trips, completed_trip.id, || {
+        rollup_trip(&completed_trip)
+    });
+    rollup_booking_change(&updated_booking, Some("in_progress"));
+    record_audit(
+        "complete_booking",
+        "booking",
+        updated_booking.id,
+        Some(booking_before),
+        Some(snapshot(&updated_booking)),
+    );
+    record_audit(
+        "complete_booking",
+        "trip",
+        completed_trip.id,
+        Some(trip_before),
+        Some(snapshot(&completed_trip)),
+    );
+    let invoice = issue_invoice(&updated_booking, &completed_trip);
+    emit_event(FleetEventKind::BookingStatusChanged {
+        booking: updated_booking,
+        previous_status: Some("in_progress".to_string()),
+    });
+
+    // Charge the invoice against the deposit, collecting or refunding the difference
+    if let Some(payment) =
+        PAYMENT_STORAGE.with(|storage| storage.borrow().get(&completed_trip.booking_id))
+    {
+        let amount_due = invoice.map_or(0, |invoice| invoice.total);
+        let payment = set_amount_due("complete_booking", payment, amount_due);
+        if let Some(ledger) = payment_ledger() {
+            // A failed settlement stays on the payment record for retry_payment_settlement
+            settle_payment(&ledger, "complete_booking", payment).await;
+        }
+    }
+    Ok(completed_trip)
+}
+
+// Function to get all trips
+#[ic_cdk::query]
+fn get_trips() -> Result<Vec<Trip>, Message> {
+    filter_trips(|_| true)
+}
+
+// Function to get a trip by ID
+#[ic_cdk::query]
+fn get_trip_by_id(id: u64) -> Result<Trip, Message> {
+    TRIP_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Trip not found".to_string()))
+    })
+}
+
+// Function to get the trips driven with a vehicle
+#[ic_cdk::query]
+fn get_trips_by_vehicle(vehicle_id: u64) -> Result<Vec<Trip>, Message> {
+    filter_trips(|trip| trip.vehicle_id == vehicle_id)
+}
+
+// Function to get the trips driven by a driver
+#[ic_cdk::query]
+fn get_trips_by_driver(driver_id: u64) -> Result<Vec<Trip>, Message> {
+    filter_trips(|trip| trip.driver_id == driver_id)
+}
+
+// Function to get the trips started within a time window
+#[ic_cdk::query]
+fn get_trips_by_date(start_time: u64, end_time: u64) -> Result<Vec<Trip>, Message> {
+    filter_trips(|trip| trip.start_time >= start_time && trip.start_time <= end_time)
+}
+
+// Helper function to collect the trips matching a predicate
+fn filter_trips(predicate: impl Fn(&Trip) -> bool) -> Result<Vec<Trip>, Message> {
+    TRIP_STORAGE.with(|storage| {
+        let trips: Vec<Trip> = storage
+            .borrow()
+            .iter()
+            .map(|(_, trip)| trip)
+            .filter(|trip| predicate(trip))
+            .collect();
+        if trips.is_empty() {
+            Err(Message::NotFound("No trips found".to_string()))
+        } else {
+            Ok(trips)
+        }
+    })
+}
+
+// Helper function to find the trip still in progress for a booking
+fn find_open_trip(booking_id: u64) -> Option<Trip> {
+    find_trip(booking_id).filter(|trip| trip.status == "in_progress")
+}
+
+// Helper function to find the trip driven for a booking
+fn find_trip(booking_id: u64) -> Option<Trip> {
+    let trip_id = TRIP_INDEX.with(|index| index.borrow().get(&booking_id))?;
+    TRIP_STORAGE.with(|storage| storage.borrow().get(&trip_id))
+}
+
+// Helper function to rebuild the booking-to-trip index from the trip store
+fn index_trips() {
+    clear_store(&TRIP_INDEX);
+    TRIP_STORAGE.with(|storage| {
+        TRIP_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (id, trip) in storage.borrow().iter() {
+                index.insert(trip.booking_id, id);
+            }
+        })
+    });
+}
+
+// Function to assign a role to a principal
+#[ic_cdk::update]
+fn assign_role(payload: RoleAssignmentPayload) -> Result<UserAccount, Message> {
+    require_role(&[UserRole::Admin])?;
+
+    // Validate the driver record, which only one Driver-role principal may act for
+    if let Some(driver_id) = payload.driver_id {
+        if payload.role != UserRole::Driver {
+            return Err(Message::InvalidPayload(
+                "Only principals with the Driver role can be linked to a driver.".to_string(),
+            ));
+        }
+        if !DRIVER_STORAGE.with(|storage| storage.borrow().contains_key(&driver_id)) {
+            return Err(Message::NotFound("Driver not found".to_string()));
+        }
+        let linked_elsewhere = USER_STORAGE.with(|storage| {
+            storage.borrow().iter().any(|(_, account)| {
+                account.driver_id == Some(driver_id) && account.principal != payload.principal
+            })
+        });
+        if linked_elsewhere {
+            return Err(Message::InvalidPayload(
+                "Driver is already linked to another principal.".to_string(),
+            ));
+        }
+    }
+
+    let existing =
+        USER_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(payload.principal)));
+
+    let (account, before) = match existing {
+        Some(account) => {
+            let before = snapshot(&account);
+            (
+                UserAccount {
+                    role: payload.role,
+                    driver_id: payload.driver_id,
+                    ..account
+                },
+                Some(before),
+            )
+        }
+        None => {
+            let id = ID_COUNTER
+                .with(|counter| {
+                    let current_value = *counter.borrow().get();
+                    counter.borrow_mut().set(current_value + 1)
+                })
+                .expect("Cannot increment ID counter");
+            (
+                UserAccount {
+                    id,
+                    principal: payload.principal,
+                    role: payload.role,
+                    driver_id: payload.driver_id,
+                    created_at: current_time(),
+                },
+                None,
+            )
+        }
+    };
+
+    USER_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(account.principal), account.clone())
+    });
+    record_audit(
+        "assign_role",
+        "user",
+        account.id,
+        before,
+        Some(snapshot(&account)),
+    );
+    Ok(account)
+}
+
+// Function to get the caller's role
+#[ic_cdk::query]
+fn get_my_role() -> UserRole {
+    caller_role()
+}
+
+// Function to page through the audit log, newest entries first
+#[ic_cdk::query]
+fn get_audit_log(filter: AuditLogFilter) -> Result<AuditLogPage, Message> {
+    require_role(&[UserRole::Admin])?;
+
+    let limit = filter.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = filter.before_seq.unwrap_or(u64::MAX).min(log.len());
+        let scan_from = end.saturating_sub(MAX_AUDIT_SCAN);
+
+        // Walk back from the cursor, stopping at a full page or the scan budget
+        let mut entries = Vec::new();
+        let mut seq = end;
+        while seq > scan_from && entries.len() < limit {
+            seq -= 1;
+            let Some(entry) = log.get(seq) else {
+                continue;
+            };
+            if filter
+                .entity_type
+                .as_ref()
+                .is_none_or(|entity_type| &entry.entity_type == entity_type)
+                && filter.entity_id.is_none_or(|id| entry.entity_id == id)
+                && filter.caller.is_none_or(|caller| entry.caller == caller)
+                && filter.from.is_none_or(|from| entry.timestamp >= from)
+                && filter.to.is_none_or(|to| entry.timestamp <= to)
+            {
+                entries.push(entry);
+            }
+        }
+        Ok(AuditLogPage {
+            entries,
+            next_seq: (seq > 0).then_some(seq),
+        })
+    })
+}
+
+// Function to read the change feed from a sequence number onwards
+#[ic_cdk::query]
+fn get_events_since(seq: u64, limit: u64) -> Result<EventPage, Message> {
+    // Events carry whole records, requester principals included
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    EVENT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = log.len().min(seq.saturating_add(limit));
+        let events: Vec<FleetEvent> = (seq..end).filter_map(|seq| log.get(seq)).collect();
+        Ok(EventPage {
+            next_seq: end.max(seq),
+            events,
+        })
+    })
+}
+
+// Function to record a vehicle document
+#[ic_cdk::update]
+fn add_vehicle_document(payload: VehicleDocumentPayload) -> Result<VehicleDocument, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    if payload.reference_number.is_empty()
+        || payload.issuer.is_empty()
+        || payload.expires_at <= payload.issued_at
+    {
+        return Err(Message::InvalidPayload(
+            "Ensure 'reference_number' and 'issuer' are provided and 'expires_at' is after 'issued_at'."
+                .to_string(),
+        ));
+    }
+    if payload.reference_number.len() > MAX_NAME_LENGTH || payload.issuer.len() > MAX_NAME_LENGTH {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'reference_number' and 'issuer' are at most {} bytes.",
+            MAX_NAME_LENGTH
+        )));
+    }
+
+    if !VEHICLE_STORAGE.with(|storage| storage.borrow().contains_key(&payload.vehicle_id)) {
+        return Err(Message::NotFound("Vehicle not found".to_string()));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let document = VehicleDocument {
+        id,
+        vehicle_id: payload.vehicle_id,
+        kind: payload.kind,
+        reference_number: payload.reference_number,
+        issuer: payload.issuer,
+        cost: payload.cost,
+        issued_at: payload.issued_at,
+        expires_at: payload.expires_at,
+        created_at: current_time(),
+    };
+
+    DOCUMENT_STORAGE.with(|storage| storage.borrow_mut().insert(id, document.clone()));
+    rollup_write(RollupRebuildStage::Documents, id, || {
+        rollup_document(&document)
+    });
+    record_audit(
+        "add_vehicle_document",
+        "vehicle_document",
+        id,
+        None,
+        Some(snapshot(&document)),
+    );
+    Ok(document)
+}
+
+// Function to get the documents recorded for a vehicle
+#[ic_cdk::query]
+fn get_vehicle_documents(vehicle_id: u64) -> Result<Vec<VehicleDocument>, Message> {
+    let documents = vehicle_documents(vehicle_id);
+    if documents.is_empty() {
+        Err(Message::NotFound("No vehicle documents found".to_string()))
+    } else {
+        Ok(documents)
+    }
+}
+
+// Function to list the vehicles missing valid mandatory documents at a time
+// (now if not given)
+#[ic_cdk::query]
+fn get_non_compliant_vehicles(at: Option<u64>) -> Result<Vec<VehicleCompliance>, Message> {
+    let at = at.unwrap_or_else(current_time);
+    let vehicles: Vec<Vehicle> = VEHICLE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, vehicle)| vehicle)
+            .collect()
+    });
+
+    let reports: Vec<VehicleCompliance> = vehicles
+        .iter()
+        .map(|vehicle| check_vehicle_compliance(vehicle, at))
+        .filter(|report| {
+            !report.missing.is_empty()
+                || !report.expired.is_empty()
+                || !report.not_yet_valid.is_empty()
+        })
+        .collect();
+    if reports.is_empty() {
+        Err(Message::NotFound(
+            "No non-compliant vehicles found".to_string(),
+        ))
+    } else {
+        Ok(reports)
+    }
+}
+
+// Helper function to collect the documents recorded for a vehicle
+fn vehicle_documents(vehicle_id: u64) -> Vec<VehicleDocument> {
+    DOCUMENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, document)| document)
+            .filter(|document| document.vehicle_id == vehicle_id)
+            .collect()
+    })
+}
+
+// Helper function to check a vehicle's mandatory documents at a given time
+fn check_vehicle_compliance(vehicle: &Vehicle, at: u64) -> VehicleCompliance {
+    let documents = vehicle_documents(vehicle.id);
+    let mut missing = Vec::new();
+    let mut expired = Vec::new();
+    let mut not_yet_valid = Vec::new();
+    for kind in MANDATORY_DOCUMENTS {
+        let of_kind: Vec<&VehicleDocument> = documents
+            .iter()
+            .filter(|document| document.kind == kind)
+            .collect();
+        if of_kind.is_empty() {
+            missing.push(kind);
+        } else if of_kind
+            .iter()
+            .any(|document| document.issued_at <= at && at < document.expires_at)
+        {
+            continue;
+        } else if of_kind.iter().any(|document| document.issued_at > at) {
+            not_yet_valid.push(kind);
+        } else {
+            expired.push(kind);
+        }
+    }
+
+    VehicleCompliance {
+        vehicle_id: vehicle.id,
+        registration_number: vehicle.registration_number.clone(),
+        missing,
+        expired,
+        not_yet_valid,
+    }
+}
+
+// Function to configure the hours-of-service rules
+#[ic_cdk::update]
+fn set_hours_of_service_rules(rules: HoursOfServiceRules) -> Result<HoursOfServiceRules, Message> {
+    require_role(&[UserRole::Admin])?;
+
+    if rules.max_daily_driving_secs == 0
+        || rules.max_weekly_driving_secs < rules.max_daily_driving_secs
+    {
+        return Err(Message::InvalidPayload(
+            "Ensure 'max_daily_driving_secs' is set and does not exceed 'max_weekly_driving_secs'."
+                .to_string(),
+        ));
+    }
+    if [rules.max_weekly_driving_secs, rules.min_rest_secs]
+        .iter()
+        .any(|secs| secs.checked_mul(NANOS_PER_SECOND).is_none())
+    {
+        return Err(Message::InvalidPayload(
+            "Ensure 'max_weekly_driving_secs' and 'min_rest_secs' are at most 584 years."
+                .to_string(),
+        ));
+    }
+
+    let before = HOURS_OF_SERVICE_RULES.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let before = snapshot(cell.get());
+        cell.set(rules.clone())
+            .expect("Cannot update the hours-of-service rules");
+        before
+    });
+    record_audit(
+        "set_hours_of_service_rules",
+        "hours_of_service_rules",
+        0,
+        Some(before),
+        Some(snapshot(&rules)),
+    );
+    Ok(rules)
+}
+
+// Function to get the hours-of-service rules
+#[ic_cdk::query]
+fn get_hours_of_service_rules() -> HoursOfServiceRules {
+    HOURS_OF_SERVICE_RULES.with(|cell| cell.borrow().get().clone())
+}
+
+// Function to report how long a driver drove, or is booked to drive, within a period
+#[ic_cdk::query]
+fn get_driver_hours(driver_id: u64, period: ReportPeriod) -> Result<DriverHoursReport, Message> {
+    if period.end <= period.start {
+        return Err(Message::InvalidPayload(
+            "Ensure the period's 'end' is after its 'start'.".to_string(),
+        ));
+    }
+    if period.end - period.start > MAX_HOURS_REPORT_DAYS * DAY_SECS * NANOS_PER_SECOND {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure the period spans at most {} days.",
+            MAX_HOURS_REPORT_DAYS
+        )));
+    }
+    if !DRIVER_STORAGE.with(|storage| storage.borrow().contains_key(&driver_id)) {
+        return Err(Message::NotFound("Driver not found".to_string()));
+    }
+
+    let rules = HOURS_OF_SERVICE_RULES.with(|cell| cell.borrow().get().clone());
+    let intervals: Vec<(u64, u64)> = driving_intervals(driver_id)
+        .into_iter()
+        .filter(|&(start, end)| overlap(start, end, period.start, period.end) > 0)
+        .collect();
+
+    let day = DAY_SECS * NANOS_PER_SECOND;
+    let mut daily = Vec::new();
+    let mut day_start = period.start - period.start % day;
+    while day_start < period.end {
+        let day_end = day_start.saturating_add(day); // the last day ends with the timestamp range
+        let driving: u64 = intervals
+            .iter()
+            .map(|&(start, end)| {
+                overlap(
+                    start,
+                    end,
+                    day_start.max(period.start),
+                    day_end.min(period.end),
+                )
+            })
+            .sum();
+        daily.push(DailyDrivingTime {
+            day_start,
+            driving_secs: driving / NANOS_PER_SECOND,
+        });
+        day_start = day_end;
+    }
+
+    Ok(DriverHoursReport {
+        driver_id,
+        period,
+        driving_secs: daily.iter().map(|day| day.driving_secs).sum(),
+        booking_count: intervals.len() as u64,
+        days_over_daily_limit: daily
+            .iter()
+            .filter(|day| day.driving_secs > rules.max_daily_driving_secs)
+            .count() as u64,
+        daily,
+    })
+}
+
+// Helper function to collect the times a driver is driving or booked to drive,
+// using a trip's actual times where one exists
+fn driving_intervals(driver_id: u64) -> Vec<(u64, u64)> {
+    let bookings: Vec<Booking> = BOOKING_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, booking)| booking)
+            .filter(|booking| {
+                booking.driver_id == driver_id
+                    && booking.status != "cancelled"
+                    && booking.status != "no_show"
+            })
+            .collect()
+    });
+
+    bookings
+        .iter()
+        .map(|booking| match find_trip(booking.id) {
+            Some(trip) => (
+                trip.start_time,
+                trip.end_time
+                    .unwrap_or_else(|| booking.end_time.max(current_time())),
+            ),
+            None => (booking.start_time, booking.end_time),
+        })
+        .collect()
+}
+
+// Helper function to check a new booking against the hours-of-service rules;
+// returns the rules it would break, or an error if the driver is already booked
+fn check_hours_of_service(driver_id: u64, start: u64, end: u64) -> Result<Vec<String>, Message> {
+    let rules = HOURS_OF_SERVICE_RULES.with(|cell| cell.borrow().get().clone());
+    let intervals = driving_intervals(driver_id);
+
+    if intervals
+        .iter()
+        .any(|&(other_start, other_end)| overlap(start, end, other_start, other_end) > 0)
+    {
+        return Err(Message::InvalidPayload(
+            "Driver is already booked during this time.".to_string(),
+        ));
+    }
+
+    // Driving time within the busiest rolling window overlapping the new booking;
+    // that window starts or ends at the edge of one of the bookings
+    let mut violations = Vec::new();
+    let driving_within = |window_secs: u64| -> u64 {
+        let window = window_secs.saturating_mul(NANOS_PER_SECOND);
+        let nearby: Vec<(u64, u64)> = intervals
+            .iter()
+            .copied()
+            .chain([(start, end)])
+            .filter(|&(other_start, other_end)| {
+                overlap(
+                    other_start,
+                    other_end,
+                    start.saturating_sub(window),
+                    end.saturating_add(window),
+                ) > 0
+            })
+            .collect();
+        nearby
+            .iter()
+            .flat_map(|&(other_start, other_end)| {
+                [
+                    other_start,
+                    other_end,
+                    other_start.saturating_sub(window),
+                    other_end.saturating_sub(window),
+                ]
+            })
+            .filter(|&window_start| {
+                start.saturating_sub(window) <= window_start && window_start <= end
+            })
+            .map(|window_start| {
+                let window_end = window_start.saturating_add(window);
+                nearby
+                    .iter()
+                    .map(|&(other_start, other_end)| {
+                        overlap(other_start, other_end, window_start, window_end)
+                    })
+                    .sum::<u64>()
+            })
+            .max()
+            .unwrap_or(0)
+            / NANOS_PER_SECOND
+    };
+
+    let daily = driving_within(DAY_SECS);
+    if daily > rules.max_daily_driving_secs {
+        violations.push(format!(
+            "Driver would drive {}s within 24 hours, above the {}s limit.",
+            daily, rules.max_daily_driving_secs
+        ));
+    }
+    let weekly = driving_within(WEEK_SECS);
+    if weekly > rules.max_weekly_driving_secs {
+        violations.push(format!(
+            "Driver would drive {}s within 7 days, above the {}s limit.",
+            weekly, rules.max_weekly_driving_secs
+        ));
+    }
+
+    let min_rest = rules.min_rest_secs.saturating_mul(NANOS_PER_SECOND);
+    if intervals.iter().any(|&(other_start, other_end)| {
+        (other_end <= start && start - other_end < min_rest)
+            || (end <= other_start && other_start - end < min_rest)
+    }) {
+        violations.push(format!(
+            "Driver would rest less than the required {}s between bookings.",
+            rules.min_rest_secs
+        ));
+    }
+    Ok(violations)
+}
+
+// Helper function to get how much of [start, end) falls within [from, to)
+fn overlap(start: u64, end: u64, from: u64, to: u64) -> u64 {
+    end.min(to).saturating_sub(start.max(from))
+}
+
+// Function to schedule a shift for a driver
+#[ic_cdk::update]
+fn create_driver_shift(payload: DriverShiftPayload) -> Result<DriverShift, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    if payload.end_time <= payload.start_time {
+        return Err(Message::InvalidPayload(
+            "Ensure 'end_time' is after 'start_time'.".to_string(),
+        ));
+    }
+    if !DRIVER_STORAGE.with(|storage| storage.borrow().contains_key(&payload.driver_id)) {
+        return Err(Message::NotFound("Driver not found".to_string()));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let shift = DriverShift {
+        id,
+        driver_id: payload.driver_id,
+        start_time: payload.start_time,
+        end_time: payload.end_time,
+        created_at: current_time(),
+    };
+
+    SHIFT_STORAGE.with(|storage| storage.borrow_mut().insert(id, shift.clone()));
+    record_audit(
+        "create_driver_shift",
+        "driver_shift",
+        id,
+        None,
+        Some(snapshot(&shift)),
+    );
+    Ok(shift)
+}
+
+// Function to get the shifts scheduled for a driver
+#[ic_cdk::query]
+fn get_driver_shifts(driver_id: u64) -> Result<Vec<DriverShift>, Message> {
+    let shifts = driver_shifts(driver_id);
+    if shifts.is_empty() {
+        Err(Message::NotFound("No shifts found".to_string()))
+    } else {
+        Ok(shifts)
+    }
+}
+
+// Function to request a day off or leave for a driver
+#[ic_cdk::update]
+fn request_leave(payload: LeaveRequestPayload) -> Result<LeaveRequest, Message> {
+    require_driver_access(payload.driver_id)?;
+
+    if payload.end_time <= payload.start_time || payload.reason.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'reason' is provided and 'end_time' is after 'start_time'.".to_string(),
+        ));
+    }
+    if payload.reason.len() > MAX_TEXT_LENGTH {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'reason' is at most {} bytes.",
+            MAX_TEXT_LENGTH
+        )));
+    }
+    if !DRIVER_STORAGE.with(|storage| storage.borrow().contains_key(&payload.driver_id)) {
+        return Err(Message::NotFound("Driver not found".to_string()));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let request = LeaveRequest {
+        id,
+        driver_id: payload.driver_id,
+        kind: payload.kind,
+        start_time: payload.start_time,
+        end_time: payload.end_time,
+        reason: payload.reason,
+        status: LeaveStatus::Pending,
+        requested_by: caller(),
+        reviewed_by: None,
+        reviewed_at: None,
+        created_at: current_time(),
+    };
+
+    LEAVE_STORAGE.with(|storage| storage.borrow_mut().insert(id, request.clone()));
+    record_audit(
+        "request_leave",
+        "leave_request",
+        id,
+        None,
+        Some(snapshot(&request)),
+    );
+    Ok(request)
+}
+
+// Function for a manager to approve or reject a pending leave request
+#[ic_cdk::update]
+fn review_leave_request(id: u64, approve: bool) -> Result<LeaveRequest, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let request = LEAVE_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Leave request not found".to_string()))?;
+    if request.status != LeaveStatus::Pending {
+        return Err(Message::InvalidPayload(
+            "Leave request has already been reviewed.".to_string(),
+        ));
+    }
+
+    let before = snapshot(&request);
+    let reviewed = LeaveRequest {
+        status: if approve {
+            LeaveStatus::Approved
+        } else {
+            LeaveStatus::Rejected
+        },
+        reviewed_by: Some(caller()),
+        reviewed_at: Some(current_time()),
+        ..request
+    };
+
+    LEAVE_STORAGE.with(|storage| storage.borrow_mut().insert(id, reviewed.clone()));
+    record_audit(
+        "review_leave_request",
+        "leave_request",
+        id,
+        Some(before),
+        Some(snapshot(&reviewed)),
+    );
+    Ok(reviewed)
+}
+
+// Function to get leave requests, optionally for one driver and/or status;
+// drivers only see their own
+#[ic_cdk::query]
+fn get_leave_requests(
+    driver_id: Option<u64>,
+    status: Option<LeaveStatus>,
+) -> Result<Vec<LeaveRequest>, Message> {
+    let driver_id = match caller_role() {
+        UserRole::Admin | UserRole::Manager => driver_id,
+        _ => {
+            let own = caller_driver_id().ok_or(Message::Unauthorized(
+                "Caller is not allowed to view leave requests.".to_string(),
+            ))?;
+            if driver_id.is_some_and(|driver_id| driver_id != own) {
+                return Err(Message::Unauthorized(
+                    "Drivers can only view their own leave requests.".to_string(),
+                ));
+            }
+            Some(own)
+        }
+    };
+
+    LEAVE_STORAGE.with(|storage| {
+        let requests: Vec<LeaveRequest> = storage
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| {
+                driver_id.is_none_or(|driver_id| request.driver_id == driver_id)
+                    && status.is_none_or(|status| request.status == status)
+            })
+            .collect();
+        if requests.is_empty() {
+            Err(Message::NotFound("No leave requests found".to_string()))
+        } else {
+            Ok(requests)
+        }
+    })
+}
+
+// Function to get the drivers free to drive throughout a time window
+#[ic_cdk::query]
+fn get_available_drivers(start_time: u64, end_time: u64) -> Result<Vec<Driver>, Message> {
+    if end_time <= start_time {
+        return Err(Message::InvalidPayload(
+            "Ensure 'end_time' is after 'start_time'.".to_string(),
+        ));
+    }
+
+    let drivers: Vec<Driver> =
+        DRIVER_STORAGE.with(|storage| storage.borrow().iter().map(|(_, driver)| driver).collect());
+    let available: Vec<Driver> = drivers
+        .into_iter()
+        .filter(|driver| driver_is_free(driver.id, start_time, end_time))
+        .collect();
+    if available.is_empty() {
+        Err(Message::NotFound("No available drivers found".to_string()))
+    } else {
+        Ok(available)
+    }
+}
+
+// Helper function to tell whether a driver's calendar allows a time window and
+// the driver is not already booked during it
+fn driver_is_free(driver_id: u64, start: u64, end: u64) -> bool {
+    check_driver_calendar(driver_id, start, end).is_ok()
+        && driving_intervals(driver_id)
+            .iter()
+            .all(|&(other_start, other_end)| overlap(start, end, other_start, other_end) == 0)
+}
+
+// Helper function to collect the shifts scheduled for a driver
+fn driver_shifts(driver_id: u64) -> Vec<DriverShift> {
+    SHIFT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, shift)| shift)
+            .filter(|shift| shift.driver_id == driver_id)
+            .collect()
+    })
+}
+
+// Helper function to check a time window against a driver's shifts and approved
+// leave; drivers without any shifts on record are not restricted by shifts
+fn check_driver_calendar(driver_id: u64, start: u64, end: u64) -> Result<(), String> {
+    let mut shifts: Vec<(u64, u64)> = driver_shifts(driver_id)
+        .iter()
+        .map(|shift| (shift.start_time, shift.end_time))
+        .collect();
+    if !shifts.is_empty() {
+        // Back-to-back or overlapping shifts cover the time between them
+        shifts.sort_unstable();
+        let mut covered: Vec<(u64, u64)> = Vec::new();
+        for (shift_start, shift_end) in shifts {
+            match covered.last_mut() {
+                Some((_, covered_end)) if shift_start <= *covered_end => {
+                    *covered_end = (*covered_end).max(shift_end);
+                }
+                _ => covered.push((shift_start, shift_end)),
+            }
+        }
+        if !covered
+            .iter()
+            .any(|&(shift_start, shift_end)| shift_start <= start && end <= shift_end)
+        {
+            return Err("Driver has no shift covering this time.".to_string());
+        }
+    }
+
+    let on_leave = LEAVE_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, request)| {
+            request.driver_id == driver_id
+                && request.status == LeaveStatus::Approved
+                && overlap(start, end, request.start_time, request.end_time) > 0
+        })
+    });
+    if on_leave {
+        return Err("Driver is on leave during this time.".to_string());
+    }
+    Ok(())
+}
+
+// Function to import a chunk of vehicles from CSV or JSON
+#[ic_cdk::update]
+fn bulk_import_vehicles(payload: BulkImportPayload) -> Result<BulkImportReport, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    bulk_import(payload, vehicle_from_csv, validate_vehicle, |payload| {
+        insert_vehicle("bulk_import_vehicles", payload).id
+    })
+}
+
+// Function to import a chunk of drivers from CSV or JSON
+#[ic_cdk::update]
+fn bulk_import_drivers(payload: BulkImportPayload) -> Result<BulkImportReport, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    bulk_import(payload, driver_from_csv, validate_driver, |payload| {
+        insert_driver("bulk_import_drivers", payload).id
+    })
+}
+
+// Helper function to parse, validate and import a chunk of records; every row is
+// validated before any is imported, so all-or-nothing imports leave no trace on error
+fn bulk_import<T: serde::de::DeserializeOwned>(
+    payload: BulkImportPayload,
+    from_csv: fn(&HashMap<String, String>) -> Result<T, String>,
+    validate: fn(&T) -> Result<(), Message>,
+    insert: fn(T) -> u64,
+) -> Result<BulkImportReport, Message> {
+    let rows: Vec<Result<T, String>> = match payload.format {
+        ImportFormat::Json => serde_json::from_str::<Vec<serde_json::Value>>(&payload.data)
+            .map_err(|error| Message::InvalidPayload(format!("Invalid JSON: {}", error)))?
+            .into_iter()
+            .map(|value| serde_json::from_value(value).map_err(|error| error.to_string()))
+            .collect(),
+        ImportFormat::Csv => {
+            let mut lines = payload.data.lines().filter(|line| !line.trim().is_empty());
+            let header = lines.next().map(split_csv_line).unwrap_or_default();
+            lines
+                .map(|line| {
+                    let fields = split_csv_line(line);
+                    if fields.len() != header.len() {
+                        return Err(format!(
+                            "Expected {} fields, found {}.",
+                            header.len(),
+                            fields.len()
+                        ));
+                    }
+                    from_csv(&header.iter().cloned().zip(fields).collect())
+                })
+                .collect()
+        }
+    };
+    if rows.len() > MAX_IMPORT_ROWS {
+        return Err(Message::InvalidPayload(format!(
+            "Split the import into chunks of at most {} records.",
+            MAX_IMPORT_ROWS
+        )));
+    }
+
+    let mut errors = Vec::new();
+    let mut valid = Vec::new();
+    for (index, row) in rows.into_iter().enumerate() {
+        let checked =
+            row.and_then(|record| validate(&record).map(|()| record).map_err(Message::text));
+        match checked {
+            Ok(record) => valid.push(record),
+            Err(message) => errors.push(ImportRowError {
+                row: payload.first_row + index as u64,
+                message,
+            }),
+        }
+    }
+
+    let rows = (valid.len() + errors.len()) as u64;
+    let valid_count = valid.len() as u64;
+    let skip = payload.dry_run || (payload.all_or_nothing && !errors.is_empty());
+    let imported = if skip {
+        Vec::new()
+    } else {
+        valid.into_iter().map(insert).collect()
+    };
+    Ok(BulkImportReport {
+        rows,
+        valid: valid_count,
+        imported,
+        errors,
+        dry_run: payload.dry_run,
+    })
+}
+
+// Helper function to build a vehicle payload from a CSV record; the acquisition
+// columns are optional and read only when 'acquisition_cost' is filled in
+fn vehicle_from_csv(record: &HashMap<String, String>) -> Result<VehiclePayload, String> {
+    let acquisition = match csv_optional(record, "acquisition_cost")? {
+        Some(cost) => Some(VehicleAcquisition {
+            cost,
+            acquired_at: csv_number(record, "acquired_at")?,
+            residual_value: csv_optional(record, "residual_value")?.unwrap_or_default(),
+            useful_life_years: csv_number(record, "useful_life_years")?,
+        }),
+        None => None,
+    };
+    Ok(VehiclePayload {
+        registration_number: csv_text(record, "registration_number"),
+        model: csv_text(record, "model"),
+        capacity: csv_number(record, "capacity")?,
+        location: csv_text(record, "location"),
+        department_id: csv_optional(record, "department_id")?,
+        acquisition,
+        profile: None, // profiles come through JSON imports or set_vehicle_profile
+    })
+}
+
+// Helper function to build a driver payload from a CSV record
+fn driver_from_csv(record: &HashMap<String, String>) -> Result<DriverPayload, String> {
+    Ok(DriverPayload {
+        name: csv_text(record, "name"),
+        license_number: csv_text(record, "license_number"),
+        contact_info: csv_text(record, "contact_info"),
+        license_expiry: csv_optional(record, "license_expiry")?,
+    })
+}
+
+// Helper function to read a text column of a CSV record; missing columns read as empty
+fn csv_text(record: &HashMap<String, String>, column: &str) -> String {
+    record.get(column).cloned().unwrap_or_default()
+}
+
+// Helper function to read a required number column of a CSV record
+fn csv_number(record: &HashMap<String, String>, column: &str) -> Result<u64, String> {
+    csv_optional(record, column)?.ok_or(format!("Column '{}' is required.", column))
+}
+
+// Helper function to read an optional number column of a CSV record
+fn csv_optional(record: &HashMap<String, String>, column: &str) -> Result<Option<u64>, String> {
+    match record.get(column).map(String::as_str) {
+        None | Some("") => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Column '{}' is not a number: '{}'.", column, value)),
+    }
+}
+
+// Helper function to split a CSV line into trimmed fields; fields may be
+// double-quoted to hold commas, with "" standing for a quote
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+        .into_iter()
+        .map(|field| field.trim().to_string())
+        .collect()
+}
+
+// Helper function to apply the rules every new driver must meet
+fn validate_driver(payload: &DriverPayload) -> Result<(), Message> {
+    if payload.name.is_empty()
+        || payload.license_number.is_empty()
+        || payload.contact_info.is_empty()
+    {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name', 'license_number', and 'contact_info' are provided.".to_string(),
+        ));
+    }
+    if payload.name.len() > MAX_NAME_LENGTH
+        || payload.license_number.len() > MAX_NAME_LENGTH
+        || payload.contact_info.len() > MAX_TEXT_LENGTH
+    {
+        return Err(Message::InvalidPayload(format!(
+            "Keep 'name' and 'license_number' within {} bytes and 'contact_info' within {}.",
+            MAX_NAME_LENGTH, MAX_TEXT_LENGTH
+        )));
+    }
+
+    // Validate the driver's license number by checking if it exists in any vehicle
+    let license_exists = VEHICLE_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, vehicle)| vehicle.registration_number == payload.license_number)
+    });
+
+    if !license_exists {
+        return Err(Message::InvalidPayload(
+            "Driver's license number does not exist in any vehicle.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Helper function to store a validated driver
+fn insert_driver(endpoint: &str, payload: DriverPayload) -> Driver {
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let driver = Driver {
+        id,
+        name: payload.name,
+        license_number: payload.license_number,
+        contact_info: payload.contact_info,
+        license_expiry: payload.license_expiry,
+        points: 0,
+        created_at: current_time(),
+    };
+
+    DRIVER_STORAGE.with(|storage| storage.borrow_mut().insert(id, driver.clone()));
+    certify("drivers", id, &driver);
+    record_audit(endpoint, "driver", id, None, Some(snapshot(&driver)));
+    driver
+}
+
+// Helper function to apply the rules every new vehicle must meet
+fn validate_vehicle(payload: &VehiclePayload) -> Result<(), Message> {
+    if payload.registration_number.is_empty()
+        || payload.model.is_empty()
+        || payload.capacity == 0
+        || payload.location.is_empty()
+    {
+        return Err(Message::InvalidPayload(
+            "Ensure 'registration_number', 'model', 'capacity', and 'location' are provided."
+                .to_string(),
+        ));
+    }
+    if payload.registration_number.len() > MAX_NAME_LENGTH
+        || payload.model.len() > MAX_NAME_LENGTH
+        || payload.location.len() > MAX_TEXT_LENGTH
+    {
+        return Err(Message::InvalidPayload(format!(
+            "Keep 'registration_number' and 'model' within {} bytes and 'location' within {}.",
+            MAX_NAME_LENGTH, MAX_TEXT_LENGTH
+        )));
+    }
+
+    // Validate the owning department, if one was given
+    if let Some(department_id) = payload.department_id {
+        if !DEPARTMENT_STORAGE.with(|storage| storage.borrow().contains_key(&department_id)) {
+            return Err(Message::NotFound("Department not found".to_string()));
+        }
+    }
+    if let Some(acquisition) = &payload.acquisition {
+        validate_acquisition(acquisition)?;
+    }
+    if let Some(profile) = &payload.profile {
+        validate_profile(profile)?;
+    }
+    Ok(())
+}
+
+// Helper function to store a validated vehicle
+fn insert_vehicle(endpoint: &str, payload: VehiclePayload) -> Vehicle {
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let vehicle = Vehicle {
+        id,
+        registration_number: payload.registration_number,
+        model: payload.model,
+        capacity: payload.capacity,
+        status: VehicleStatus::Available,
+        location: payload.location,
+        department_id: payload.department_id,
+        acquisition: payload.acquisition,
+        profile: payload.profile.map(dedupe_equipment),
+        state_of_charge: None,
+        state_of_charge_at: None,
+        created_at: current_time(),
+    };
+
+    VEHICLE_STORAGE.with(|storage| storage.borrow_mut().insert(id, vehicle.clone()));
+    certify("vehicles", id, &vehicle);
+    record_audit(endpoint, "vehicle", id, None, Some(snapshot(&vehicle)));
+    emit_event(FleetEventKind::VehicleCreated(vehicle.clone()));
+    vehicle
+}
+
+// Function to create a department
+#[ic_cdk::update]
+fn create_department(payload: DepartmentPayload) -> Result<Department, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    if payload.name.is_empty() || payload.cost_centre.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name' and 'cost_centre' are provided.".to_string(),
+        ));
+    }
+    if payload.name.len() > MAX_NAME_LENGTH || payload.cost_centre.len() > MAX_NAME_LENGTH {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'name' and 'cost_centre' are at most {} bytes.",
+            MAX_NAME_LENGTH
+        )));
+    }
+    let duplicate = DEPARTMENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .any(|(_, department)| department.cost_centre == payload.cost_centre)
+    });
+    if duplicate {
+        return Err(Message::InvalidPayload(
+            "A department with this cost centre already exists.".to_string(),
+        ));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let department = Department {
+        id,
+        name: payload.name,
+        cost_centre: payload.cost_centre,
+        monthly_budget: payload.monthly_budget,
+        budget_alerted_month: None,
+        created_at: current_time(),
+    };
+
+    DEPARTMENT_STORAGE.with(|storage| storage.borrow_mut().insert(id, department.clone()));
+    record_audit(
+        "create_department",
+        "department",
+        id,
+        None,
+        Some(snapshot(&department)),
+    );
+    Ok(department)
+}
+
+// Function to change the monthly budget of a department
+#[ic_cdk::update]
+fn set_department_budget(id: u64, monthly_budget: u64) -> Result<Department, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let department = DEPARTMENT_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Department not found".to_string()))?;
+
+    let before = snapshot(&department);
+    let updated = Department {
+        monthly_budget,
+        budget_alerted_month: None,
+        ..department
+    };
+    DEPARTMENT_STORAGE.with(|storage| storage.borrow_mut().insert(id, updated.clone()));
+    record_audit(
+        "set_department_budget",
+        "department",
+        id,
+        Some(before),
+        Some(snapshot(&updated)),
+    );
+    Ok(updated)
+}
+
+// Function to get all departments
+#[ic_cdk::query]
+fn get_departments() -> Result<Vec<Department>, Message> {
+    DEPARTMENT_STORAGE.with(|storage| {
+        let departments: Vec<Department> = storage
+            .borrow()
+            .iter()
+            .map(|(_, department)| department)
+            .collect();
+        if departments.is_empty() {
+            Err(Message::NotFound("No departments found".to_string()))
+        } else {
+            Ok(departments)
+        }
+    })
+}
+
+// Function to move a vehicle to another department, or out of any department
+#[ic_cdk::update]
+fn assign_vehicle_department(
+    vehicle_id: u64,
+    department_id: Option<u64>,
+) -> Result<Vehicle, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let vehicle = VEHICLE_STORAGE
+        .with(|storage| storage.borrow().get(&vehicle_id))
+        .ok_or(Message::NotFound("Vehicle not found".to_string()))?;
+    if let Some(department_id) = department_id {
+        if !DEPARTMENT_STORAGE.with(|storage| storage.borrow().contains_key(&department_id)) {
+            return Err(Message::NotFound("Department not found".to_string()));
+        }
+    }
+
+    let before = snapshot(&vehicle);
+    let updated = Vehicle {
+        department_id,
+        ..vehicle
+    };
+    VEHICLE_STORAGE.with(|storage| storage.borrow_mut().insert(vehicle_id, updated.clone()));
+    certify("vehicles", vehicle_id, &updated);
+    record_audit(
+        "assign_vehicle_department",
+        "vehicle",
+        vehicle_id,
+        Some(before),
+        Some(snapshot(&updated)),
+    );
+    Ok(updated)
+}
+
+// Function to report a department's costs for a calendar month (UTC)
+#[ic_cdk::query]
+fn get_department_costs(
+    department_id: u64,
+    year: i32,
+    month: u32,
+) -> Result<DepartmentCostReport, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let department = DEPARTMENT_STORAGE
+        .with(|storage| storage.borrow().get(&department_id))
+        .ok_or(Message::NotFound("Department not found".to_string()))?;
+    let period = month_period(year, month).ok_or(Message::InvalidPayload(
+        "Ensure 'year' and 'month' form a valid month from 1970 on.".to_string(),
+    ))?;
+    Ok(department_costs(&department, period))
+}
+
+// Helper function to read the costs charged to a department in a calendar
+// month from the monthly rollups
+fn department_costs(department: &Department, period: ReportPeriod) -> DepartmentCostReport {
+    let cost = |metric: RollupMetric| {
+        rollup_value(RollupKey::new(
+            RollupScope::Department,
+            department.id,
+            metric,
+            RollupGranularity::Month,
+            period.start,
+        ))
+        .total as u64
+    };
+    let fuel_cost = cost(RollupMetric::FuelCost);
+    let charging_cost = cost(RollupMetric::ChargingCost);
+    let maintenance_cost = cost(RollupMetric::MaintenanceCost);
+    let trip_cost = cost(RollupMetric::TripCost);
+
+    let total = fuel_cost
+        .saturating_add(charging_cost)
+        .saturating_add(maintenance_cost)
+        .saturating_add(trip_cost);
+    DepartmentCostReport {
+        department_id: department.id,
+        period,
+        fuel_cost,
+        charging_cost,
+        maintenance_cost,
+        trip_cost,
+        total,
+        monthly_budget: department.monthly_budget,
+        over_budget: department.monthly_budget > 0 && total > department.monthly_budget,
+    }
+}
+
+// Helper function to alert once per month when a cost pushes a department
+// over its monthly budget
+fn check_department_budget(department_id: u64, at: u64) {
+    let Some(department) = DEPARTMENT_STORAGE.with(|storage| storage.borrow().get(&department_id))
+    else {
+        return;
+    };
+    let (year, month) = month_of(at);
+    let month_key = year as u32 * 100 + month;
+    if department.budget_alerted_month == Some(month_key) {
+        return;
+    }
+    let Some(period) = month_period(year, month) else {
+        return;
+    };
+    let report = department_costs(&department, period);
+    if !report.over_budget {
+        return;
+    }
+
+    let before = snapshot(&department);
+    let updated = Department {
+        budget_alerted_month: Some(month_key),
+        ..department
+    };
+    DEPARTMENT_STORAGE.with(|storage| storage.borrow_mut().insert(department_id, updated.clone()));
+    record_audit(
+        "check_department_budget",
+        "department",
+        department_id,
+        Some(before),
+        Some(snapshot(&updated)),
+    );
+    notify(
+        NotificationTopic::BudgetExceeded,
+        format!(
+            "Department {} ({}) has spent {} against a monthly budget of {} in {}-{:02}.",
+            updated.name, updated.cost_centre, report.total, updated.monthly_budget, year, month
+        ),
+    );
+}
+
+// Helper function to get the bounds of a calendar month (UTC) in nanoseconds;
+// None for months before 1970 or past what nanoseconds in a u64 can hold
+fn month_period(year: i32, month: u32) -> Option<ReportPeriod> {
+    let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let next = first.checked_add_months(Months::new(1))?;
+    let nanos = |date: NaiveDate| {
+        let seconds = date.and_hms_opt(0, 0, 0)?.and_utc().timestamp();
+        u64::try_from(seconds).ok()?.checked_mul(NANOS_PER_SECOND)
+    };
+    Some(ReportPeriod {
+        start: nanos(first)?,
+        end: nanos(next)? - 1,
+    })
+}
+
+// Helper function to get the calendar month (UTC) of a timestamp
+fn month_of(timestamp: u64) -> (i32, u32) {
+    let date = chrono::DateTime::from_timestamp((timestamp / NANOS_PER_SECOND) as i64, 0)
+        .unwrap_or_default()
+        .date_naive();
+    (date.year(), date.month())
+}
+
+// Function to read a rollup series within a period
+#[ic_cdk::query]
+fn get_rollups(
+    scope: RollupScope,
+    entity_id: u64,
+    metric: RollupMetric,
+    granularity: RollupGranularity,
+    period: ReportPeriod,
+) -> Result<Vec<RollupPoint>, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    if period.end < period.start {
+        return Err(Message::InvalidPayload(
+            "Ensure 'end' is not before 'start'.".to_string(),
+        ));
+    }
+    let from = RollupKey::new(
+        scope,
+        entity_id,
+        metric,
+        granularity,
+        bucket_start(granularity, period.start),
+    );
+    let to = RollupKey::new(scope, entity_id, metric, granularity, period.end);
+    let points: Vec<RollupPoint> = ROLLUP_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(from..=to)
+            .map(|(key, value)| RollupPoint {
+                bucket_start: key.bucket_start,
+                total: value.total,
+                count: value.count,
+            })
+            .collect()
+    });
+    if points.is_empty() {
+        Err(Message::NotFound("No rollups found".to_string()))
+    } else {
+        Ok(points)
+    }
+}
+
+// Function to rebuild every rollup from the stores, e.g. after a migration; the
+// rebuild runs in batches on a timer, resumes after upgrades and starts over
+// if called again. Fuel and maintenance are charged to the department owning
+// the vehicle now
+#[ic_cdk::update]
+fn rebuild_rollups() -> Result<RollupRebuild, Message> {
+    require_role(&[UserRole::Admin])?;
+
+    let rebuild = start_rollup_rebuild();
+    record_audit(
+        "rebuild_rollups",
+        "rollups",
+        0,
+        None,
+        Some(snapshot(&rebuild)),
+    );
+    Ok(rebuild)
+}
+
+// Function to get the progress of the last rollup rebuild
+#[ic_cdk::query]
+fn get_rollup_rebuild_status() -> Result<RollupRebuild, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    Ok(ROLLUP_REBUILD.with(|cell| *cell.borrow().get()))
+}
+
+// Helper function to start rebuilding the rollups from scratch
+fn start_rollup_rebuild() -> RollupRebuild {
+    let rebuild = RollupRebuild {
+        stage: Some(RollupRebuildStage::Clear),
+        next_id: 0,
+        replayed: 0,
+        started_at: Some(current_time()),
+        finished_at: None,
+    };
+    ROLLUP_REBUILD.with(|cell| {
+        cell.borrow_mut()
+            .set(rebuild)
+            .expect("Cannot update the rollup rebuild state")
+    });
+    schedule_rollup_rebuild();
+    rebuild
+}
+
+// Helper function to replay the next batch of a running rollup rebuild, then
+// schedule the batch after it
+fn continue_rollup_rebuild() {
+    let mut rebuild = ROLLUP_REBUILD.with(|cell| *cell.borrow().get());
+    let vehicle_department = |vehicle_id: u64| {
+        VEHICLE_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .get(&vehicle_id)
+                .and_then(|vehicle| vehicle.department_id)
+        })
+    };
+
+    let mut budget = ROLLUP_REBUILD_BATCH_SIZE;
+    while let Some(stage) = rebuild.stage {
+        let next_id = rebuild.next_id;
+        let (done, last_id) = match stage {
+            RollupRebuildStage::Clear => (clear_rollups(budget), None),
+            RollupRebuildStage::Fuel => {
+                replay_batch(&FUEL_CONSUMPTION_STORAGE, next_id, budget, |fuel| {
+                    rollup_fuel(fuel, vehicle_department(fuel.vehicle_id))
+                })
+            }
+            RollupRebuildStage::Charging => {
+                replay_batch(&CHARGING_STORAGE, next_id, budget, |session| {
+                    rollup_charging(session, vehicle_department(session.vehicle_id))
+                })
+            }
+            RollupRebuildStage::Maintenance => {
+                replay_batch(&MAINTENANCE_STORAGE, next_id, budget, |maintenance| {
+                    rollup_maintenance(maintenance, vehicle_department(maintenance.vehicle_id))
+                })
+            }
+            RollupRebuildStage::Trips => replay_batch(&TRIP_STORAGE, next_id, budget, rollup_trip),
+            RollupRebuildStage::Documents => {
+                replay_batch(&DOCUMENT_STORAGE, next_id, budget, rollup_document)
+            }
+            RollupRebuildStage::Incidents => replay_batch(
+                &EMERGENCY_ASSISTANCE_STORAGE,
+                next_id,
+                budget,
+                rollup_incident,
+            ),
+            RollupRebuildStage::Invoices => {
+                replay_batch(&INVOICE_STORAGE, next_id, budget, |invoice| {
+                    if let Some(booking) =
+                        BOOKING_STORAGE.with(|storage| storage.borrow().get(&invoice.booking_id))
+                    {
+                        rollup_invoice(invoice, &booking);
+                    }
+                })
+            }
+            RollupRebuildStage::Bookings => {
+                replay_batch(&BOOKING_STORAGE, next_id, budget, |booking| {
+                    rollup_booking(booking, 1.0)
+                })
+            }
+        };
+        if stage != RollupRebuildStage::Clear {
+            rebuild.replayed += done as u64;
+        }
+        budget -= done;
+        if budget == 0 {
+            // The stage may have more records; resume after the last one
+            if let Some(last_id) = last_id {
+                rebuild.next_id = last_id + 1;
+            }
+            break;
+        }
+        rebuild.stage = next_rebuild_stage(stage);
+        rebuild.next_id = 0;
+    }
+
+    if rebuild.stage.is_none() {
+        rebuild.finished_at = Some(current_time());
+        record_audit(
+            "rebuild_rollups",
+            "rollups",
+            0,
+            None,
+            Some(snapshot(&rebuild)),
+        );
+    }
+    ROLLUP_REBUILD.with(|cell| {
+        cell.borrow_mut()
+            .set(rebuild)
+            .expect("Cannot update the rollup rebuild state")
+    });
+    if rebuild.stage.is_some() {
+        schedule_rollup_rebuild();
+    }
+}
+
+// Helper function to get the stage a rollup rebuild moves on to
+fn next_rebuild_stage(stage: RollupRebuildStage) -> Option<RollupRebuildStage> {
+    match stage {
+        RollupRebuildStage::Clear => Some(RollupRebuildStage::Fuel),
+        RollupRebuildStage::Fuel => Some(RollupRebuildStage::Charging),
+        RollupRebuildStage::Charging => Some(RollupRebuildStage::Maintenance),
+        RollupRebuildStage::Maintenance => Some(RollupRebuildStage::Trips),
+        RollupRebuildStage::Trips => Some(RollupRebuildStage::Documents),
+        RollupRebuildStage::Documents => Some(RollupRebuildStage::Incidents),
+        RollupRebuildStage::Incidents => Some(RollupRebuildStage::Invoices),
+        RollupRebuildStage::Invoices => Some(RollupRebuildStage::Bookings),
+        RollupRebuildStage::Bookings => None,
+    }
+}
+
+// Helper function to remove up to a number of rollup buckets, returning how
+// many were removed
+fn clear_rollups(limit: usize) -> usize {
+    ROLLUP_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let keys: Vec<RollupKey> = storage.iter().map(|(key, _)| key).take(limit).collect();
+        for key in &keys {
+            storage.remove(key);
+        }
+        keys.len()
+    })
+}
+
+// Helper function to replay a batch of a store's records into the rollups,
+// starting at an ID; returns how many were replayed and the last ID
+fn replay_batch<T: BoundedStorable>(
+    store: &'static LocalKey<RefCell<StableBTreeMap<u64, T, Memory>>>,
+    next_id: u64,
+    limit: usize,
+    replay: impl Fn(&T),
+) -> (usize, Option<u64>) {
+    let records: Vec<(u64, T)> =
+        store.with(|storage| storage.borrow().range(next_id..).take(limit).collect());
+    for (_, record) in &records {
+        replay(record);
+    }
+    (records.len(), records.last().map(|(id, _)| *id))
+}
+
+// Helper function to add a written record to the rollups, unless a running
+// rebuild has yet to reach it and will replay it then
+fn rollup_write(stage: RollupRebuildStage, id: u64, write: impl FnOnce()) {
+    let rebuild = ROLLUP_REBUILD.with(|cell| *cell.borrow().get());
+    let pending = rebuild
+        .stage
+        .is_some_and(|current| current < stage || (current == stage && id >= rebuild.next_id));
+    if !pending {
+        write();
+    }
+}
+
+// Helper function to add a fuel record to the rollups
+fn rollup_fuel(fuel: &FuelConsumption, department_id: Option<u64>) {
+    add_to_rollups(
+        RollupScope::Vehicle,
+        fuel.vehicle_id,
+        RollupMetric::FuelLitres,
+        fuel.date,
+        fuel.amount,
+    );
+    if let Some(cost) = fuel.cost {
+        add_to_rollups(
+            RollupScope::Vehicle,
+            fuel.vehicle_id,
+            RollupMetric::FuelCost,
+            fuel.date,
+            cost as f64,
+        );
+        if let Some(department_id) = department_id {
+            add_to_rollups(
+                RollupScope::Department,
+                department_id,
+                RollupMetric::FuelCost,
+                fuel.date,
+                cost as f64,
+            );
+        }
+    }
+}
+
+// Helper function to add a charging session to the rollups
+fn rollup_charging(session: &ChargingSession, department_id: Option<u64>) {
+    add_to_rollups(
+        RollupScope::Vehicle,
+        session.vehicle_id,
+        RollupMetric::EnergyKwh,
+        session.start_time,
+        session.energy_kwh,
+    );
+    if let Some(cost) = session.cost {
+        add_to_rollups(
+            RollupScope::Vehicle,
+            session.vehicle_id,
+            RollupMetric::ChargingCost,
+            session.start_time,
+            cost as f64,
+        );
+        if let Some(department_id) = department_id {
+            add_to_rollups(
+                RollupScope::Department,
+                department_id,
+                RollupMetric::ChargingCost,
+                session.start_time,
+                cost as f64,
+            );
+        }
+    }
+}
+
+// Helper function to add a maintenance record to the rollups
+fn rollup_maintenance(maintenance: &Maintenance, department_id: Option<u64>) {
+    let Some(cost) = maintenance.cost else {
+        return;
+    };
+    let at = maintenance.scheduled_date;
+    add_to_rollups(
+        RollupScope::Vehicle,
+        maintenance.vehicle_id,
+        RollupMetric::MaintenanceCost,
+        at,
+        cost as f64,
+    );
+    if let Some(department_id) = department_id {
+        add_to_rollups(
+            RollupScope::Department,
+            department_id,
+            RollupMetric::MaintenanceCost,
+            at,
+            cost as f64,
+        );
+    }
+}
+
+// Helper function to add a completed trip to the rollups, in the bucket it started in
+fn rollup_trip(trip: &Trip) {
+    let Some(end_time) = trip.end_time else {
+        return;
+    };
+    let hours = end_time.saturating_sub(trip.start_time) as f64 / (3600 * NANOS_PER_SECOND) as f64;
+    for (scope, entity_id) in [
+        (RollupScope::Vehicle, trip.vehicle_id),
+        (RollupScope::Driver, trip.driver_id),
+    ] {
+        add_to_rollups(
+            scope,
+            entity_id,
+            RollupMetric::Distance,
+            trip.start_time,
+            trip.distance,
+        );
+        add_to_rollups(
+            scope,
+            entity_id,
+            RollupMetric::DrivenHours,
+            trip.start_time,
+            hours,
+        );
+    }
+}
+
+// Helper function to add an invoice to the rollups
+fn rollup_invoice(invoice: &Invoice, booking: &Booking) {
+    let mut series = vec![
+        (RollupScope::Vehicle, invoice.vehicle_id),
+        (RollupScope::Driver, booking.driver_id),
+    ];
+    if let Some(department_id) = booking.department_id {
+        series.push((RollupScope::Department, department_id));
+    }
+    for (scope, entity_id) in series {
+        add_to_rollups(
+            scope,
+            entity_id,
+            RollupMetric::TripCost,
+            invoice.issued_at,
+            invoice.total as f64,
+        );
+    }
+}
+
+// Helper function to add the premium of an insurance document to the rollups
+fn rollup_document(document: &VehicleDocument) {
+    if document.kind == DocumentKind::Insurance && document.cost > 0 {
+        add_to_rollups(
+            RollupScope::Vehicle,
+            document.vehicle_id,
+            RollupMetric::InsuranceCost,
+            document.issued_at,
+            document.cost as f64,
+        );
+    }
+}
+
+// Helper function to add the cost of a resolved emergency to the rollups
+fn rollup_incident(assistance: &EmergencyAssistance) {
+    if let (Some(cost), Some(resolved_at)) = (assistance.cost, assistance.resolved_at) {
+        add_to_rollups(
+            RollupScope::Vehicle,
+            assistance.vehicle_id,
+            RollupMetric::IncidentCost,
+            resolved_at,
+            cost as f64,
+        );
+    }
+}
+
+// Helper function to move a booking between the open and completed booking
+// rollups when its status changes
+fn rollup_booking_change(booking: &Booking, previous_status: Option<&str>) {
+    rollup_write(RollupRebuildStage::Bookings, booking.id, || {
+        if let Some(previous_status) = previous_status {
+            let previous = Booking {
+                status: previous_status.to_string(),
+                ..booking.clone()
+            };
+            rollup_booking(&previous, -1.0);
+        }
+        rollup_booking(booking, 1.0);
+    });
+}
+
+// Helper function to count a booking in the rollup of its status, by start time
+fn rollup_booking(booking: &Booking, amount: f64) {
+    let metric = match booking.status.as_str() {
+        "pending" | "approved" => RollupMetric::OpenBookings,
+        "completed" => RollupMetric::CompletedBookings,
+        _ => return,
+    };
+    add_to_rollups(
+        RollupScope::Vehicle,
+        booking.vehicle_id,
+        metric,
+        booking.start_time,
+        amount,
+    );
+}
+
+// Helper function to add a measurement to the day, week and month rollups of an
+// entity; a negative amount takes back an earlier one, as a rebuild would not see it
+fn add_to_rollups(scope: RollupScope, entity_id: u64, metric: RollupMetric, at: u64, amount: f64) {
+    ROLLUP_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for granularity in [
+            RollupGranularity::Day,
+            RollupGranularity::Week,
+            RollupGranularity::Month,
+        ] {
+            let key = RollupKey::new(
+                scope,
+                entity_id,
+                metric,
+                granularity,
+                bucket_start(granularity, at),
+            );
+            let value = storage.get(&key).unwrap_or_default();
+            if amount < 0.0 && value.count <= 1 {
+                storage.remove(&key);
+                continue;
+            }
+            storage.insert(
+                key,
+                RollupValue {
+                    total: value.total + amount,
+                    count: if amount < 0.0 {
+                        value.count - 1
+                    } else {
+                        value.count + 1
+                    },
+                },
+            );
+        }
+    });
+}
+
+// Helper function to add up a rollup series over all time, from its monthly buckets
+fn rollup_total(scope: RollupScope, entity_id: u64, metric: RollupMetric) -> f64 {
+    rollup_sum(
+        scope,
+        entity_id,
+        metric,
+        RollupGranularity::Month,
+        0,
+        u64::MAX,
+    )
+}
+
+// Helper function to add up the buckets of a rollup series starting between two times
+fn rollup_sum(
+    scope: RollupScope,
+    entity_id: u64,
+    metric: RollupMetric,
+    granularity: RollupGranularity,
+    from: u64,
+    to: u64,
+) -> f64 {
+    let from = RollupKey::new(scope, entity_id, metric, granularity, from);
+    let to = RollupKey::new(scope, entity_id, metric, granularity, to);
+    ROLLUP_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(from..=to)
+            .map(|(_, value)| value.total)
+            .sum()
+    })
+}
+
+// Helper function to read a single rollup bucket
+fn rollup_value(key: RollupKey) -> RollupValue {
+    ROLLUP_STORAGE.with(|storage| storage.borrow().get(&key).unwrap_or_default())
+}
+
+// Helper function to get the start of the bucket a timestamp falls into
+fn bucket_start(granularity: RollupGranularity, at: u64) -> u64 {
+    let day = DAY_SECS * NANOS_PER_SECOND;
+    let days = at / day;
+    match granularity {
+        RollupGranularity::Day => days * day,
+        // 1970-01-01 was a Thursday, three days after a Monday
+        RollupGranularity::Week => days.saturating_sub((days + 3) % 7) * day,
+        RollupGranularity::Month => {
+            let (year, month) = month_of(at);
+            month_period(year, month).map_or(0, |period| period.start)
+        }
+    }
+}
+
+// Function to record how a vehicle was acquired, for depreciation
+#[ic_cdk::update]
+fn set_vehicle_acquisition(
+    vehicle_id: u64,
+    acquisition: VehicleAcquisition,
+) -> Result<Vehicle, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    validate_acquisition(&acquisition)?;
+
+    let vehicle = VEHICLE_STORAGE
+        .with(|storage| storage.borrow().get(&vehicle_id))
+        .ok_or(Message::NotFound("Vehicle not found".to_string()))?;
+
+    let before = snapshot(&vehicle);
+    let updated = Vehicle {
+        acquisition: Some(acquisition),
+        ..vehicle
+    };
+    VEHICLE_STORAGE.with(|storage| storage.borrow_mut().insert(vehicle_id, updated.clone()));
+    certify("vehicles", vehicle_id, &updated);
+    record_audit(
+        "set_vehicle_acquisition",
+        "vehicle",
+        vehicle_id,
+        Some(before),
+        Some(snapshot(&updated)),
+    );
+    Ok(updated)
+}
+
+// Function to set what a vehicle is and can carry
+#[ic_cdk::update]
+fn set_vehicle_profile(vehicle_id: u64, profile: VehicleProfile) -> Result<Vehicle, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    validate_profile(&profile)?;
+
+    let vehicle = VEHICLE_STORAGE
+        .with(|storage| storage.borrow().get(&vehicle_id))
+        .ok_or(Message::NotFound("Vehicle not found".to_string()))?;
+
+    let before = snapshot(&vehicle);
+    let updated = Vehicle {
+        profile: Some(dedupe_equipment(profile)),
+        ..vehicle
+    };
+    VEHICLE_STORAGE.with(|storage| storage.borrow_mut().insert(vehicle_id, updated.clone()));
+    certify("vehicles", vehicle_id, &updated);
+    record_audit(
+        "set_vehicle_profile",
+        "vehicle",
+        vehicle_id,
+        Some(before),
+        Some(snapshot(&updated)),
+    );
+    Ok(updated)
+}
+
+// Function to search the available vehicles by category, energy, capacity,
+// licence class, equipment and location
+#[ic_cdk::query]
+fn search_available_vehicles(filter: VehicleFilter) -> Result<Vec<Vehicle>, Message> {
+    let vehicles: Vec<Vehicle> = VEHICLE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, vehicle)| vehicle)
+            .filter(|vehicle| {
+                vehicle.status == VehicleStatus::Available && vehicle_matches(vehicle, &filter)
+            })
+            .collect()
+    });
+    if vehicles.is_empty() {
+        Err(Message::NotFound("No matching vehicles found".to_string()))
+    } else {
+        Ok(vehicles)
+    }
+}
+
+// Helper function to validate a vehicle profile
+fn validate_profile(profile: &VehicleProfile) -> Result<(), Message> {
+    if profile.passenger_seats == 0 && profile.cargo_capacity_kg == 0 {
+        return Err(Message::InvalidPayload(
+            "Ensure 'passenger_seats' or 'cargo_capacity_kg' is greater than zero.".to_string(),
+        ));
+    }
+    if !profile.cargo_volume_m3.is_finite() || profile.cargo_volume_m3 < 0.0 {
+        return Err(Message::InvalidPayload(
+            "Ensure 'cargo_volume_m3' is a non-negative number.".to_string(),
+        ));
+    }
+    if profile
+        .rated_range_km
+        .is_some_and(|range| !range.is_finite() || range <= 0.0)
+    {
+        return Err(Message::InvalidPayload(
+            "Ensure 'rated_range_km' is greater than zero.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Helper function to keep one of each kind of equipment in a profile, which
+// also bounds the profile's size
+fn dedupe_equipment(mut profile: VehicleProfile) -> VehicleProfile {
+    let mut fitted = Vec::new();
+    for equipment in profile.equipment {
+        if !fitted.contains(&equipment) {
+            fitted.push(equipment);
+        }
+    }
+    profile.equipment = fitted;
+    profile
+}
+
+// Helper function to tell whether a vehicle meets every criterion of a filter
+fn vehicle_matches(vehicle: &Vehicle, filter: &VehicleFilter) -> bool {
+    let location_matches = filter.location.as_ref().is_none_or(|location| {
+        vehicle
+            .location
+            .to_lowercase()
+            .contains(&location.to_lowercase())
+    });
+    let profile_matches = match &vehicle.profile {
+        Some(profile) => {
+            filter
+                .category
+                .is_none_or(|category| profile.category == category)
+                && filter
+                    .energy_type
+                    .is_none_or(|energy_type| profile.energy_type == energy_type)
+                && filter
+                    .min_passenger_seats
+                    .is_none_or(|seats| profile.passenger_seats >= seats)
+                && filter
+                    .min_cargo_capacity_kg
+                    .is_none_or(|kg| profile.cargo_capacity_kg >= kg)
+                && filter
+                    .license_class
+                    .is_none_or(|class| profile.license_class == class)
+                && filter
+                    .equipment
+                    .iter()
+                    .all(|equipment| profile.equipment.contains(equipment))
+        }
+        None => {
+            filter.category.is_none()
+                && filter.energy_type.is_none()
+                && filter.min_passenger_seats.is_none()
+                && filter.min_cargo_capacity_kg.is_none()
+                && filter.license_class.is_none()
+                && filter.equipment.is_empty()
+        }
+    };
+    location_matches && profile_matches
+}
+
+// Function to get the total cost of ownership of a vehicle
+#[ic_cdk::query]
+fn get_vehicle_tco(vehicle_id: u64) -> Result<VehicleTco, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let vehicle = VEHICLE_STORAGE
+        .with(|storage| storage.borrow().get(&vehicle_id))
+        .ok_or(Message::NotFound("Vehicle not found".to_string()))?;
+    Ok(vehicle_tco(&vehicle, current_time()))
+}
+
+// Function to rank the fleet by cost per kilometre, most expensive first; vehicles
+// that have never been driven come first, as they cost without earning
+#[ic_cdk::query]
+fn get_fleet_tco_ranking() -> Result<Vec<VehicleTco>, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let now = current_time();
+    let mut ranking: Vec<VehicleTco> = VEHICLE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, vehicle)| vehicle_tco(&vehicle, now))
+            .collect()
+    });
+    if ranking.is_empty() {
+        return Err(Message::NotFound("No vehicles found".to_string()));
+    }
+    ranking.sort_by(|a, b| {
+        let cost = |tco: &VehicleTco| tco.cost_per_km.unwrap_or(f64::INFINITY);
+        cost(b).total_cmp(&cost(a))
+    });
+    Ok(ranking)
+}
+
+// Helper function to validate a vehicle acquisition
+fn validate_acquisition(acquisition: &VehicleAcquisition) -> Result<(), Message> {
+    if acquisition.useful_life_years == 0
+        || acquisition.useful_life_years > MAX_USEFUL_LIFE_YEARS
+        || acquisition.residual_value > acquisition.cost
+    {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'useful_life_years' is between 1 and {} and 'residual_value' does not exceed 'cost'.",
+            MAX_USEFUL_LIFE_YEARS
+        )));
+    }
+    Ok(())
+}
+
+// Helper function to work out the straight-line depreciation of a vehicle up to a time
+fn depreciation(acquisition: &VehicleAcquisition, at: u64) -> u64 {
+    let life = acquisition.useful_life_years as u128 * (YEAR_SECS * NANOS_PER_SECOND) as u128;
+    let elapsed = (at.saturating_sub(acquisition.acquired_at) as u128).min(life);
+    let depreciable = acquisition.cost.saturating_sub(acquisition.residual_value);
+    (depreciable as u128 * elapsed / life.max(1)) as u64
+}
+
+// Helper function to add up everything a vehicle has cost, per kilometre and
+// per hour driven on its completed trips, from the vehicle's rollups
+fn vehicle_tco(vehicle: &Vehicle, at: u64) -> VehicleTco {
+    let depreciation = vehicle
+        .acquisition
+        .map_or(0, |acquisition| depreciation(&acquisition, at));
+    let total_of = |metric: RollupMetric| rollup_total(RollupScope::Vehicle, vehicle.id, metric);
+    let fuel_cost = total_of(RollupMetric::FuelCost) as u64;
+    let charging_cost = total_of(RollupMetric::ChargingCost) as u64;
+    let maintenance_cost = total_of(RollupMetric::MaintenanceCost) as u64;
+    let insurance_cost = total_of(RollupMetric::InsuranceCost) as u64;
+    let incident_cost = total_of(RollupMetric::IncidentCost) as u64;
+    let kilometres = total_of(RollupMetric::Distance);
+    let utilized_hours = total_of(RollupMetric::DrivenHours);
+
+    let total = depreciation
+        .saturating_add(fuel_cost)
+        .saturating_add(charging_cost)
+        .saturating_add(maintenance_cost)
+        .saturating_add(insurance_cost)
+        .saturating_add(incident_cost);
+    VehicleTco {
+        vehicle_id: vehicle.id,
+        registration_number: vehicle.registration_number.clone(),
+        depreciation,
+        fuel_cost,
+        charging_cost,
+        maintenance_cost,
+        insurance_cost,
+        incident_cost,
+        total,
+        kilometres,
+        utilized_hours,
+        cost_per_km: (kilometres > 0.0).then(|| total as f64 / kilometres),
+        cost_per_hour: (utilized_hours > 0.0).then(|| total as f64 / utilized_hours),
+    }
+}
+
+// Function to compute the fleet KPIs for a period from the daily rollups, so the
+// period covers whole days (UTC); bookings and trips count in the day they start
+#[ic_cdk::query]
+fn get_fleet_dashboard(period: ReportPeriod) -> Result<FleetDashboard, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    if period.end <= period.start {
+        return Err(Message::InvalidPayload(
+            "Ensure 'end' is after 'start'.".to_string(),
+        ));
+    }
+    let first_day = bucket_start(RollupGranularity::Day, period.start);
+    let period_hours = (bucket_start(RollupGranularity::Day, period.end) - first_day) as f64
+        / (3600 * NANOS_PER_SECOND) as f64
+        + 24.0;
+
+    let vehicles: Vec<Vehicle> = VEHICLE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, vehicle)| vehicle)
+            .collect()
+    });
+    let vehicle_sum = |vehicle_id: u64, metric: RollupMetric| {
+        rollup_sum(
+            RollupScope::Vehicle,
+            vehicle_id,
+            metric,
+            RollupGranularity::Day,
+            first_day,
+            period.end,
+        )
+    };
+    let fleet_sum = |metric: RollupMetric| {
+        vehicles
+            .iter()
+            .map(|vehicle| vehicle_sum(vehicle.id, metric))
+            .sum::<f64>()
+    };
+
+    // Utilization counts the hours driven on trips started within the period
+    let utilization: Vec<VehicleUtilization> = vehicles
+        .iter()
+        .map(|vehicle| {
+            let driven_hours = vehicle_sum(vehicle.id, RollupMetric::DrivenHours);
+            VehicleUtilization {
+                vehicle_id: vehicle.id,
+                registration_number: vehicle.registration_number.clone(),
+                driven_hours,
+                utilization_percent: 100.0 * driven_hours / period_hours,
+            }
+        })
+        .collect();
+    let fleet_utilization_percent = if utilization.is_empty() {
+        0.0
+    } else {
+        utilization
+            .iter()
+            .map(|vehicle| vehicle.utilization_percent)
+            .sum::<f64>()
+            / utilization.len() as f64
+    };
+
+    let vehicles_by_status = [
+        VehicleStatus::Available,
+        VehicleStatus::Booked,
+        VehicleStatus::Maintenance,
+    ]
+    .into_iter()
+    .map(|status| VehicleStatusCount {
+        status,
+        count: vehicles
+            .iter()
+            .filter(|vehicle| vehicle.status == status)
+            .count() as u64,
+    })
+    .collect();
+
+    let open_emergencies = EMERGENCY_ASSISTANCE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, assistance)| assistance.status != "resolved")
+            .count() as u64
+    });
+    // Overdue as of now, whether or not check_maintenance has flagged it yet
+    let now = current_time();
+    let overdue_maintenance = MAINTENANCE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, maintenance)| {
+                maintenance.status != "completed" && maintenance.scheduled_date < now
+            })
+            .count() as u64
+    });
+
+    let fuel = fleet_sum(RollupMetric::FuelLitres);
+    let kilometres = fleet_sum(RollupMetric::Distance);
+
+    let mut drivers: Vec<Driver> =
+        DRIVER_STORAGE.with(|storage| storage.borrow().iter().map(|(_, driver)| driver).collect());
+    drivers.sort_by_key(|driver| driver.points);
+    let top_count = drivers.len().min(DASHBOARD_DRIVER_COUNT);
+    let top_drivers = drivers[..top_count].to_vec();
+    let bottom_drivers = drivers[top_count..]
+        .iter()
+        .rev()
+        .take(DASHBOARD_DRIVER_COUNT)
+        .cloned()
+        .collect();
+
+    Ok(FleetDashboard {
+        period,
+        utilization,
+        fleet_utilization_percent,
+        vehicles_by_status,
+        pending_bookings: fleet_sum(RollupMetric::OpenBookings).round() as u64,
+        completed_bookings: fleet_sum(RollupMetric::CompletedBookings).round() as u64,
+        open_emergencies,
+        overdue_maintenance,
+        average_fuel_per_km: (kilometres > 0.0).then(|| fuel / kilometres),
+        top_drivers,
+        bottom_drivers,
+    })
+}
+
+// Function to register or update the caller's customer profile
+#[ic_cdk::update]
+fn register_customer(payload: CustomerPayload) -> Result<Customer, Message> {
+    if payload.name.is_empty() || payload.contact_info.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name' and 'contact_info' are provided.".to_string(),
+        ));
+    }
+    if payload.name.len() > MAX_NAME_LENGTH || payload.contact_info.len() > MAX_TEXT_LENGTH {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'name' is at most {} bytes and 'contact_info' at most {}.",
+            MAX_NAME_LENGTH, MAX_TEXT_LENGTH
+        )));
+    }
+
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return Err(Message::Unauthorized(
+            "Anonymous callers cannot register as customers.".to_string(),
+        ));
+    }
+
+    let (customer, before) = match find_customer(caller) {
+        Some(existing) => {
+            let before = snapshot(&existing);
+            (
+                Customer {
+                    name: payload.name,
+                    contact_info: payload.contact_info,
+                    ..existing
+                },
+                Some(before),
+            )
+        }
+        None => {
+            let id = ID_COUNTER
+                .with(|counter| {
+                    let current_value = *counter.borrow().get();
+                    counter.borrow_mut().set(current_value + 1)
+                })
+                .expect("Cannot increment ID counter");
+            (
+                Customer {
+                    id,
+                    principal: caller,
+                    name: payload.name,
+                    contact_info: payload.contact_info,
+                    created_at: current_time(),
+                },
+                None,
+            )
+        }
+    };
+
+    CUSTOMER_STORAGE.with(|storage| storage.borrow_mut().insert(customer.id, customer.clone()));
+    record_audit(
+        "register_customer",
+        "customer",
+        customer.id,
+        before,
+        Some(snapshot(&customer)),
+    );
+    Ok(customer)
+}
+
+// Function to get the caller's customer profile
+#[ic_cdk::query]
+fn get_my_customer_profile() -> Result<Customer, Message> {
+    find_customer(caller()).ok_or(Message::NotFound("Customer not found".to_string()))
+}
+
+// Function to get all customers
+#[ic_cdk::query]
+fn get_customers() -> Result<Vec<Customer>, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    CUSTOMER_STORAGE.with(|storage| {
+        let customers: Vec<Customer> = storage
+            .borrow()
+            .iter()
+            .map(|(_, customer)| customer)
+            .collect();
+        if customers.is_empty() {
+            Err(Message::NotFound("No customers found".to_string()))
+        } else {
+            Ok(customers)
+        }
+    })
+}
+
+// Helper function to find the customer profile of a principal
+fn find_customer(principal: Principal) -> Option<Customer> {
+    CUSTOMER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, customer)| customer)
+            .find(|customer| customer.principal == principal)
+    })
+}
+
+// Function to create or replace the tariff of a vehicle model
+#[ic_cdk::update]
+fn set_tariff(payload: TariffPayload) -> Result<Tariff, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    if payload.vehicle_model.is_empty()
+        || payload.vehicle_model.len() > MAX_NAME_LENGTH
+        || payload
+            .surcharges
+            .iter()
+            .any(|surcharge| surcharge.name.is_empty() || surcharge.name.len() > MAX_NAME_LENGTH)
+    {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'vehicle_model' and every surcharge 'name' are provided and at most {} bytes long.",
+            MAX_NAME_LENGTH
+        )));
+    }
+    if payload.surcharges.len() > MAX_TARIFF_SURCHARGES {
+        return Err(Message::InvalidPayload(format!(
+            "A tariff can have at most {} surcharges.",
+            MAX_TARIFF_SURCHARGES
+        )));
+    }
+
+    let (tariff, before) = match find_tariff(&payload.vehicle_model) {
+        Some(existing) => {
+            let before = snapshot(&existing);
+            (
+                Tariff {
+                    base_fee: payload.base_fee,
+                    per_km: payload.per_km,
+                    per_hour: payload.per_hour,
+                    surcharges: payload.surcharges,
+                    updated_at: Some(current_time()),
+                    ..existing
+                },
+                Some(before),
+            )
+        }
+        None => {
+            let id = ID_COUNTER
+                .with(|counter| {
+                    let current_value = *counter.borrow().get();
+                    counter.borrow_mut().set(current_value + 1)
+                })
+                .expect("Cannot increment ID counter");
+            (
+                Tariff {
+                    id,
+                    vehicle_model: payload.vehicle_model,
+                    base_fee: payload.base_fee,
+                    per_km: payload.per_km,
+                    per_hour: payload.per_hour,
+                    surcharges: payload.surcharges,
+                    created_at: current_time(),
+                    updated_at: None,
+                },
+                None,
+            )
+        }
+    };
+
+    TARIFF_STORAGE.with(|storage| storage.borrow_mut().insert(tariff.id, tariff.clone()));
+    record_audit(
+        "set_tariff",
+        "tariff",
+        tariff.id,
+        before,
+        Some(snapshot(&tariff)),
+    );
+    Ok(tariff)
+}
+
+// Function to get all tariffs
+#[ic_cdk::query]
+fn get_tariffs() -> Result<Vec<Tariff>, Message> {
+    TARIFF_STORAGE.with(|storage| {
+        let tariffs: Vec<Tariff> = storage.borrow().iter().map(|(_, tariff)| tariff).collect();
+        if tariffs.is_empty() {
+            Err(Message::NotFound("No tariffs found".to_string()))
+        } else {
+            Ok(tariffs)
+        }
+    })
+}
+
+// Function to price a prospective booking from its route distance and duration
+#[ic_cdk::query]
+fn quote_booking(payload: QuotePayload) -> Result<Quote, Message> {
+    if payload.end_time <= payload.start_time {
+        return Err(Message::InvalidPayload(
+            "Ensure 'end_time' is after 'start_time'.".to_string(),
+        ));
+    }
+
+    let vehicle = VEHICLE_STORAGE
+        .with(|storage| storage.borrow().get(&payload.vehicle_id))
+        .ok_or(Message::NotFound("Vehicle not found".to_string()))?;
+    let route = ROUTE_STORAGE
+        .with(|storage| storage.borrow().get(&payload.route_id))
+        .ok_or(Message::NotFound("Route not found".to_string()))?;
+    let tariff = find_tariff(&vehicle.model).ok_or(Message::NotFound(format!(
+        "No tariff for vehicle model '{}'",
+        vehicle.model
+    )))?;
+
+    let duration = payload.end_time - payload.start_time;
+    let line_items = price_trip(&tariff, route.distance, duration)?;
+    Ok(Quote {
+        vehicle_id: vehicle.id,
+        tariff_id: tariff.id,
+        distance: route.distance,
+        billed_hours: billed_hours(duration),
+        total: line_items_total(&line_items).unwrap_or_default(),
+        line_items,
+    })
+}
+
+// Function to get an invoice by ID; plain users only see their own
+#[ic_cdk::query]
+fn get_invoice_by_id(id: u64) -> Result<Invoice, Message> {
+    let invoice = INVOICE_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Invoice not found".to_string()))?;
+    if !matches!(caller_role(), UserRole::Admin | UserRole::Manager)
+        && invoice.customer != Some(caller())
+    {
+        return Err(Message::Unauthorized(
+            "Caller is not allowed to view this invoice.".to_string(),
+        ));
+    }
+    Ok(invoice)
+}
+
+// Function to list invoices by customer and issue period; plain users only
+// see their own
+#[ic_cdk::query]
+fn get_invoices(
+    customer: Option<Principal>,
+    period: Option<ReportPeriod>,
+) -> Result<Vec<Invoice>, Message> {
+    let caller = caller();
+    let customer = if matches!(caller_role(), UserRole::Admin | UserRole::Manager) {
+        customer
+    } else if customer.is_none_or(|customer| customer == caller) {
+        Some(caller)
+    } else {
+        return Err(Message::Unauthorized(
+            "Caller is not allowed to view other customers' invoices.".to_string(),
+        ));
+    };
+
+    INVOICE_STORAGE.with(|storage| {
+        let invoices: Vec<Invoice> = storage
+            .borrow()
+            .iter()
+            .map(|(_, invoice)| invoice)
+            .filter(|invoice| customer.is_none_or(|customer| invoice.customer == Some(customer)))
+            .filter(|invoice| {
+                period.is_none_or(|period| {
+                    invoice.issued_at >= period.start && invoice.issued_at <= period.end
+                })
+            })
+            .collect();
+        if invoices.is_empty() {
+            Err(Message::NotFound("No invoices found".to_string()))
+        } else {
+            Ok(invoices)
+        }
+    })
 }
 
-// Function to create a new driver
+// Helper function to issue the invoice of a completed booking from the trip
+// actually driven; bookings of vehicle models without a tariff, or whose price
+// overflows, are not invoiced
+fn issue_invoice(booking: &Booking, trip: &Trip) -> Option<Invoice> {
+    let vehicle = VEHICLE_STORAGE.with(|storage| storage.borrow().get(&booking.vehicle_id))?;
+    let tariff = find_tariff(&vehicle.model)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let now = current_time();
+    let duration = trip.end_time.unwrap_or(now).saturating_sub(trip.start_time);
+    let line_items = price_trip(&tariff, trip.distance, duration).ok()?;
+    let invoice = Invoice {
+        id,
+        booking_id: booking.id,
+        trip_id: trip.id,
+        customer: booking.requester,
+        vehicle_id: vehicle.id,
+        tariff_id: tariff.id,
+        total: line_items_total(&line_items).unwrap_or_default(),
+        line_items,
+        issued_at: now,
+    };
+
+    INVOICE_STORAGE.with(|storage| storage.borrow_mut().insert(id, invoice.clone()));
+    rollup_write(RollupRebuildStage::Invoices, id, || {
+        rollup_invoice(&invoice, booking)
+    });
+    record_audit(
+        "complete_booking",
+        "invoice",
+        id,
+        None,
+        Some(snapshot(&invoice)),
+    );
+    if let Some(department_id) = booking.department_id {
+        check_department_budget(department_id, now);
+    }
+    Some(invoice)
+}
+
+// Helper function to find the tariff of a vehicle model, ignoring case
+fn find_tariff(vehicle_model: &str) -> Option<Tariff> {
+    TARIFF_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, tariff)| tariff)
+            .find(|tariff| tariff.vehicle_model.eq_ignore_ascii_case(vehicle_model))
+    })
+}
+
+// Helper function to count the started hours of a duration in nanoseconds
+fn billed_hours(duration: u64) -> u64 {
+    duration.div_ceil(3600 * NANOS_PER_SECOND)
+}
+
+// Helper function to break the price of a trip down into line items; fails
+// when an amount or the total does not fit in a u64
+fn price_trip(tariff: &Tariff, distance: f64, duration: u64) -> Result<Vec<LineItem>, Message> {
+    let overflow =
+        || Message::InvalidPayload("The trip's price is too large to be charged.".to_string());
+    let hours = billed_hours(duration);
+    let distance_amount = (distance * tariff.per_km as f64).round();
+    if !distance_amount.is_finite() || distance_amount < 0.0 || distance_amount >= u64::MAX as f64 {
+        return Err(overflow());
+    }
+    let mut line_items = vec![
+        LineItem {
+            description: "Base fee".to_string(),
+            quantity: 1.0,
+            unit_price: tariff.base_fee,
+            amount: tariff.base_fee,
+        },
+        LineItem {
+            description: "Distance (km)".to_string(),
+            quantity: distance,
+            unit_price: tariff.per_km,
+            amount: distance_amount as u64,
+        },
+        LineItem {
+            description: "Time (hours)".to_string(),
+            quantity: hours as f64,
+            unit_price: tariff.per_hour,
+            amount: hours.checked_mul(tariff.per_hour).ok_or_else(overflow)?,
+        },
+    ];
+
+    // Percentage surcharges apply to the base, distance and time charges only
+    let subtotal = line_items_total(&line_items).ok_or_else(overflow)?;
+    for surcharge in &tariff.surcharges {
+        let (quantity, unit_price, amount) = match surcharge.kind {
+            SurchargeKind::Flat(amount) => (1.0, amount, amount),
+            SurchargeKind::Percent(percent) => {
+                let amount = u64::try_from(subtotal as u128 * percent as u128 / 100)
+                    .map_err(|_| overflow())?;
+                (percent as f64 / 100.0, subtotal, amount)
+            }
+        };
+        line_items.push(LineItem {
+            description: surcharge.name.clone(),
+            quantity,
+            unit_price,
+            amount,
+        });
+    }
+    line_items_total(&line_items).ok_or_else(overflow)?;
+    Ok(line_items)
+}
+
+// Helper function to add up line items, or None on overflow
+fn line_items_total(line_items: &[LineItem]) -> Option<u64> {
+    line_items
+        .iter()
+        .try_fold(0u64, |total, item| total.checked_add(item.amount))
+}
+
+// Function to configure booking payments
 #[ic_cdk::update]
-fn create_driver(payload: DriverPayload) -> Result<Driver, Message> {
-    if payload.name.is_empty()
-        || payload.license_number.is_empty()
-        || payload.contact_info.is_empty()
-    {
+fn set_payment_config(config: PaymentConfig) -> Result<PaymentConfig, Message> {
+    require_role(&[UserRole::Admin])?;
+
+    if config.late_cancellation_fee_percent > 100 {
         return Err(Message::InvalidPayload(
-            "Ensure 'name', 'license_number', and 'contact_info' are provided.".to_string(),
+            "Ensure 'late_cancellation_fee_percent' is at most 100.".to_string(),
         ));
     }
 
-    // Validate the driver's license number by checking if it exists in any vehicle
-    let license_exists = VEHICLE_STORAGE.with(|storage| {
-        storage.borrow().iter().any(|(_, vehicle)| vehicle.registration_number == payload.license_number)
+    let before = PAYMENT_CONFIG.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let before = snapshot(cell.get());
+        cell.set(config.clone())
+            .expect("Cannot update the payment config");
+        before
     });
+    record_audit(
+        "set_payment_config",
+        "payment_config",
+        0,
+        Some(before),
+        Some(snapshot(&config)),
+    );
+    Ok(config)
+}
 
-    if !license_exists {
+// Function to get the payment configuration, including the cancellation policy
+#[ic_cdk::query]
+fn get_payment_config() -> PaymentConfig {
+    PAYMENT_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+// Function to get the payment of a booking
+#[ic_cdk::query]
+fn get_booking_payment(booking_id: u64) -> Result<BookingPayment, Message> {
+    let booking = BOOKING_STORAGE
+        .with(|storage| storage.borrow().get(&booking_id))
+        .ok_or(Message::NotFound("Booking not found".to_string()))?;
+    if !can_access_booking(&booking) {
+        return Err(Message::Unauthorized(
+            "Caller is not allowed to view this booking.".to_string(),
+        ));
+    }
+    PAYMENT_STORAGE
+        .with(|storage| storage.borrow().get(&booking_id))
+        .ok_or(Message::NotFound("Payment not found".to_string()))
+}
+
+// Function to retry the settlement or refund of a booking after a ledger failure
+#[ic_cdk::update]
+async fn retry_payment_settlement(booking_id: u64) -> Result<BookingPayment, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let payment = PAYMENT_STORAGE
+        .with(|storage| storage.borrow().get(&booking_id))
+        .ok_or(Message::NotFound("Payment not found".to_string()))?;
+    if payment.status != PaymentStatus::Failed {
         return Err(Message::InvalidPayload(
-            "Driver's license number does not exist in any vehicle.".to_string(),
+            "Only failed settlements can be retried.".to_string(),
+        ));
+    }
+    let ledger =
+        payment_ledger().ok_or(Message::Error("Payments are not configured.".to_string()))?;
+
+    let payment = settle_payment(&ledger, "retry_payment_settlement", payment).await;
+    match payment.status {
+        PaymentStatus::Failed => Err(Message::Error(format!(
+            "Settlement failed: {}",
+            payment.last_error.unwrap_or_default()
+        ))),
+        _ => Ok(payment),
+    }
+}
+
+// Helper function to collect the security deposit of a new booking; the
+// booking is cancelled again if the ledger refuses the transfer
+async fn collect_deposit<L: Ledger>(
+    ledger: &L,
+    booking: Booking,
+    payer: Principal,
+) -> Result<Booking, Message> {
+    let deposit = PAYMENT_CONFIG.with(|cell| cell.borrow().get().deposit);
+    let now = current_time();
+    let payment = BookingPayment {
+        booking_id: booking.id,
+        payer,
+        deposit,
+        amount_due: None,
+        collected: 0,
+        refunded: 0,
+        fees_paid: Some(0),
+        status: PaymentStatus::Processing,
+        ledger_blocks: vec![],
+        last_error: None,
+        created_at_time: Some(now),
+        created_at: now,
+        updated_at: None,
+    };
+    PAYMENT_STORAGE.with(|storage| storage.borrow_mut().insert(booking.id, payment.clone()));
+
+    let result = if deposit == 0 {
+        Ok(None)
+    } else {
+        ledger
+            .transfer_from(payer, deposit, booking.id, now)
+            .await
+            .map(Some)
+    };
+
+    match result {
+        Ok(block) => {
+            let held = BookingPayment {
+                collected: deposit,
+                status: PaymentStatus::DepositHeld,
+                ledger_blocks: block.into_iter().collect(),
+                created_at_time: None,
+                ..payment
+            };
+            PAYMENT_STORAGE
+                .with(|storage| storage.borrow_mut().insert(held.booking_id, held.clone()));
+            record_audit(
+                "create_booking",
+                "payment",
+                held.booking_id,
+                None,
+                Some(snapshot(&held)),
+            );
+            Ok(booking)
+        }
+        Err(error) => {
+            PAYMENT_STORAGE.with(|storage| storage.borrow_mut().remove(&booking.id));
+            let booking = BOOKING_STORAGE
+                .with(|storage| storage.borrow().get(&booking.id))
+                .unwrap_or(booking);
+            close_booking("create_booking", booking, "cancelled");
+            Err(Message::Error(format!(
+                "Security deposit could not be collected: {}",
+                error
+            )))
+        }
+    }
+}
+
+// Helper function to record what the fleet keeps of a booking's payment; a
+// payment already holding exactly that much is settled on the spot
+fn set_amount_due(endpoint: &str, payment: BookingPayment, amount_due: u64) -> BookingPayment {
+    let before = snapshot(&payment);
+    let status = if held_amount(&payment) == amount_due {
+        PaymentStatus::Settled
+    } else {
+        payment.status
+    };
+    let updated = BookingPayment {
+        amount_due: Some(amount_due),
+        status,
+        updated_at: Some(current_time()),
+        ..payment
+    };
+    PAYMENT_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(updated.booking_id, updated.clone())
+    });
+    record_audit(
+        endpoint,
+        "payment",
+        updated.booking_id,
+        Some(before),
+        Some(snapshot(&updated)),
+    );
+    updated
+}
+
+// Helper function to move tokens until the payer has paid exactly the amount
+// due, collecting any shortfall and refunding any excess; the ledger fee of a
+// refund comes out of the excess, and an excess too small to cover it is kept
+async fn settle_payment<L: Ledger>(
+    ledger: &L,
+    endpoint: &str,
+    payment: BookingPayment,
+) -> BookingPayment {
+    let Some(amount_due) = payment.amount_due else {
+        return payment;
+    };
+    if matches!(
+        payment.status,
+        PaymentStatus::Processing | PaymentStatus::Settled
+    ) {
+        return payment;
+    }
+
+    let before = snapshot(&payment);
+    let created_at_time = transfer_created_at(&payment);
+    let processing = BookingPayment {
+        status: PaymentStatus::Processing,
+        created_at_time: Some(created_at_time),
+        ..payment
+    };
+    PAYMENT_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(processing.booking_id, processing.clone())
+    });
+
+    let held = held_amount(&processing);
+    let booking_id = processing.booking_id;
+    let result = if amount_due > held {
+        let shortfall = amount_due - held;
+        ledger
+            .transfer_from(processing.payer, shortfall, booking_id, created_at_time)
+            .await
+            .map(|block| (Some(block), shortfall, 0, 0))
+    } else {
+        let excess = held - amount_due;
+        match ledger.fee().await {
+            Ok(fee) if excess <= fee => Ok((None, 0, 0, 0)),
+            Ok(fee) => ledger
+                .transfer(processing.payer, excess - fee, booking_id, created_at_time)
+                .await
+                .map(|block| (Some(block), 0, excess - fee, fee)),
+            Err(error) => Err(error),
+        }
+    };
+
+    let settled = match result {
+        Ok((block, collected, refunded, fee)) => {
+            let mut ledger_blocks = processing.ledger_blocks.clone();
+            ledger_blocks.extend(block);
+            BookingPayment {
+                collected: processing.collected + collected,
+                refunded: processing.refunded + refunded,
+                fees_paid: Some(processing.fees_paid.unwrap_or(0) + fee),
+                status: PaymentStatus::Settled,
+                ledger_blocks,
+                last_error: None,
+                created_at_time: None,
+                updated_at: Some(current_time()),
+                ..processing
+            }
+        }
+        Err(error) => BookingPayment {
+            status: PaymentStatus::Failed,
+            last_error: Some(error),
+            updated_at: Some(current_time()),
+            ..processing
+        },
+    };
+    PAYMENT_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(settled.booking_id, settled.clone())
+    });
+    record_audit(
+        endpoint,
+        "payment",
+        settled.booking_id,
+        Some(before),
+        Some(snapshot(&settled)),
+    );
+    settled
+}
+
+// Helper function to get what the canister still holds of a payment
+fn held_amount(payment: &BookingPayment) -> u64 {
+    payment.collected - payment.refunded - payment.fees_paid.unwrap_or(0)
+}
+
+// Helper function to pick the created_at_time of a payment's next transfer; a
+// failed transfer is retried with its original time so that the ledger reports
+// a transfer that did go through as a duplicate, until that time leaves the
+// ledger's deduplication window
+fn transfer_created_at(payment: &BookingPayment) -> u64 {
+    let now = current_time();
+    payment
+        .created_at_time
+        .filter(|time| now.saturating_sub(*time) < LEDGER_DEDUP_WINDOW_SECS * NANOS_PER_SECOND)
+        .unwrap_or(now)
+}
+
+// Helper function to tell whether a ledger transfer is in flight for a booking
+fn payment_in_flight(booking_id: u64) -> bool {
+    PAYMENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&booking_id)
+            .is_some_and(|payment| payment.status == PaymentStatus::Processing)
+    })
+}
+
+// Ledger that booking payments are made on; amounts are in the token's smallest
+// unit, and a transfer repeated with the same created_at_time must not move
+// tokens twice
+trait Ledger {
+    // Pulls tokens the payer approved the canister to spend; returns the block index
+    async fn transfer_from(
+        &self,
+        from: Principal,
+        amount: u64,
+        booking_id: u64,
+        created_at_time: u64,
+    ) -> Result<u64, String>;
+    // Sends tokens held by the canister back to a payer; returns the block index
+    async fn transfer(
+        &self,
+        to: Principal,
+        amount: u64,
+        booking_id: u64,
+        created_at_time: u64,
+    ) -> Result<u64, String>;
+    // Fee the canister pays on top of every transfer it sends
+    async fn fee(&self) -> Result<u64, String>;
+}
+
+// Client of ICRC-1/ICRC-2 ledger canisters; tests pay through tests::MockLedger
+#[cfg_attr(test, allow(dead_code))]
+mod icrc {
+    use super::*;
+
+    // ICRC-1 account
+    #[derive(candid::CandidType, Clone, Deserialize)]
+    struct Account {
+        owner: Principal,
+        subaccount: Option<Vec<u8>>,
+    }
+
+    // Arguments of icrc1_transfer
+    #[derive(candid::CandidType, Deserialize)]
+    struct TransferArg {
+        from_subaccount: Option<Vec<u8>>,
+        to: Account,
+        amount: Nat,
+        fee: Option<Nat>,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    }
+
+    // Arguments of icrc2_transfer_from
+    #[derive(candid::CandidType, Deserialize)]
+    struct TransferFromArgs {
+        spender_subaccount: Option<Vec<u8>>,
+        from: Account,
+        to: Account,
+        amount: Nat,
+        fee: Option<Nat>,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    }
+
+    // Errors of icrc1_transfer and icrc2_transfer_from
+    #[derive(candid::CandidType, Deserialize, Debug)]
+    enum LedgerError {
+        BadFee { expected_fee: Nat },
+        BadBurn { min_burn_amount: Nat },
+        InsufficientFunds { balance: Nat },
+        InsufficientAllowance { allowance: Nat },
+        TooOld,
+        CreatedInFuture { ledger_time: u64 },
+        Duplicate { duplicate_of: Nat },
+        TemporarilyUnavailable,
+        GenericError { error_code: Nat, message: String },
+    }
+
+    // Ledger backed by an ICRC-1/ICRC-2 ledger canister, local mock ledgers included
+    pub(super) struct IcrcLedger {
+        pub(super) canister_id: Principal,
+    }
+
+    impl Ledger for IcrcLedger {
+        async fn transfer_from(
+            &self,
+            from: Principal,
+            amount: u64,
+            booking_id: u64,
+            created_at_time: u64,
+        ) -> Result<u64, String> {
+            let args = TransferFromArgs {
+                spender_subaccount: None,
+                from: Account {
+                    owner: from,
+                    subaccount: None,
+                },
+                to: Account {
+                    owner: ic_cdk::id(),
+                    subaccount: None,
+                },
+                amount: Nat::from(amount),
+                fee: None,
+                memo: Some(booking_id.to_be_bytes().to_vec()),
+                created_at_time: Some(created_at_time),
+            };
+            let (result,): (Result<Nat, LedgerError>,) =
+                ic_cdk::call(self.canister_id, "icrc2_transfer_from", (args,))
+                    .await
+                    .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+            ledger_block(result)
+        }
+
+        async fn transfer(
+            &self,
+            to: Principal,
+            amount: u64,
+            booking_id: u64,
+            created_at_time: u64,
+        ) -> Result<u64, String> {
+            let args = TransferArg {
+                from_subaccount: None,
+                to: Account {
+                    owner: to,
+                    subaccount: None,
+                },
+                amount: Nat::from(amount),
+                fee: None,
+                memo: Some(booking_id.to_be_bytes().to_vec()),
+                created_at_time: Some(created_at_time),
+            };
+            let (result,): (Result<Nat, LedgerError>,) =
+                ic_cdk::call(self.canister_id, "icrc1_transfer", (args,))
+                    .await
+                    .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+            ledger_block(result)
+        }
+
+        async fn fee(&self) -> Result<u64, String> {
+            let (fee,): (Nat,) = ic_cdk::call(self.canister_id, "icrc1_fee", ())
+                .await
+                .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+            u64::try_from(fee.0).map_err(|_| "Ledger returned an invalid fee".to_string())
+        }
+    }
+
+    // Helper function to turn a ledger reply into its block index; a retried
+    // transfer that already went through is answered with the original block
+    fn ledger_block(result: Result<Nat, LedgerError>) -> Result<u64, String> {
+        let block = match result {
+            Err(LedgerError::Duplicate { duplicate_of }) => duplicate_of,
+            result => result.map_err(|error| format!("{:?}", error))?,
+        };
+        u64::try_from(block.0).map_err(|_| "Ledger returned an invalid block index".to_string())
+    }
+}
+
+#[cfg(not(test))]
+use icrc::IcrcLedger;
+
+// Function to have the canister pick the best vehicle and driver for a trip;
+// the returned proposal must be confirmed to become a booking
+#[ic_cdk::update]
+fn request_trip(payload: TripRequestPayload) -> Result<TripProposal, Message> {
+    let required_capacity = payload
+        .passenger_count
+        .checked_add(payload.cargo_count)
+        .ok_or(Message::InvalidPayload(
+            "Ensure 'passenger_count' and 'cargo_count' add up to a realistic load.".to_string(),
+        ))?;
+    if payload.from_location.is_empty()
+        || payload.to_location.is_empty()
+        || payload.end_time <= payload.start_time
+        || required_capacity == 0
+    {
+        return Err(Message::InvalidPayload(
+            "Ensure 'from_location', 'to_location', a passenger or cargo count, and valid 'start_time' and 'end_time' are provided."
+                .to_string(),
         ));
     }
 
+    let (vehicle, vehicle_score) = VEHICLE_STORAGE
+        .with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, vehicle)| vehicle)
+                .collect::<Vec<Vehicle>>()
+        })
+        .into_iter()
+        .filter(|vehicle| {
+            vehicle.status == VehicleStatus::Available
+                && carries_load(vehicle, payload.passenger_count, payload.cargo_count)
+                && payload
+                    .requirements
+                    .as_ref()
+                    .is_none_or(|requirements| vehicle_matches(vehicle, requirements))
+                && {
+                    let compliance = check_vehicle_compliance(vehicle, payload.start_time);
+                    compliance.expired.is_empty() && compliance.not_yet_valid.is_empty()
+                }
+        })
+        .map(|vehicle| {
+            let score = score_vehicle(&vehicle, &payload.from_location, required_capacity);
+            (vehicle, score)
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .ok_or(Message::NotFound(
+            "No suitable vehicle available".to_string(),
+        ))?;
+
+    let rules = HOURS_OF_SERVICE_RULES.with(|cell| cell.borrow().get().clone());
+    let drivers: Vec<Driver> =
+        DRIVER_STORAGE.with(|storage| storage.borrow().iter().map(|(_, driver)| driver).collect());
+    let max_points = drivers
+        .iter()
+        .map(|driver| driver.points)
+        .max()
+        .unwrap_or(0);
+    let (driver, driver_score) = drivers
+        .into_iter()
+        .filter(|driver| {
+            driver
+                .license_expiry
+                .is_none_or(|expiry| expiry > payload.end_time)
+                && driver_is_free(driver.id, payload.start_time, payload.end_time)
+                && check_hours_of_service(driver.id, payload.start_time, payload.end_time)
+                    .is_ok_and(|violations| {
+                        violations.is_empty() || rules.enforcement == RuleEnforcement::Warn
+                    })
+        })
+        .map(|driver| {
+            let score = score_driver(&driver, max_points);
+            (driver, score)
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .ok_or(Message::NotFound(
+            "No suitable driver available".to_string(),
+        ))?;
+
     let id = ID_COUNTER
         .with(|counter| {
             let current_value = *counter.borrow().get();
@@ -358,391 +6932,1531 @@ fn create_driver(payload: DriverPayload) -> Result<Driver, Message> {
         })
         .expect("Cannot increment ID counter");
 
-    let driver = Driver {
+    let now = current_time();
+    let proposal = TripProposal {
         id,
-        name: payload.name,
-        license_number: payload.license_number,
-        contact_info: payload.contact_info,
-        points: 0,
-        created_at: current_time(),
+        requester: caller(),
+        vehicle_id: vehicle.id,
+        driver_id: driver.id,
+        from_location: payload.from_location,
+        to_location: payload.to_location,
+        start_time: payload.start_time,
+        end_time: payload.end_time,
+        score: vehicle_score + driver_score,
+        status: ProposalStatus::Proposed,
+        booking_id: None,
+        expires_at: now + TRIP_PROPOSAL_TTL_SECS * NANOS_PER_SECOND,
+        created_at: now,
+    };
+
+    PROPOSAL_STORAGE.with(|storage| storage.borrow_mut().insert(id, proposal.clone()));
+    record_audit(
+        "request_trip",
+        "trip_proposal",
+        id,
+        None,
+        Some(snapshot(&proposal)),
+    );
+    Ok(proposal)
+}
+
+// Function to turn a trip proposal into a booking
+#[ic_cdk::update]
+async fn confirm_trip_proposal(id: u64) -> Result<Booking, Message> {
+    let proposal = PROPOSAL_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Trip proposal not found".to_string()))?;
+
+    if proposal.requester != caller() {
+        return Err(Message::Unauthorized(
+            "Only the requester can confirm this proposal.".to_string(),
+        ));
+    }
+    if proposal.status != ProposalStatus::Proposed {
+        return Err(Message::InvalidPayload(
+            "Trip proposal has already been confirmed.".to_string(),
+        ));
+    }
+    if proposal.expires_at < current_time() {
+        return Err(Message::InvalidPayload(
+            "Trip proposal has expired; request the trip again.".to_string(),
+        ));
+    }
+
+    // The booking is validated again, as things may have changed since the proposal
+    let booking = create_booking(BookingPayload {
+        vehicle_id: proposal.vehicle_id,
+        driver_id: proposal.driver_id,
+        from_location: proposal.from_location.clone(),
+        to_location: proposal.to_location.clone(),
+        start_time: proposal.start_time,
+        end_time: proposal.end_time,
+        route_id: None,
+        department_id: None,
+        manifest: None,
+    })
+    .await?;
+
+    let before = snapshot(&proposal);
+    let confirmed = TripProposal {
+        status: ProposalStatus::Confirmed,
+        booking_id: Some(booking.id),
+        ..proposal
+    };
+    PROPOSAL_STORAGE.with(|storage| storage.borrow_mut().insert(id, confirmed.clone()));
+    record_audit(
+        "confirm_trip_proposal",
+        "trip_proposal",
+        id,
+        Some(before),
+        Some(snapshot(&confirmed)),
+    );
+    Ok(booking)
+}
+
+// Helper function to score a vehicle for a trip: being at the pickup location
+// counts most, then how snugly the vehicle fits the load
+fn score_vehicle(vehicle: &Vehicle, from_location: &str, required_capacity: u64) -> f64 {
+    let location = vehicle.location.to_lowercase();
+    let from_location = from_location.to_lowercase();
+    let proximity = if location == from_location {
+        60.0
+    } else if location.contains(&from_location) || from_location.contains(&location) {
+        30.0
+    } else {
+        0.0
+    };
+    let fit = 40.0 * required_capacity as f64 / vehicle.capacity as f64;
+    proximity + fit
+}
+
+// Helper function to tell whether a vehicle can carry a trip's passengers and
+// cargo: against its profile's seats and cargo capacity when it has one, and
+// against its overall capacity otherwise
+fn carries_load(vehicle: &Vehicle, passenger_count: u64, cargo_count: u64) -> bool {
+    match &vehicle.profile {
+        Some(profile) => {
+            passenger_count <= profile.passenger_seats && cargo_count <= profile.cargo_capacity_kg
+        }
+        None => passenger_count.saturating_add(cargo_count) <= vehicle.capacity,
+    }
+}
+
+// Helper function to score a driver for a trip: the fewer penalty points
+// relative to the most held by any driver, the better
+fn score_driver(driver: &Driver, max_points: u64) -> f64 {
+    if max_points == 0 {
+        40.0
+    } else {
+        40.0 * (1.0 - driver.points as f64 / max_points as f64)
+    }
+}
+
+// Function to export fuel records as CSV
+#[ic_cdk::query]
+fn export_fuel_csv(filter: CsvExportFilter) -> Result<CsvPage, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    reject_driver_filter(&filter, "Fuel records")?;
+
+    Ok(csv_page(
+        &FUEL_CONSUMPTION_STORAGE,
+        &filter,
+        |fuel| csv_filter_matches(&filter, fuel.date, fuel.vehicle_id, None),
+        &["id", "vehicle_id", "date", "amount", "cost"],
+        |fuel| {
+            vec![
+                fuel.id.to_string(),
+                fuel.vehicle_id.to_string(),
+                format_timestamp(fuel.date),
+                fuel.amount.to_string(),
+                optional_field(fuel.cost),
+            ]
+        },
+    ))
+}
+
+// Function to export maintenance history as CSV
+#[ic_cdk::query]
+fn export_maintenance_csv(filter: CsvExportFilter) -> Result<CsvPage, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    reject_driver_filter(&filter, "Maintenance records")?;
+
+    Ok(csv_page(
+        &MAINTENANCE_STORAGE,
+        &filter,
+        |maintenance| {
+            csv_filter_matches(
+                &filter,
+                maintenance.scheduled_date,
+                maintenance.vehicle_id,
+                None,
+            )
+        },
+        &[
+            "id",
+            "vehicle_id",
+            "description",
+            "scheduled_date",
+            "status",
+            "cost",
+            "created_at",
+        ],
+        |maintenance| {
+            vec![
+                maintenance.id.to_string(),
+                maintenance.vehicle_id.to_string(),
+                maintenance.description.clone(),
+                format_timestamp(maintenance.scheduled_date),
+                maintenance.status.clone(),
+                optional_field(maintenance.cost),
+                format_timestamp(maintenance.created_at),
+            ]
+        },
+    ))
+}
+
+// Function to export booking history as CSV
+#[ic_cdk::query]
+fn export_bookings_csv(filter: CsvExportFilter) -> Result<CsvPage, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    Ok(csv_page(
+        &BOOKING_STORAGE,
+        &filter,
+        |booking| {
+            csv_filter_matches(
+                &filter,
+                booking.start_time,
+                booking.vehicle_id,
+                Some(booking.driver_id),
+            )
+        },
+        &[
+            "id",
+            "vehicle_id",
+            "driver_id",
+            "department_id",
+            "requester",
+            "from_location",
+            "to_location",
+            "start_time",
+            "end_time",
+            "status",
+            "created_at",
+        ],
+        |booking| {
+            vec![
+                booking.id.to_string(),
+                booking.vehicle_id.to_string(),
+                booking.driver_id.to_string(),
+                optional_field(booking.department_id),
+                optional_field(booking.requester),
+                booking.from_location.clone(),
+                booking.to_location.clone(),
+                format_timestamp(booking.start_time),
+                format_timestamp(booking.end_time),
+                booking.status.clone(),
+                format_timestamp(booking.created_at),
+            ]
+        },
+    ))
+}
+
+// Function to export emergency history as CSV
+#[ic_cdk::query]
+fn export_emergencies_csv(filter: CsvExportFilter) -> Result<CsvPage, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+    reject_driver_filter(&filter, "Emergencies")?;
+
+    Ok(csv_page(
+        &EMERGENCY_ASSISTANCE_STORAGE,
+        &filter,
+        |assistance| {
+            csv_filter_matches(&filter, assistance.created_at, assistance.vehicle_id, None)
+        },
+        &[
+            "id",
+            "vehicle_id",
+            "description",
+            "location",
+            "status",
+            "cost",
+            "created_at",
+            "resolved_at",
+        ],
+        |assistance| {
+            vec![
+                assistance.id.to_string(),
+                assistance.vehicle_id.to_string(),
+                assistance.description.clone(),
+                assistance.location.clone(),
+                assistance.status.clone(),
+                optional_field(assistance.cost),
+                format_timestamp(assistance.created_at),
+                assistance
+                    .resolved_at
+                    .map(format_timestamp)
+                    .unwrap_or_default(),
+            ]
+        },
+    ))
+}
+
+// Helper function to refuse a driver filter on records that have no driver
+fn reject_driver_filter(filter: &CsvExportFilter, records: &str) -> Result<(), Message> {
+    if filter.driver_id.is_some() {
+        return Err(Message::InvalidPayload(format!(
+            "{} cannot be filtered by driver.",
+            records
+        )));
+    }
+    Ok(())
+}
+
+// Helper function to tell whether a record passes a CSV export filter
+fn csv_filter_matches(
+    filter: &CsvExportFilter,
+    at: u64,
+    vehicle_id: u64,
+    driver_id: Option<u64>,
+) -> bool {
+    filter.from.is_none_or(|from| at >= from)
+        && filter.to.is_none_or(|to| at <= to)
+        && filter.vehicle_id.is_none_or(|id| id == vehicle_id)
+        && filter.driver_id.is_none_or(|id| Some(id) == driver_id)
+}
+
+// Helper function to render one page of a store's matching records as CSV,
+// reading on from the ID the filter starts after
+fn csv_page<T: BoundedStorable>(
+    store: &'static LocalKey<RefCell<StableBTreeMap<u64, T, Memory>>>,
+    filter: &CsvExportFilter,
+    matches: impl Fn(&T) -> bool,
+    header: &[&str],
+    render: impl Fn(&T) -> Vec<String>,
+) -> CsvPage {
+    let limit = filter.limit.clamp(1, MAX_CSV_ROWS) as usize;
+    let from = filter.start_after.map_or(Bound::Unbounded, Bound::Excluded);
+    // One record past the page tells whether another page follows
+    let mut page: Vec<(u64, T)> = store.with(|storage| {
+        storage
+            .borrow()
+            .range((from, Bound::Unbounded))
+            .filter(|(_, record)| matches(record))
+            .take(limit + 1)
+            .collect()
+    });
+    let more = page.len() > limit;
+    page.truncate(limit);
+
+    let mut csv = String::new();
+    if filter.start_after.is_none() {
+        csv.push_str(&csv_line(header.iter().map(|column| column.to_string())));
+    }
+    for (_, record) in &page {
+        csv.push_str(&csv_line(render(record)));
+    }
+
+    CsvPage {
+        csv,
+        rows: page.len() as u64,
+        next_start_after: if more {
+            page.last().map(|(id, _)| *id)
+        } else {
+            None
+        },
+    }
+}
+
+// Helper function to join fields into a CSV line, quoting fields that hold
+// commas, quotes or line breaks; fields a spreadsheet would read as a formula
+// get a leading apostrophe
+fn csv_line(fields: impl IntoIterator<Item = String>) -> String {
+    let fields: Vec<String> = fields
+        .into_iter()
+        .map(|field| {
+            let field = if field.starts_with(['=', '+', '-', '@']) {
+                format!("'{}", field)
+            } else {
+                field
+            };
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field
+            }
+        })
+        .collect();
+    format!("{}\r\n", fields.join(","))
+}
+
+// Helper function to render an optional CSV field, empty when absent
+fn optional_field<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+// Helper function to render a timestamp as an RFC 3339 date (UTC)
+fn format_timestamp(timestamp: u64) -> String {
+    chrono::DateTime::from_timestamp(
+        (timestamp / NANOS_PER_SECOND) as i64,
+        (timestamp % NANOS_PER_SECOND) as u32,
+    )
+    .unwrap_or_default()
+    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+// Function to serve a read-only JSON REST view of the fleet over the HTTP gateway
+#[ic_cdk::query]
+fn http_request(request: GatewayRequest) -> GatewayResponse {
+    if request.method != "GET" {
+        return http_json(405, &http_error_body("Only GET is supported."));
+    }
+
+    let (path, query) = request
+        .url
+        .split_once('?')
+        .unwrap_or((request.url.as_str(), ""));
+    let Some(params) = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| Some((percent_decode(key)?, percent_decode(value)?)))
+        .collect::<Option<HashMap<String, String>>>()
+    else {
+        return http_json(
+            400,
+            &http_error_body("Query parameters must be percent-encoded UTF-8."),
+        );
+    };
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    match segments.as_slice() {
+        ["vehicles"] => http_list(get_vehicles(), &params),
+        ["vehicles", id] => match id.parse() {
+            Ok(id) => http_result(get_vehicle_by_id(id)),
+            Err(_) => http_json(400, &http_error_body("Vehicle id must be a number.")),
+        },
+        ["bookings"] => {
+            // Anonymous callers own no bookings, so they are asked to sign in
+            if caller() == Principal::anonymous() {
+                return http_json(401, &http_error_body("Sign in to list bookings."));
+            }
+            let bookings = get_bookings().map(|bookings| {
+                bookings
+                    .into_iter()
+                    .filter(|booking| {
+                        params
+                            .get("status")
+                            .is_none_or(|status| booking.status == *status)
+                    })
+                    .collect()
+            });
+            http_list(bookings, &params)
+        }
+        ["drivers", id] => match id.parse() {
+            Ok(id) => http_result(get_driver_by_id(id)),
+            Err(_) => http_json(400, &http_error_body("Driver id must be a number.")),
+        },
+        _ => http_json(404, &http_error_body("No such resource.")),
+    }
+}
+
+// Helper function to decode a percent-encoded query string component, with '+'
+// standing for a space; none if an escape or the decoded text is invalid
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.bytes();
+    while let Some(byte) = rest.next() {
+        match byte {
+            b'%' => {
+                let high = char::from(rest.next()?).to_digit(16)?;
+                let low = char::from(rest.next()?).to_digit(16)?;
+                bytes.push((high * 16 + low) as u8);
+            }
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Helper function to render a query result as an HTTP response
+fn http_result<T: serde::Serialize>(result: Result<T, Message>) -> GatewayResponse {
+    match result {
+        Ok(value) => http_json(200, &value),
+        Err(error) => http_message(error),
+    }
+}
+
+// Helper function to render a list query as one page of an HTTP response; an
+// empty list is an empty page rather than a 404
+fn http_list<T: serde::Serialize>(
+    result: Result<Vec<T>, Message>,
+    params: &HashMap<String, String>,
+) -> GatewayResponse {
+    let items = match result {
+        Ok(items) => items,
+        Err(Message::NotFound(_)) => Vec::new(),
+        Err(error) => return http_message(error),
+    };
+    let (Ok(offset), Ok(limit)) = (
+        params
+            .get("offset")
+            .map_or(Ok(0), |offset| offset.parse::<u64>()),
+        params
+            .get("limit")
+            .map_or(Ok(MAX_PAGE_SIZE), |limit| limit.parse::<u64>()),
+    ) else {
+        return http_json(400, &http_error_body("Offset and limit must be numbers."));
+    };
+
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let total = items.len() as u64;
+    let items: Vec<T> = items
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .collect();
+    let end = offset.saturating_add(items.len() as u64);
+    http_json(
+        200,
+        &HttpPage {
+            items,
+            offset,
+            limit,
+            total,
+            next_offset: (end < total).then_some(end),
+        },
+    )
+}
+
+// Helper function to map a Message error to its HTTP status code
+fn http_message(message: Message) -> GatewayResponse {
+    let (status_code, text) = match message {
+        Message::Success(text) => (200, text),
+        Message::InvalidPayload(text) => (400, text),
+        Message::Unauthorized(text) => (403, text),
+        Message::NotFound(text) => (404, text),
+        Message::Error(text) => (500, text),
     };
+    http_json(status_code, &http_error_body(&text))
+}
 
-    DRIVER_STORAGE.with(|storage| storage.borrow_mut().insert(id, driver.clone()));
-    Ok(driver)
+// Helper function to build the JSON body of an HTTP error
+fn http_error_body(text: &str) -> HashMap<&'static str, &str> {
+    HashMap::from([("error", text)])
 }
 
+// Helper function to build a JSON HTTP response
+fn http_json<T: serde::Serialize>(status_code: u16, body: &T) -> GatewayResponse {
+    GatewayResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: serde_json::to_vec(body).unwrap_or_default(),
+    }
+}
 
-// Function to get all drivers
+// Function to get a vehicle with a certificate and witness for its record
 #[ic_cdk::query]
-fn get_drivers() -> Result<Vec<Driver>, Message> {
-    DRIVER_STORAGE.with(|storage| {
-        let drivers: Vec<Driver> = storage
-            .borrow()
-            .iter()
-            .map(|(_, driver)| driver.clone())
-            .collect();
-        if drivers.is_empty() {
-            Err(Message::NotFound("No drivers found".to_string()))
-        } else {
-            Ok(drivers)
-        }
+fn get_certified_vehicle(id: u64) -> Result<CertifiedVehicle, Message> {
+    let vehicle = get_vehicle_by_id(id)?;
+    Ok(CertifiedVehicle {
+        certification: certification("vehicles", id, &vehicle)?,
+        vehicle,
     })
 }
 
-// Function to get a driver by ID
+// Function to get a booking with a certificate and witness for its record
 #[ic_cdk::query]
-fn get_driver_by_id(id: u64) -> Result<Driver, Message> {
-    DRIVER_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .get(&id)
-            .map(|driver| driver.clone())
-            .ok_or(Message::NotFound("Driver not found".to_string()))
+fn get_certified_booking(id: u64) -> Result<CertifiedBooking, Message> {
+    let booking = get_booking_by_id(id)?;
+    Ok(CertifiedBooking {
+        certification: certification("bookings", id, &booking)?,
+        booking,
     })
 }
 
-// Function to create a new vehicle
-#[ic_cdk::update]
-fn create_vehicle(payload: VehiclePayload) -> Result<Vehicle, Message> {
-    if payload.registration_number.is_empty()
-        || payload.model.is_empty()
-        || payload.capacity == 0
-        || payload.location.is_empty()
-    {
-        return Err(Message::InvalidPayload(
-            "Ensure 'registration_number', 'model', 'capacity', and 'location' are provided."
-                .to_string(),
+// Function to get a driver with a certificate and witness for its record
+#[ic_cdk::query]
+fn get_certified_driver(id: u64) -> Result<CertifiedDriver, Message> {
+    let driver = get_driver_by_id(id)?;
+    Ok(CertifiedDriver {
+        certification: certification("drivers", id, &driver)?,
+        driver,
+    })
+}
+
+// Helper function to build the certificate and witness for a record
+fn certification<T: Storable>(
+    collection: &str,
+    id: u64,
+    record: &T,
+) -> Result<Certification, Message> {
+    if CERTIFICATION_REBUILD.with(|rebuild| rebuild.borrow().is_some()) {
+        return Err(Message::Error(
+            "Certificates are being rebuilt after an upgrade; try again shortly.".to_string(),
         ));
     }
+    let certificate = data_certificate().ok_or(Message::Error(
+        "No data certificate is available; call this method as a query.".to_string(),
+    ))?;
+    let key = certified_key(collection, id);
+    let witness = CERTIFIED_TREE.with(|tree| {
+        let tree = tree.borrow();
+        let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+        serializer.self_describe()?;
+        serde::Serialize::serialize(
+            &labeled(CERTIFIED_LABEL, tree.witness(key.as_bytes())),
+            &mut serializer,
+        )?;
+        Ok::<_, serde_cbor::Error>(serializer.into_inner())
+    });
+    Ok(Certification {
+        key,
+        certificate,
+        witness: witness
+            .map_err(|error| Message::Error(format!("Cannot encode witness: {}", error)))?,
+        record: record.to_bytes().into_owned(),
+    })
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+// Helper function to refresh a record's leaf in the certified tree and publish
+// the new root; the leaf is the SHA-256 of the record's stored bytes, its Candid
+// message ("DIDL", the type table, then the value)
+fn certify<T: Storable>(collection: &str, id: u64, record: &T) {
+    CERTIFIED_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.insert(certified_key(collection, id), leaf_hash(&record.to_bytes()));
+        set_certified_data(&labeled_hash(CERTIFIED_LABEL, &tree.root_hash()));
+    });
+}
 
-    let vehicle = Vehicle {
-        id,
-        registration_number: payload.registration_number,
-        model: payload.model,
-        capacity: payload.capacity,
-        status: VehicleStatus::Available,
-        location: payload.location,
-        created_at: current_time(),
-    };
+// Helper function to empty the certified tree and publish its root
+fn reset_certification() {
+    CERTIFICATION_REBUILD.with(|rebuild| *rebuild.borrow_mut() = None);
+    CERTIFIED_TREE.with(|tree| {
+        *tree.borrow_mut() = RbTree::new();
+        set_certified_data(&labeled_hash(CERTIFIED_LABEL, &tree.borrow().root_hash()));
+    });
+}
 
-    VEHICLE_STORAGE.with(|storage| storage.borrow_mut().insert(id, vehicle.clone()));
-    Ok(vehicle)
+// Helper function to start rebuilding the certified tree from the vehicle,
+// booking and driver stores, in batches on a timer
+fn start_certification() {
+    reset_certification();
+    CERTIFICATION_REBUILD.with(|rebuild| *rebuild.borrow_mut() = Some((0, 0)));
+    schedule_certification();
 }
 
-// Function to get all vehicles
-#[ic_cdk::query]
-fn get_vehicles() -> Result<Vec<Vehicle>, Message> {
-    VEHICLE_STORAGE.with(|storage| {
-        let vehicles: Vec<Vehicle> = storage
-            .borrow()
-            .iter()
-            .map(|(_, vehicle)| vehicle.clone())
-            .collect();
-        if vehicles.is_empty() {
-            Err(Message::NotFound("No vehicles found".to_string()))
-        } else {
-            Ok(vehicles)
+// Helper function to certify the next batch of records of a running rebuild,
+// publishing the root once the last collection is done
+fn continue_certification() {
+    let Some((mut collection, mut next_id)) =
+        CERTIFICATION_REBUILD.with(|rebuild| *rebuild.borrow())
+    else {
+        return;
+    };
+    let mut budget = CERTIFY_BATCH_SIZE;
+    let leaf = |collection: &str, id: u64, bytes: &[u8]| {
+        CERTIFIED_TREE.with(|tree| {
+            tree.borrow_mut()
+                .insert(certified_key(collection, id), leaf_hash(bytes))
+        })
+    };
+    while collection < CERTIFIED_COLLECTIONS.len() {
+        let name = CERTIFIED_COLLECTIONS[collection];
+        let (done, last_id) = match name {
+            "vehicles" => replay_batch(&VEHICLE_STORAGE, next_id, budget, |vehicle| {
+                leaf(name, vehicle.id, &vehicle.to_bytes())
+            }),
+            "bookings" => replay_batch(&BOOKING_STORAGE, next_id, budget, |booking| {
+                leaf(name, booking.id, &booking.to_bytes())
+            }),
+            _ => replay_batch(&DRIVER_STORAGE, next_id, budget, |driver| {
+                leaf(name, driver.id, &driver.to_bytes())
+            }),
+        };
+        budget -= done;
+        if budget == 0 {
+            if let Some(last_id) = last_id {
+                next_id = last_id + 1;
+            }
+            break;
         }
-    })
+        collection += 1;
+        next_id = 0;
+    }
+
+    if collection < CERTIFIED_COLLECTIONS.len() {
+        CERTIFICATION_REBUILD.with(|rebuild| *rebuild.borrow_mut() = Some((collection, next_id)));
+        schedule_certification();
+    } else {
+        CERTIFICATION_REBUILD.with(|rebuild| *rebuild.borrow_mut() = None);
+        CERTIFIED_TREE.with(|tree| {
+            set_certified_data(&labeled_hash(CERTIFIED_LABEL, &tree.borrow().root_hash()))
+        });
+    }
 }
 
-// Function to get a vehicle by ID
+// Helper function to build a record's key in the certified tree
+fn certified_key(collection: &str, id: u64) -> String {
+    format!("{}/{}", collection, id)
+}
+
+// Function to export a chunk of one store for a snapshot
 #[ic_cdk::query]
-fn get_vehicle_by_id(id: u64) -> Result<Vehicle, Message> {
-    VEHICLE_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .get(&id)
-            .map(|vehicle| vehicle.clone())
-            .ok_or(Message::NotFound("Vehicle not found".to_string()))
+fn export_snapshot(
+    store: SnapshotStore,
+    start_after: Option<Vec<u8>>,
+    limit: u64,
+) -> Result<SnapshotChunk, Message> {
+    require_role(&[UserRole::Admin])?;
+
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let after = start_after.as_deref();
+    let (data, records, next) = match store {
+        SnapshotStore::Drivers => export_store(&DRIVER_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Vehicles => export_store(&VEHICLE_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Bookings => export_store(&BOOKING_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::FuelConsumptions => {
+            export_store(&FUEL_CONSUMPTION_STORAGE, snapshot_cursor(after)?, limit)
+        }
+        SnapshotStore::Maintenances => {
+            export_store(&MAINTENANCE_STORAGE, snapshot_cursor(after)?, limit)
+        }
+        SnapshotStore::EmergencyAssistances => export_store(
+            &EMERGENCY_ASSISTANCE_STORAGE,
+            snapshot_cursor(after)?,
+            limit,
+        ),
+        SnapshotStore::Routes => export_store(&ROUTE_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Trips => export_store(&TRIP_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Users => export_store(&USER_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Documents => export_store(&DOCUMENT_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Shifts => export_store(&SHIFT_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::LeaveRequests => {
+            export_store(&LEAVE_STORAGE, snapshot_cursor(after)?, limit)
+        }
+        SnapshotStore::Proposals => export_store(&PROPOSAL_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Customers => export_store(&CUSTOMER_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Tariffs => export_store(&TARIFF_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Invoices => export_store(&INVOICE_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Payments => export_store(&PAYMENT_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Departments => {
+            export_store(&DEPARTMENT_STORAGE, snapshot_cursor(after)?, limit)
+        }
+        SnapshotStore::ChargingSessions => {
+            export_store(&CHARGING_STORAGE, snapshot_cursor(after)?, limit)
+        }
+        SnapshotStore::Stations => export_store(&STATION_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Manifests => export_store(&MANIFEST_STORAGE, snapshot_cursor(after)?, limit),
+        SnapshotStore::Subscriptions => {
+            export_store(&SUBSCRIPTION_STORAGE, snapshot_cursor(after)?, limit)
+        }
+        SnapshotStore::Notifications => {
+            export_store(&NOTIFICATION_STORAGE, snapshot_cursor(after)?, limit)
+        }
+        SnapshotStore::JobStatuses => {
+            export_store(&JOB_STATUS_STORAGE, snapshot_cursor(after)?, limit)
+        }
+        SnapshotStore::AuditLog => export_log(&AUDIT_LOG, snapshot_cursor(after)?, limit),
+        SnapshotStore::EventLog => export_log(&EVENT_LOG, snapshot_cursor(after)?, limit),
+        SnapshotStore::NotificationConfig => export_cell(&NOTIFICATION_CONFIG),
+        SnapshotStore::HoursOfServiceRules => export_cell(&HOURS_OF_SERVICE_RULES),
+        SnapshotStore::PaymentConfig => export_cell(&PAYMENT_CONFIG),
+        SnapshotStore::Counters => export_cell(&ID_COUNTER),
+    };
+    Ok(SnapshotChunk {
+        version: SNAPSHOT_VERSION,
+        store,
+        checksum: snapshot_checksum(SNAPSHOT_VERSION, store, &data),
+        data,
+        records,
+        next,
     })
 }
 
-// Function to create a new booking
+// Function to empty every snapshot store, the logs and the derived state
+// before a restore; roles are emptied too, so only controllers can restore
 #[ic_cdk::update]
-fn create_booking(payload: BookingPayload) -> Result<Booking, Message> {
-    // Validate the booking payload
-    if payload.from_location.is_empty() || payload.to_location.is_empty() {
-        return Err(Message::InvalidPayload("Ensure 'from_location', 'to_location', and valid 'start_time' and 'end_time' are provided.".to_string()));
-    }
-
-    // Validate the driver ID
-    let driver = DRIVER_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .get(&payload.driver_id)
-            .map(|driver| driver.clone())
-    });
+fn begin_restore() -> Result<u64, Message> {
+    require_controller()?;
 
-    if driver.is_none() {
-        return Err(Message::NotFound("Driver not found".to_string()));
+    let mut removed = 0;
+    for store in SNAPSHOT_STORES {
+        removed += match store {
+            SnapshotStore::Drivers => clear_store(&DRIVER_STORAGE),
+            SnapshotStore::Vehicles => clear_store(&VEHICLE_STORAGE),
+            SnapshotStore::Bookings => clear_store(&BOOKING_STORAGE),
+            SnapshotStore::FuelConsumptions => clear_store(&FUEL_CONSUMPTION_STORAGE),
+            SnapshotStore::Maintenances => clear_store(&MAINTENANCE_STORAGE),
+            SnapshotStore::EmergencyAssistances => clear_store(&EMERGENCY_ASSISTANCE_STORAGE),
+            SnapshotStore::Routes => clear_store(&ROUTE_STORAGE),
+            SnapshotStore::Trips => clear_store(&TRIP_STORAGE),
+            SnapshotStore::Users => clear_store(&USER_STORAGE),
+            SnapshotStore::Documents => clear_store(&DOCUMENT_STORAGE),
+            SnapshotStore::Shifts => clear_store(&SHIFT_STORAGE),
+            SnapshotStore::LeaveRequests => clear_store(&LEAVE_STORAGE),
+            SnapshotStore::Proposals => clear_store(&PROPOSAL_STORAGE),
+            SnapshotStore::Customers => clear_store(&CUSTOMER_STORAGE),
+            SnapshotStore::Tariffs => clear_store(&TARIFF_STORAGE),
+            SnapshotStore::Invoices => clear_store(&INVOICE_STORAGE),
+            SnapshotStore::Payments => clear_store(&PAYMENT_STORAGE),
+            SnapshotStore::Departments => clear_store(&DEPARTMENT_STORAGE),
+            SnapshotStore::ChargingSessions => clear_store(&CHARGING_STORAGE),
+            SnapshotStore::Stations => clear_store(&STATION_STORAGE),
+            SnapshotStore::Manifests => clear_store(&MANIFEST_STORAGE),
+            SnapshotStore::Subscriptions => clear_store(&SUBSCRIPTION_STORAGE),
+            SnapshotStore::Notifications => clear_store(&NOTIFICATION_STORAGE),
+            SnapshotStore::JobStatuses => clear_store(&JOB_STATUS_STORAGE),
+            SnapshotStore::AuditLog => clear_log(&AUDIT_LOG, 10, 11),
+            SnapshotStore::EventLog => clear_log(&EVENT_LOG, 12, 13),
+            SnapshotStore::NotificationConfig => reset_cell(&NOTIFICATION_CONFIG),
+            SnapshotStore::HoursOfServiceRules => reset_cell(&HOURS_OF_SERVICE_RULES),
+            SnapshotStore::PaymentConfig => reset_cell(&PAYMENT_CONFIG),
+            SnapshotStore::Counters => reset_cell(&ID_COUNTER),
+        };
     }
+    clear_store(&TRIP_INDEX);
+    reset_certification();
+    Ok(removed)
+}
 
-    // Validate the vehicle ID
-    let vehicle = VEHICLE_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .get(&payload.vehicle_id)
-            .map(|vehicle| vehicle.clone())
-    });
+// Function to load a snapshot chunk back into its store; the last chunk of the
+// last store rebuilds the trip index and the rollups; restored vehicles,
+// bookings and drivers are certified as they are loaded
+#[ic_cdk::update]
+fn restore_snapshot_chunk(chunk: SnapshotChunk) -> Result<u64, Message> {
+    require_controller()?;
 
-    if vehicle.is_none() {
-        return Err(Message::NotFound("Vehicle not found".to_string()));
+    if chunk.version != SNAPSHOT_VERSION {
+        return Err(Message::InvalidPayload(format!(
+            "Unsupported snapshot version {}; expected {}.",
+            chunk.version, SNAPSHOT_VERSION
+        )));
     }
-
-    let vehicle = vehicle.unwrap();
-    if vehicle.status != VehicleStatus::Available {
+    if chunk.checksum != snapshot_checksum(chunk.version, chunk.store, &chunk.data) {
         return Err(Message::InvalidPayload(
-            "Vehicle is not available.".to_string(),
+            "Snapshot chunk checksum does not match its data.".to_string(),
         ));
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
-
-    let booking = Booking {
-        id,
-        vehicle_id: payload.vehicle_id,
-        driver_id: payload.driver_id,
-        from_location: payload.from_location,
-        to_location: payload.to_location,
-        start_time: payload.start_time,
-        end_time: payload.end_time,
-        status: "pending".to_string(),
-        created_at: current_time(),
+    let restored = match chunk.store {
+        SnapshotStore::Drivers => {
+            restore_store_with(&DRIVER_STORAGE, &chunk.data, |id, driver| {
+                certify("drivers", *id, driver)
+            })?
+        }
+        SnapshotStore::Vehicles => {
+            restore_store_with(&VEHICLE_STORAGE, &chunk.data, |id, vehicle| {
+                certify("vehicles", *id, vehicle)
+            })?
+        }
+        SnapshotStore::Bookings => {
+            restore_store_with(&BOOKING_STORAGE, &chunk.data, |id, booking| {
+                certify("bookings", *id, booking)
+            })?
+        }
+        SnapshotStore::FuelConsumptions => restore_store(&FUEL_CONSUMPTION_STORAGE, &chunk.data)?,
+        SnapshotStore::Maintenances => restore_store(&MAINTENANCE_STORAGE, &chunk.data)?,
+        SnapshotStore::EmergencyAssistances => {
+            restore_store(&EMERGENCY_ASSISTANCE_STORAGE, &chunk.data)?
+        }
+        SnapshotStore::Routes => restore_store(&ROUTE_STORAGE, &chunk.data)?,
+        SnapshotStore::Trips => restore_store(&TRIP_STORAGE, &chunk.data)?,
+        SnapshotStore::Users => restore_store(&USER_STORAGE, &chunk.data)?,
+        SnapshotStore::Documents => restore_store(&DOCUMENT_STORAGE, &chunk.data)?,
+        SnapshotStore::Shifts => restore_store(&SHIFT_STORAGE, &chunk.data)?,
+        SnapshotStore::LeaveRequests => restore_store(&LEAVE_STORAGE, &chunk.data)?,
+        SnapshotStore::Proposals => restore_store(&PROPOSAL_STORAGE, &chunk.data)?,
+        SnapshotStore::Customers => restore_store(&CUSTOMER_STORAGE, &chunk.data)?,
+        SnapshotStore::Tariffs => restore_store(&TARIFF_STORAGE, &chunk.data)?,
+        SnapshotStore::Invoices => restore_store(&INVOICE_STORAGE, &chunk.data)?,
+        SnapshotStore::Payments => restore_store(&PAYMENT_STORAGE, &chunk.data)?,
+        SnapshotStore::Departments => restore_store(&DEPARTMENT_STORAGE, &chunk.data)?,
+        SnapshotStore::ChargingSessions => restore_store(&CHARGING_STORAGE, &chunk.data)?,
+        SnapshotStore::Stations => restore_store(&STATION_STORAGE, &chunk.data)?,
+        SnapshotStore::Manifests => restore_store(&MANIFEST_STORAGE, &chunk.data)?,
+        SnapshotStore::Subscriptions => restore_store(&SUBSCRIPTION_STORAGE, &chunk.data)?,
+        SnapshotStore::Notifications => restore_store(&NOTIFICATION_STORAGE, &chunk.data)?,
+        SnapshotStore::JobStatuses => restore_store(&JOB_STATUS_STORAGE, &chunk.data)?,
+        SnapshotStore::AuditLog => restore_log(&AUDIT_LOG, &chunk.data)?,
+        SnapshotStore::EventLog => restore_log(&EVENT_LOG, &chunk.data)?,
+        SnapshotStore::NotificationConfig => restore_cell(&NOTIFICATION_CONFIG, &chunk.data)?,
+        SnapshotStore::HoursOfServiceRules => restore_cell(&HOURS_OF_SERVICE_RULES, &chunk.data)?,
+        SnapshotStore::PaymentConfig => restore_cell(&PAYMENT_CONFIG, &chunk.data)?,
+        SnapshotStore::Counters => restore_cell(&ID_COUNTER, &chunk.data)?,
     };
+    if chunk.store == SNAPSHOT_STORES[SNAPSHOT_STORES.len() - 1] && chunk.next.is_none() {
+        index_trips();
+        start_rollup_rebuild();
+        // The audit log was restored as exported, so the restore is logged last
+        record_audit(
+            "restore_snapshot_chunk",
+            "snapshot",
+            0,
+            None,
+            Some(format!("{{\"stores\":{}}}", SNAPSHOT_STORES.len())),
+        );
+    }
+    Ok(restored)
+}
 
-    // Update vehicle status to Booked
-    let updated_vehicle = Vehicle {
-        status: VehicleStatus::Booked,
-        ..vehicle
-    };
-    VEHICLE_STORAGE.with(|storage| {
+// Helper function to decode the key a snapshot chunk starts after
+fn snapshot_cursor<K: candid::CandidType + serde::de::DeserializeOwned>(
+    start_after: Option<&[u8]>,
+) -> Result<Option<K>, Message> {
+    start_after
+        .map(|bytes| Decode!(bytes, K))
+        .transpose()
+        .map_err(|error| Message::InvalidPayload(format!("Invalid 'start_after': {}", error)))
+}
+
+// Helper function to encode a page of a store's records, keyed as stored
+fn export_store<K, V>(
+    store: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    start_after: Option<K>,
+    limit: usize,
+) -> (Vec<u8>, u64, Option<Vec<u8>>)
+where
+    K: BoundedStorable + Ord + Clone + candid::CandidType,
+    V: BoundedStorable + candid::CandidType,
+{
+    let from = start_after.map_or(Bound::Unbounded, Bound::Excluded);
+    let records: Vec<(K, V)> = store.with(|storage| {
         storage
-            .borrow_mut()
-            .insert(vehicle.id, updated_vehicle.clone())
+            .borrow()
+            .range((from, Bound::Unbounded))
+            .take(limit)
+            .collect()
     });
+    let next = if records.len() == limit {
+        records.last().map(|(key, _)| Encode!(key).unwrap())
+    } else {
+        None
+    };
+    (Encode!(&records).unwrap(), records.len() as u64, next)
+}
 
-    BOOKING_STORAGE.with(|storage| storage.borrow_mut().insert(id, booking.clone()));
-    Ok(booking)
+// Helper function to insert a page of exported records back into a store
+fn restore_store<K, V>(
+    store: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    data: &[u8],
+) -> Result<u64, Message>
+where
+    K: BoundedStorable + Ord + Clone + candid::CandidType + serde::de::DeserializeOwned,
+    V: BoundedStorable + candid::CandidType + serde::de::DeserializeOwned,
+{
+    restore_store_with(store, data, |_, _| {})
 }
 
-// Function to get all bookings
-#[ic_cdk::query]
-fn get_bookings() -> Result<Vec<Booking>, Message> {
-    BOOKING_STORAGE.with(|storage| {
-        let bookings: Vec<Booking> = storage
-            .borrow()
+// Helper function to insert a page of exported records back into a store,
+// handing each record on once it is stored
+fn restore_store_with<K, V>(
+    store: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    data: &[u8],
+    restored: impl Fn(&K, &V),
+) -> Result<u64, Message>
+where
+    K: BoundedStorable + Ord + Clone + candid::CandidType + serde::de::DeserializeOwned,
+    V: BoundedStorable + candid::CandidType + serde::de::DeserializeOwned,
+{
+    let records = Decode!(data, Vec<(K, V)>)
+        .map_err(|error| Message::InvalidPayload(format!("Invalid snapshot data: {}", error)))?;
+    let count = records.len() as u64;
+    store.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (key, record) in records {
+            storage.insert(key.clone(), record);
+            if let Some(record) = storage.get(&key) {
+                restored(&key, &record);
+            }
+        }
+    });
+    Ok(count)
+}
+
+// Helper function to remove every record of a store
+fn clear_store<K, V>(store: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>) -> u64
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    store.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let keys: Vec<K> = storage.iter().map(|(key, _)| key).collect();
+        for key in &keys {
+            storage.remove(key);
+        }
+        keys.len() as u64
+    })
+}
+
+// Helper function to encode a page of a log's entries with their indexes
+fn export_log<T: Storable + candid::CandidType>(
+    log: &'static LocalKey<RefCell<StableLog<T, Memory, Memory>>>,
+    start_after: Option<u64>,
+    limit: usize,
+) -> (Vec<u8>, u64, Option<Vec<u8>>) {
+    let from = start_after.map_or(0, |index| index.saturating_add(1));
+    let entries: Vec<(u64, T)> = log.with(|log| {
+        let log = log.borrow();
+        (from..log.len())
+            .take(limit)
+            .filter_map(|index| log.get(index).map(|entry| (index, entry)))
+            .collect()
+    });
+    let next = if entries.len() == limit {
+        entries.last().map(|(index, _)| Encode!(index).unwrap())
+    } else {
+        None
+    };
+    (Encode!(&entries).unwrap(), entries.len() as u64, next)
+}
+
+// Helper function to append a page of exported entries back onto a log; pages
+// must come in order, each starting where the log ends
+fn restore_log<T: Storable + candid::CandidType + serde::de::DeserializeOwned>(
+    log: &'static LocalKey<RefCell<StableLog<T, Memory, Memory>>>,
+    data: &[u8],
+) -> Result<u64, Message> {
+    let entries = Decode!(data, Vec<(u64, T)>)
+        .map_err(|error| Message::InvalidPayload(format!("Invalid snapshot data: {}", error)))?;
+    log.with(|log| {
+        let log = log.borrow_mut();
+        let length = log.len();
+        if entries
             .iter()
-            .map(|(_, booking)| booking.clone())
-            .collect();
-        if bookings.is_empty() {
-            Err(Message::NotFound("No bookings found".to_string()))
-        } else {
-            Ok(bookings)
+            .enumerate()
+            .any(|(offset, (index, _))| *index != length + offset as u64)
+        {
+            return Err(Message::InvalidPayload(format!(
+                "Restore the log's chunks in order; the log holds {} entries.",
+                length
+            )));
+        }
+        for (_, entry) in &entries {
+            log.append(entry).expect("Cannot append to the log");
         }
+        Ok(entries.len() as u64)
     })
 }
 
-// Function to get a booking by ID
-#[ic_cdk::query]
-fn get_booking_by_id(id: u64) -> Result<Booking, Message> {
-    BOOKING_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .get(&id)
-            .map(|booking| booking.clone())
-            .ok_or(Message::NotFound("Booking not found".to_string()))
+// Helper function to empty a log by creating a new one over its memories
+fn clear_log<T: Storable>(
+    log: &'static LocalKey<RefCell<StableLog<T, Memory, Memory>>>,
+    index_memory: u8,
+    data_memory: u8,
+) -> u64 {
+    log.with(|log| {
+        let removed = log.borrow().len();
+        *log.borrow_mut() = StableLog::new(memory(index_memory), memory(data_memory));
+        removed
     })
 }
 
-// Function to record fuel consumption
-#[ic_cdk::update]
-fn record_fuel_consumption(payload: FuelConsumptionPayload) -> Result<FuelConsumption, Message> {
-    // Validate the fuel consumption payload
-    if payload.amount <= 0.0 {
-        return Err(Message::InvalidPayload(
-            "Ensure 'amount' is greater than zero.".to_string(),
-        ));
+// Helper function to encode a cell's value
+fn export_cell<T: Storable + candid::CandidType>(
+    cell: &'static LocalKey<RefCell<Cell<T, Memory>>>,
+) -> (Vec<u8>, u64, Option<Vec<u8>>) {
+    (
+        cell.with(|cell| Encode!(cell.borrow().get()).unwrap()),
+        1,
+        None,
+    )
+}
+
+// Helper function to set a cell back to its exported value
+fn restore_cell<T: Storable + candid::CandidType + serde::de::DeserializeOwned>(
+    cell: &'static LocalKey<RefCell<Cell<T, Memory>>>,
+    data: &[u8],
+) -> Result<u64, Message> {
+    let value = Decode!(data, T)
+        .map_err(|error| Message::InvalidPayload(format!("Invalid snapshot data: {}", error)))?;
+    cell.with(|cell| cell.borrow_mut().set(value))
+        .expect("Cannot restore the value");
+    Ok(1)
+}
+
+// Helper function to set a cell back to its default value
+fn reset_cell<T: Storable + Default>(cell: &'static LocalKey<RefCell<Cell<T, Memory>>>) -> u64 {
+    cell.with(|cell| cell.borrow_mut().set(T::default()))
+        .expect("Cannot reset the value");
+    0
+}
+
+// Helper function to get one of the memory manager's virtual memories
+fn memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
+
+// Helper function to checksum a snapshot chunk
+fn snapshot_checksum(version: u32, store: SnapshotStore, data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(version.to_be_bytes());
+    hasher.update([store as u8]);
+    hasher.update(data);
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Function to report on the background jobs
+#[ic_cdk::query]
+fn get_job_statuses() -> Result<Vec<JobStatus>, Message> {
+    require_role(&[UserRole::Admin])?;
+
+    Ok(BACKGROUND_JOBS
+        .iter()
+        .map(|(name, interval_secs, _)| {
+            JOB_STATUS_STORAGE
+                .with(|storage| storage.borrow().get(&JobName(name.to_string())))
+                .unwrap_or_else(|| JobStatus {
+                    name: name.to_string(),
+                    interval_secs: *interval_secs,
+                    ..Default::default()
+                })
+        })
+        .collect())
+}
+
+#[ic_cdk::init]
+fn init() {
+    start_background_jobs();
+    reset_certification();
+}
+
+// Timers and the certified tree do not survive upgrades, so they are set up
+// again here
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    start_background_jobs();
+    start_certification();
+    // Trips recorded before the booking index existed
+    if TRIP_INDEX.with(|index| index.borrow().len())
+        < TRIP_STORAGE.with(|storage| storage.borrow().len())
+    {
+        index_trips();
+    }
+    // A rollup rebuild interrupted by the upgrade
+    if ROLLUP_REBUILD.with(|cell| cell.borrow().get().stage.is_some()) {
+        schedule_rollup_rebuild();
+    }
+}
+
+// Helper function to arm a timer for every background job, and one delivering
+// queued notifications
+fn start_background_jobs() {
+    for (index, (_, interval_secs, _)) in BACKGROUND_JOBS.iter().enumerate() {
+        ic_cdk_timers::set_timer_interval(Duration::from_secs(*interval_secs), move || {
+            run_background_job(index)
+        });
     }
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(WEBHOOK_DELIVERY_INTERVAL_SECS), || {
+        ic_cdk::spawn(async {
+            deliver_due_notifications(&HttpOutcallTransport).await;
+        })
+    });
+}
 
-    // Validate the vehicle ID
-    let vehicle = VEHICLE_STORAGE.with(|storage| {
+// Helper function to run a background job and record its status
+fn run_background_job(index: usize) {
+    let (name, interval_secs, job) = BACKGROUND_JOBS[index];
+    let key = JobName(name.to_string());
+    let previous = JOB_STATUS_STORAGE.with(|storage| storage.borrow().get(&key));
+    let now = current_time();
+    let outcome = job(now, previous.as_ref().and_then(|status| status.last_run));
+
+    let status = JobStatus {
+        name: name.to_string(),
+        interval_secs,
+        runs: previous.map_or(0, |status| status.runs) + 1,
+        last_run: Some(now),
+        last_outcome: Some(outcome),
+    };
+    JOB_STATUS_STORAGE.with(|storage| storage.borrow_mut().insert(key, status));
+}
+
+// Background job marking bookings whose start time passed without the trip starting
+fn mark_no_show_bookings(now: u64, _last_run: Option<u64>) -> String {
+    let cutoff = now.saturating_sub(NO_SHOW_GRACE_SECS * NANOS_PER_SECOND);
+    let no_shows: Vec<Booking> = BOOKING_STORAGE.with(|storage| {
         storage
             .borrow()
-            .get(&payload.vehicle_id)
-            .map(|vehicle| vehicle.clone())
+            .iter()
+            .map(|(_, booking)| booking)
+            .filter(|booking| {
+                (booking.status == "pending" || booking.status == "approved")
+                    && booking.start_time < cutoff
+            })
+            .collect()
     });
 
-    if vehicle.is_none() {
-        return Err(Message::NotFound("Vehicle not found".to_string()));
-    }
+    for booking in &no_shows {
+        let updated_booking = Booking {
+            status: "no_show".to_string(),
+            ..booking.clone()
+        };
+        BOOKING_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(updated_booking.id, updated_booking.clone())
+        });
+        certify("bookings", updated_booking.id, &updated_booking);
+        rollup_booking_change(&updated_booking, Some(&booking.status));
+        record_audit(
+            "mark_no_show_bookings",
+            "booking",
+            booking.id,
+            Some(snapshot(booking)),
+            Some(snapshot(&updated_booking)),
+        );
 
-    // Update the fuel consumption record
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+        // Release the vehicle held by the booking
+        if let Some(vehicle) =
+            VEHICLE_STORAGE.with(|storage| storage.borrow().get(&booking.vehicle_id))
+        {
+            if vehicle.status == VehicleStatus::Booked {
+                let updated_vehicle = Vehicle {
+                    status: VehicleStatus::Available,
+                    ..vehicle.clone()
+                };
+                VEHICLE_STORAGE.with(|storage| {
+                    storage
+                        .borrow_mut()
+                        .insert(updated_vehicle.id, updated_vehicle.clone())
+                });
+                certify("vehicles", updated_vehicle.id, &updated_vehicle);
+                record_audit(
+                    "mark_no_show_bookings",
+                    "vehicle",
+                    vehicle.id,
+                    Some(snapshot(&vehicle)),
+                    Some(snapshot(&updated_vehicle)),
+                );
+            }
+        }
 
-    let fuel_consumption = FuelConsumption {
-        id,
-        vehicle_id: payload.vehicle_id,
-        amount: payload.amount,
-        date: payload.date,
-    };
+        emit_event(FleetEventKind::BookingStatusChanged {
+            booking: updated_booking,
+            previous_status: Some(booking.status.clone()),
+        });
 
-    FUEL_CONSUMPTION_STORAGE
-        .with(|storage| storage.borrow_mut().insert(id, fuel_consumption.clone()));
-    Ok(fuel_consumption)
+        // The deposit of a no-show is forfeited
+        if let Some(payment) = PAYMENT_STORAGE.with(|storage| storage.borrow().get(&booking.id)) {
+            if payment.status == PaymentStatus::DepositHeld {
+                let deposit = payment.deposit;
+                set_amount_due("mark_no_show_bookings", payment, deposit);
+            }
+        }
+    }
+    format!("{} booking(s) marked as no-show", no_shows.len())
 }
 
-// Function to get all fuel consumption records
-#[ic_cdk::query]
-fn get_fuel_consumptions() -> Result<Vec<FuelConsumption>, Message> {
-    FUEL_CONSUMPTION_STORAGE.with(|storage| {
-        let records: Vec<FuelConsumption> = storage
+// Background job flagging overdue maintenance and reminding of upcoming maintenance
+fn check_maintenance(now: u64, last_run: Option<u64>) -> String {
+    let window = MAINTENANCE_REMINDER_WINDOW_SECS * NANOS_PER_SECOND;
+    let pending: Vec<Maintenance> = MAINTENANCE_STORAGE.with(|storage| {
+        storage
             .borrow()
             .iter()
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err(Message::NotFound(
-                "No fuel consumption records found".to_string(),
-            ))
-        } else {
-            Ok(records)
+            .map(|(_, maintenance)| maintenance)
+            .filter(|maintenance| maintenance.status == "pending")
+            .collect()
+    });
+
+    let mut overdue = 0;
+    let mut reminded = 0;
+    for maintenance in pending {
+        if maintenance.scheduled_date < now {
+            let updated_maintenance = Maintenance {
+                status: "overdue".to_string(),
+                ..maintenance.clone()
+            };
+            MAINTENANCE_STORAGE.with(|storage| {
+                storage
+                    .borrow_mut()
+                    .insert(updated_maintenance.id, updated_maintenance.clone())
+            });
+            record_audit(
+                "check_maintenance",
+                "maintenance",
+                maintenance.id,
+                Some(snapshot(&maintenance)),
+                Some(snapshot(&updated_maintenance)),
+            );
+            notify(
+                NotificationTopic::MaintenanceOverdue,
+                format!(
+                    "Maintenance for vehicle {} is overdue: {}",
+                    maintenance.vehicle_id, maintenance.description
+                ),
+            );
+            overdue += 1;
+        } else if entered_window(
+            maintenance.scheduled_date,
+            window,
+            now,
+            last_run,
+            maintenance.created_at,
+        ) {
+            notify(
+                NotificationTopic::MaintenanceDue,
+                format!(
+                    "Maintenance for vehicle {} is coming up: {}",
+                    maintenance.vehicle_id, maintenance.description
+                ),
+            );
+            reminded += 1;
         }
-    })
+    }
+    format!(
+        "{} maintenance record(s) overdue, {} reminder(s) sent",
+        overdue, reminded
+    )
 }
 
-// Function to get a fuel consumption record by ID
-#[ic_cdk::query]
-fn get_fuel_consumption_by_id(id: u64) -> Result<FuelConsumption, Message> {
-    FUEL_CONSUMPTION_STORAGE.with(|storage| {
+// Background job warning about driver licenses and vehicle documents about to expire
+fn warn_expiring_documents(now: u64, last_run: Option<u64>) -> String {
+    let window = EXPIRY_WARNING_WINDOW_SECS * NANOS_PER_SECOND;
+    let mut warnings: Vec<String> = DRIVER_STORAGE.with(|storage| {
         storage
             .borrow()
-            .get(&id)
-            .map(|record| record.clone())
-            .ok_or(Message::NotFound(
-                "Fuel consumption record not found".to_string(),
-            ))
-    })
-}
+            .iter()
+            .map(|(_, driver)| driver)
+            .filter(|driver| {
+                driver.license_expiry.is_some_and(|expiry| {
+                    entered_window(expiry, window, now, last_run, driver.created_at)
+                })
+            })
+            .map(|driver| {
+                format!(
+                    "License {} of driver {} ({}) is about to expire",
+                    driver.license_number, driver.id, driver.name
+                )
+            })
+            .collect()
+    });
+    warnings.extend(DOCUMENT_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        storage
+            .iter()
+            .map(|(_, document)| document)
+            .filter(|document| {
+                entered_window(
+                    document.expires_at,
+                    window,
+                    now,
+                    last_run,
+                    document.created_at,
+                )
+            })
+            // Skip documents that have already been renewed
+            .filter(|document| {
+                !storage.iter().any(|(_, other)| {
+                    other.vehicle_id == document.vehicle_id
+                        && other.kind == document.kind
+                        && other.expires_at > document.expires_at
+                })
+            })
+            .map(|document| {
+                format!(
+                    "{:?} {} of vehicle {} is about to expire",
+                    document.kind, document.reference_number, document.vehicle_id
+                )
+            })
+            .collect::<Vec<String>>()
+    }));
 
-// Function to schedule maintenance
-#[ic_cdk::update]
-fn schedule_maintenance(payload: MaintenancePayload) -> Result<Maintenance, Message> {
-    // Validate the maintenance payload
-    if payload.description.is_empty() || payload.scheduled_date <= current_time() {
-        return Err(Message::InvalidPayload(
-            "Ensure 'description' and valid 'scheduled_date' are provided.".to_string(),
-        ));
+    let count = warnings.len();
+    for warning in warnings {
+        notify(NotificationTopic::DocumentExpiring, warning);
     }
+    format!("{} expiry warning(s) sent", count)
+}
 
-    // Validate the vehicle ID
-    let vehicle = VEHICLE_STORAGE.with(|storage| {
+// Background job compacting the telemetry of trips completed longer ago than
+// the retention period: the driven route trace is dropped, while the distance,
+// fuel and timing figures that reports rely on are kept
+fn compact_telemetry(now: u64, _last_run: Option<u64>) -> String {
+    let cutoff = now.saturating_sub(TRIP_TRACE_RETENTION_SECS * NANOS_PER_SECOND);
+    let expired: Vec<Trip> = TRIP_STORAGE.with(|storage| {
         storage
             .borrow()
-            .get(&payload.vehicle_id)
-            .map(|vehicle| vehicle.clone())
+            .iter()
+            .map(|(_, trip)| trip)
+            .filter(|trip| {
+                trip.end_time.is_some_and(|end_time| end_time < cutoff)
+                    && !trip.route_taken.is_empty()
+            })
+            .collect()
     });
 
-    if vehicle.is_none() {
-        return Err(Message::NotFound("Vehicle not found".to_string()));
+    for trip in &expired {
+        let compacted = Trip {
+            route_taken: String::new(),
+            ..trip.clone()
+        };
+        TRIP_STORAGE.with(|storage| storage.borrow_mut().insert(compacted.id, compacted.clone()));
+        record_audit(
+            "compact_telemetry",
+            "trip",
+            trip.id,
+            Some(snapshot(trip)),
+            Some(snapshot(&compacted)),
+        );
     }
-
-    // Update the maintenance record
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
-
-    let maintenance = Maintenance {
-        id,
-        vehicle_id: payload.vehicle_id,
-        description: payload.description,
-        scheduled_date: payload.scheduled_date,
-        status: "pending".to_string(),
-        created_at: current_time(),
-    };
-
-    MAINTENANCE_STORAGE.with(|storage| storage.borrow_mut().insert(id, maintenance.clone()));
-    Ok(maintenance)
+    format!("{} trip record(s) compacted", expired.len())
 }
 
-// Function to get all maintenance records
-#[ic_cdk::query]
-fn get_maintenances() -> Result<Vec<Maintenance>, Message> {
-    MAINTENANCE_STORAGE.with(|storage| {
-        let records: Vec<Maintenance> = storage
-            .borrow()
-            .iter()
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err(Message::NotFound(
-                "No maintenance records found".to_string(),
-            ))
-        } else {
-            Ok(records)
-        }
-    })
+// Helper function telling whether a deadline came within the warning window
+// since the last run (or was recorded since then already inside it), so each
+// deadline is only warned about once
+fn entered_window(
+    deadline: u64,
+    window: u64,
+    now: u64,
+    last_run: Option<u64>,
+    created_at: u64,
+) -> bool {
+    let warn_from = deadline.saturating_sub(window);
+    deadline > now
+        && warn_from <= now
+        && last_run.is_none_or(|last_run| warn_from > last_run || created_at > last_run)
 }
 
-// Function to get a maintenance record by ID
-#[ic_cdk::query]
-fn get_maintenance_by_id(id: u64) -> Result<Maintenance, Message> {
-    MAINTENANCE_STORAGE.with(|storage| {
+// Helper function to resolve the caller's role; controllers are always admins
+fn caller_role() -> UserRole {
+    let caller = caller();
+    if is_controller(&caller) {
+        return UserRole::Admin;
+    }
+    USER_STORAGE.with(|storage| {
         storage
             .borrow()
-            .get(&id)
-            .map(|record| record.clone())
-            .ok_or(Message::NotFound(
-                "Maintenance record not found".to_string(),
-            ))
+            .get(&PrincipalKey(caller))
+            .map_or(UserRole::default(), |account| account.role)
     })
 }
 
-// Function to request emergency assistance
+// Helper function to reject callers without one of the given roles
+fn require_role(roles: &[UserRole]) -> Result<(), Message> {
+    if roles.contains(&caller_role()) {
+        Ok(())
+    } else {
+        Err(Message::Unauthorized(
+            "Caller is not allowed to perform this action.".to_string(),
+        ))
+    }
+}
+
+// Helper function to reject callers other than the canister's controllers
+fn require_controller() -> Result<(), Message> {
+    if is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Message::Unauthorized(
+            "Only controllers can perform this action.".to_string(),
+        ))
+    }
+}
+
+// Helper function to get the driver record the caller acts for, if the caller
+// holds the Driver role
+fn caller_driver_id() -> Option<u64> {
+    USER_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(caller())))
+        .filter(|account| account.role == UserRole::Driver)
+        .and_then(|account| account.driver_id)
+}
+
+// Helper function to reject callers other than admins, managers and the
+// principal linked to the given driver
+fn require_driver_access(driver_id: u64) -> Result<(), Message> {
+    if matches!(caller_role(), UserRole::Admin | UserRole::Manager)
+        || caller_driver_id() == Some(driver_id)
+    {
+        Ok(())
+    } else {
+        Err(Message::Unauthorized(
+            "Caller is not allowed to act for this driver.".to_string(),
+        ))
+    }
+}
+
+// Helper function to append an entry to the audit log
+fn record_audit(
+    endpoint: &str,
+    entity_type: &str,
+    entity_id: u64,
+    before: Option<String>,
+    after: Option<String>,
+) {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow_mut();
+        let entry = AuditEntry {
+            seq: log.len(),
+            caller: caller(),
+            endpoint: endpoint.to_string(),
+            entity_type: entity_type.to_string(),
+            entity_id,
+            before,
+            after,
+            timestamp: current_time(),
+        };
+        log.append(&entry).expect("Cannot append to the audit log");
+    });
+}
+
+// Function to configure webhook delivery
 #[ic_cdk::update]
-fn request_emergency_assistance(
-    payload: EmergencyAssistancePayload,
-) -> Result<EmergencyAssistance, Message> {
-    // Validate the emergency assistance payload
-    if payload.description.is_empty() || payload.location.is_empty() {
+fn set_notification_config(config: NotificationConfig) -> Result<NotificationConfig, Message> {
+    require_role(&[UserRole::Admin])?;
+
+    if !config.webhook_url.is_empty() && !config.webhook_url.starts_with("https://") {
         return Err(Message::InvalidPayload(
-            "Ensure 'description' and 'location' are provided.".to_string(),
+            "Ensure 'webhook_url' is an https:// URL.".to_string(),
+        ));
+    }
+    if config.max_attempts == 0 {
+        return Err(Message::InvalidPayload(
+            "Ensure 'max_attempts' is greater than zero.".to_string(),
         ));
     }
 
-    // Validate the vehicle ID
-    let vehicle = VEHICLE_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .get(&payload.vehicle_id)
-            .map(|vehicle| vehicle.clone())
+    let before = NOTIFICATION_CONFIG.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let before = snapshot(cell.get());
+        cell.set(config.clone())
+            .expect("Cannot update the notification config");
+        before
     });
+    record_audit(
+        "set_notification_config",
+        "notification_config",
+        0,
+        Some(before),
+        Some(snapshot(&config)),
+    );
+    Ok(config)
+}
 
-    if vehicle.is_none() {
-        return Err(Message::NotFound("Vehicle not found".to_string()));
-    }
+// Function to get the webhook delivery configuration
+#[ic_cdk::query]
+fn get_notification_config() -> Result<NotificationConfig, Message> {
+    require_role(&[UserRole::Admin])?;
+    Ok(NOTIFICATION_CONFIG.with(|cell| cell.borrow().get().clone()))
+}
+
+// Function to subscribe a role or principal to notification topics
+#[ic_cdk::update]
+fn subscribe_notifications(payload: SubscriptionPayload) -> Result<Subscription, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
 
-    // Update the emergency assistance record
     let id = ID_COUNTER
         .with(|counter| {
             let current_value = *counter.borrow().get();
@@ -750,123 +8464,351 @@ fn request_emergency_assistance(
         })
         .expect("Cannot increment ID counter");
 
-    let assistance = EmergencyAssistance {
+    let subscription = Subscription {
         id,
-        vehicle_id: payload.vehicle_id,
-        description: payload.description,
-        location: payload.location,
-        status: "pending".to_string(),
+        target: payload.target,
+        topics: payload.topics,
         created_at: current_time(),
     };
 
-    EMERGENCY_ASSISTANCE_STORAGE
-        .with(|storage| storage.borrow_mut().insert(id, assistance.clone()));
-    Ok(assistance)
+    SUBSCRIPTION_STORAGE.with(|storage| storage.borrow_mut().insert(id, subscription.clone()));
+    record_audit(
+        "subscribe_notifications",
+        "subscription",
+        id,
+        None,
+        Some(snapshot(&subscription)),
+    );
+    Ok(subscription)
 }
 
-// Function to get all emergency assistance records
+// Function to remove a notification subscription
+#[ic_cdk::update]
+fn unsubscribe_notifications(id: u64) -> Result<Subscription, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    let subscription = SUBSCRIPTION_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&id))
+        .ok_or(Message::NotFound("Subscription not found".to_string()))?;
+    record_audit(
+        "unsubscribe_notifications",
+        "subscription",
+        id,
+        Some(snapshot(&subscription)),
+        None,
+    );
+    Ok(subscription)
+}
+
+// Function to get all notification subscriptions
 #[ic_cdk::query]
-fn get_emergency_assistances() -> Result<Vec<EmergencyAssistance>, Message> {
-    EMERGENCY_ASSISTANCE_STORAGE.with(|storage| {
-        let records: Vec<EmergencyAssistance> = storage
+fn get_subscriptions() -> Result<Vec<Subscription>, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    SUBSCRIPTION_STORAGE.with(|storage| {
+        let subscriptions: Vec<Subscription> = storage
             .borrow()
             .iter()
-            .map(|(_, record)| record.clone())
+            .map(|(_, subscription)| subscription)
             .collect();
-        if records.is_empty() {
-            Err(Message::NotFound(
-                "No emergency assistance records found".to_string(),
-            ))
+        if subscriptions.is_empty() {
+            Err(Message::NotFound("No subscriptions found".to_string()))
         } else {
-            Ok(records)
+            Ok(subscriptions)
         }
     })
 }
 
-// Function to get an emergency assistance record by ID
+// Function to get queued and sent notifications, optionally by delivery status
 #[ic_cdk::query]
-fn get_emergency_assistance_by_id(id: u64) -> Result<EmergencyAssistance, Message> {
-    EMERGENCY_ASSISTANCE_STORAGE.with(|storage| {
-        storage
+fn get_notifications(status: Option<DeliveryStatus>) -> Result<Vec<Notification>, Message> {
+    require_role(&[UserRole::Admin, UserRole::Manager])?;
+
+    NOTIFICATION_STORAGE.with(|storage| {
+        let notifications: Vec<Notification> = storage
             .borrow()
-            .get(&id)
-            .map(|record| record.clone())
-            .ok_or(Message::NotFound(
-                "Emergency assistance record not found".to_string(),
-            ))
+            .iter()
+            .map(|(_, notification)| notification)
+            .filter(|notification| status.is_none_or(|status| notification.status == status))
+            .collect();
+        if notifications.is_empty() {
+            Err(Message::NotFound("No notifications found".to_string()))
+        } else {
+            Ok(notifications)
+        }
     })
 }
 
-// Function to create an optimized route
+// Function to deliver due notifications now instead of waiting for the next attempt
 #[ic_cdk::update]
-fn create_route(payload: RoutePayload) -> Result<Route, Message> {
-    // Validate the route payload
-    if payload.from_location.is_empty() || payload.to_location.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Ensure 'from_location' and 'to_location' are provided.".to_string(),
-        ));
-    }
+async fn process_notifications() -> Result<u64, Message> {
+    require_role(&[UserRole::Admin])?;
+    Ok(deliver_due_notifications(&HttpOutcallTransport).await)
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+// Function to strip the webhook response down to what replicas can agree on
+#[ic_cdk::query]
+fn transform_webhook_response(args: outcall::TransformArgs) -> outcall::HttpResponse {
+    outcall::HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
 
-    // Dummy data for route optimization
-    let optimized_route = format!(
-        "Optimized route from {} to {}",
-        payload.from_location, payload.to_location
-    );
-    let distance = 100.0; // Dummy distance
-    let time_estimate = 3600; // Dummy time estimate in seconds
+// Transport used to POST notifications to the configured webhook
+trait WebhookTransport {
+    // Returns the HTTP status code of the webhook's response
+    async fn post(&self, url: &str, body: Vec<u8>) -> Result<u16, String>;
+}
 
-    let route = Route {
-        id,
-        from_location: payload.from_location,
-        to_location: payload.to_location,
-        optimized_route,
-        distance,
-        time_estimate,
-    };
+// Webhook transport backed by IC HTTPS outcalls
+struct HttpOutcallTransport;
 
-    ROUTE_STORAGE.with(|storage| storage.borrow_mut().insert(id, route.clone()));
-    Ok(route)
+impl WebhookTransport for HttpOutcallTransport {
+    async fn post(&self, url: &str, body: Vec<u8>) -> Result<u16, String> {
+        let request = outcall::CanisterHttpRequestArgument {
+            url: url.to_string(),
+            max_response_bytes: Some(2048),
+            method: outcall::HttpMethod::POST,
+            headers: vec![outcall::HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            }],
+            body: Some(body),
+            transform: Some(outcall::TransformContext::from_name(
+                "transform_webhook_response".to_string(),
+                vec![],
+            )),
+        };
+        let (response,) = outcall::http_request(request, WEBHOOK_OUTCALL_CYCLES)
+            .await
+            .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+        u16::try_from(response.status.0)
+            .map_err(|_| "Webhook responded with an invalid status code".to_string())
+    }
 }
 
-// Function to get all routes
-#[ic_cdk::query]
-fn get_routes() -> Result<Vec<Route>, Message> {
-    ROUTE_STORAGE.with(|storage| {
-        let routes: Vec<Route> = storage
+// Helper function to queue a notification for every subscriber of a topic;
+// the delivery timer sends it
+fn notify(topic: NotificationTopic, message: String) {
+    let subscriptions: Vec<Subscription> = SUBSCRIPTION_STORAGE.with(|storage| {
+        storage
             .borrow()
             .iter()
-            .map(|(_, route)| route.clone())
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| {
+                subscription.topics.is_empty() || subscription.topics.contains(&topic)
+            })
+            .collect()
+    });
+    if subscriptions.is_empty() {
+        return;
+    }
+
+    let now = current_time();
+    for subscription in subscriptions {
+        let id = ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("Cannot increment ID counter");
+        let notification = Notification {
+            id,
+            subscription_id: subscription.id,
+            recipient: subscription.target,
+            topic,
+            message: message.clone(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            delivered_at: None,
+            created_at: now,
+        };
+        NOTIFICATION_STORAGE.with(|storage| storage.borrow_mut().insert(id, notification));
+    }
+}
+
+// Helper function to deliver pending notifications whose next attempt is due,
+// backing off exponentially after failures; returns how many were delivered
+async fn deliver_due_notifications<T: WebhookTransport>(transport: &T) -> u64 {
+    let config = NOTIFICATION_CONFIG.with(|cell| cell.borrow().get().clone());
+    if config.webhook_url.is_empty() {
+        return 0;
+    }
+
+    // Lease the batch so overlapping runs do not send the same notification twice
+    let now = current_time();
+    let due: Vec<Notification> = NOTIFICATION_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let due: Vec<Notification> = storage
+            .iter()
+            .map(|(_, notification)| notification)
+            .filter(|notification| {
+                notification.status == DeliveryStatus::Pending
+                    && notification.next_attempt_at <= now
+            })
+            .take(WEBHOOK_BATCH_SIZE)
             .collect();
-        if routes.is_empty() {
-            Err(Message::NotFound("No routes found".to_string()))
-        } else {
-            Ok(routes)
+        for notification in &due {
+            let leased = Notification {
+                next_attempt_at: now + WEBHOOK_DELIVERY_LEASE_SECS * NANOS_PER_SECOND,
+                ..notification.clone()
+            };
+            storage.insert(leased.id, leased);
         }
-    })
+        due
+    });
+
+    let mut delivered = 0;
+    for notification in due {
+        let body = serde_json::json!({
+            "id": notification.id,
+            "topic": notification.topic,
+            "recipient": notification.recipient,
+            "message": notification.message,
+            "created_at": notification.created_at,
+        })
+        .to_string()
+        .into_bytes();
+
+        let result = transport.post(&config.webhook_url, body).await;
+        let attempts = notification.attempts + 1;
+        let now = current_time();
+        let updated = match result {
+            Ok(status) if (200..300).contains(&status) => {
+                delivered += 1;
+                Notification {
+                    status: DeliveryStatus::Delivered,
+                    attempts,
+                    last_error: None,
+                    delivered_at: Some(now),
+                    ..notification
+                }
+            }
+            outcome => {
+                let error = match outcome {
+                    Ok(status) => format!("Webhook responded with status {}", status),
+                    Err(error) => error,
+                };
+                let backoff = config
+                    .base_backoff_secs
+                    .saturating_mul(1 << (attempts - 1).min(16));
+                Notification {
+                    status: if attempts >= config.max_attempts {
+                        DeliveryStatus::Failed
+                    } else {
+                        DeliveryStatus::Pending
+                    },
+                    attempts,
+                    next_attempt_at: now + backoff * NANOS_PER_SECOND,
+                    last_error: Some(error),
+                    ..notification
+                }
+            }
+        };
+        NOTIFICATION_STORAGE.with(|storage| storage.borrow_mut().insert(updated.id, updated));
+    }
+    delivered
 }
 
-// Function to get a route by ID
-#[ic_cdk::query]
-fn get_route_by_id(id: u64) -> Result<Route, Message> {
-    ROUTE_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .get(&id)
-            .map(|route| route.clone())
-            .ok_or(Message::NotFound("Route not found".to_string()))
+// Helper function to publish a domain event on the change feed
+fn emit_event(kind: FleetEventKind) {
+    match &kind {
+        FleetEventKind::EmergencyRaised(assistance) => notify(
+            NotificationTopic::EmergencyRaised,
+            format!(
+                "Emergency on vehicle {} at {}: {}",
+                assistance.vehicle_id, assistance.location, assistance.description
+            ),
+        ),
+        FleetEventKind::MaintenanceScheduled(maintenance) => notify(
+            NotificationTopic::MaintenanceScheduled,
+            format!(
+                "Maintenance scheduled for vehicle {}: {}",
+                maintenance.vehicle_id, maintenance.description
+            ),
+        ),
+        _ => {}
+    }
+
+    EVENT_LOG.with(|log| {
+        let log = log.borrow_mut();
+        let event = FleetEvent {
+            seq: log.len(),
+            timestamp: current_time(),
+            kind,
+        };
+        log.append(&event).expect("Cannot append to the event log");
+    });
+}
+
+// Helper function to snapshot an entity as JSON for the audit log
+fn snapshot<T: serde::Serialize>(entity: &T) -> String {
+    serde_json::to_string(entity).unwrap_or_default()
+}
+
+// Helper function to get the configured ledger, if payments are enabled
+#[cfg(not(test))]
+fn payment_ledger() -> Option<IcrcLedger> {
+    PAYMENT_CONFIG.with(|cell| {
+        cell.borrow()
+            .get()
+            .ledger_canister_id
+            .map(|canister_id| IcrcLedger { canister_id })
     })
 }
 
+// Helper function to replay the next batch of a rollup rebuild in a message of its own
+#[cfg(not(test))]
+fn schedule_rollup_rebuild() {
+    ic_cdk_timers::set_timer(Duration::ZERO, continue_rollup_rebuild);
+}
+
+// Helper function to certify the next batch of records in a message of its own
+#[cfg(not(test))]
+fn schedule_certification() {
+    ic_cdk_timers::set_timer(Duration::ZERO, continue_certification);
+}
+
 // Helper function to get the current time
+#[cfg(not(test))]
 fn current_time() -> u64 {
     time()
 }
 
+// Helper function to get the principal calling the canister
+#[cfg(not(test))]
+fn caller() -> Principal {
+    ic_cdk::caller()
+}
+
+// Helper function to tell whether a principal controls the canister
+#[cfg(not(test))]
+fn is_controller(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+}
+
+// Helper function to publish the root hash of the certified tree
+#[cfg(not(test))]
+fn set_certified_data(data: &[u8]) {
+    ic_cdk::api::set_certified_data(data)
+}
+
+// Helper function to get the certificate of the certified data, in queries only
+#[cfg(not(test))]
+fn data_certificate() -> Option<Vec<u8>> {
+    ic_cdk::api::data_certificate()
+}
+
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests;
+#[cfg(test)]
+use tests::{
+    caller, current_time, data_certificate, is_controller, payment_ledger, schedule_certification,
+    schedule_rollup_rebuild, set_certified_data,
+};
@@ -0,0 +1,2732 @@
+use super::*;
+use std::future::Future;
+use std::task::{Context, Poll, Waker};
+
+const HOUR: u64 = 60 * 60 * NANOS_PER_SECOND;
+const DAY: u64 = 24 * HOUR;
+const START: u64 = 1_700_000_000 * NANOS_PER_SECOND; // 2023-11-14T22:13:20Z
+
+thread_local! {
+    static CALLER: RefCell<Principal> = RefCell::new(admin());
+    static NOW: RefCell<u64> = const { RefCell::new(START) };
+    static LEDGER: RefCell<LedgerState> = RefCell::new(LedgerState::default());
+}
+
+// Stand-ins for the system API, which is only available inside a canister
+
+pub(super) fn current_time() -> u64 {
+    NOW.with(|now| *now.borrow())
+}
+
+pub(super) fn caller() -> Principal {
+    CALLER.with(|caller| *caller.borrow())
+}
+
+pub(super) fn is_controller(principal: &Principal) -> bool {
+    *principal == admin()
+}
+
+pub(super) fn set_certified_data(_data: &[u8]) {}
+
+pub(super) fn data_certificate() -> Option<Vec<u8>> {
+    Some(b"certificate".to_vec())
+}
+
+// Rebuild batches run when the test calls continue_rollup_rebuild
+pub(super) fn schedule_rollup_rebuild() {}
+
+// Certification batches run when the test calls continue_certification
+pub(super) fn schedule_certification() {}
+
+pub(super) fn payment_ledger() -> Option<MockLedger> {
+    PAYMENT_CONFIG.with(|cell| cell.borrow().get().ledger_canister_id.map(|_| MockLedger))
+}
+
+// Mock ledger that answers at once and deduplicates transfers like an ICRC-1
+// ledger; `rejections` refuses the next transfers, `lost_replies` executes them
+// but reports an error, as when the reply never reaches the canister
+#[derive(Default)]
+struct LedgerState {
+    fee: u64,
+    rejections: u32,
+    lost_replies: u32,
+    transfers: Vec<LedgerTransfer>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct LedgerTransfer {
+    incoming: bool, // icrc2_transfer_from into the canister
+    account: Principal,
+    amount: u64,
+    created_at_time: u64,
+}
+
+pub(super) struct MockLedger;
+
+impl MockLedger {
+    fn record(&self, transfer: LedgerTransfer) -> Result<u64, String> {
+        LEDGER.with(|ledger| {
+            let mut ledger = ledger.borrow_mut();
+            if ledger.rejections > 0 {
+                ledger.rejections -= 1;
+                return Err("InsufficientFunds".to_string());
+            }
+            if let Some(block) = ledger.transfers.iter().position(|done| *done == transfer) {
+                return Ok(block as u64);
+            }
+            ledger.transfers.push(transfer);
+            if ledger.lost_replies > 0 {
+                ledger.lost_replies -= 1;
+                return Err("SysTransient: reply lost".to_string());
+            }
+            Ok(ledger.transfers.len() as u64 - 1)
+        })
+    }
+}
+
+impl Ledger for MockLedger {
+    async fn transfer_from(
+        &self,
+        from: Principal,
+        amount: u64,
+        _booking_id: u64,
+        created_at_time: u64,
+    ) -> Result<u64, String> {
+        self.record(LedgerTransfer {
+            incoming: true,
+            account: from,
+            amount,
+            created_at_time,
+        })
+    }
+
+    async fn transfer(
+        &self,
+        to: Principal,
+        amount: u64,
+        _booking_id: u64,
+        created_at_time: u64,
+    ) -> Result<u64, String> {
+        self.record(LedgerTransfer {
+            incoming: false,
+            account: to,
+            amount,
+            created_at_time,
+        })
+    }
+
+    async fn fee(&self) -> Result<u64, String> {
+        Ok(LEDGER.with(|ledger| ledger.borrow().fee))
+    }
+}
+
+// Test helpers
+
+fn admin() -> Principal {
+    Principal::from_slice(&[1])
+}
+
+fn user(n: u8) -> Principal {
+    Principal::from_slice(&[2, n])
+}
+
+fn set_caller(principal: Principal) {
+    CALLER.with(|caller| *caller.borrow_mut() = principal);
+}
+
+fn set_time(time: u64) {
+    NOW.with(|now| *now.borrow_mut() = time);
+}
+
+fn advance(nanos: u64) {
+    set_time(current_time() + nanos);
+}
+
+// Runs a future whose awaits all complete immediately, as the mocks' do
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    match future
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("future did not complete"),
+    }
+}
+
+fn vehicle_payload(registration_number: &str) -> VehiclePayload {
+    VehiclePayload {
+        registration_number: registration_number.to_string(),
+        model: "Transit".to_string(),
+        capacity: 8,
+        location: "Depot".to_string(),
+        department_id: None,
+        acquisition: None,
+        profile: None,
+    }
+}
+
+fn add_vehicle(registration_number: &str) -> Vehicle {
+    create_vehicle(vehicle_payload(registration_number)).unwrap()
+}
+
+// Drivers must hold a license number matching a vehicle's registration
+fn add_driver(license_number: &str) -> Driver {
+    create_driver(DriverPayload {
+        name: "Ada".to_string(),
+        license_number: license_number.to_string(),
+        contact_info: "ada@example.com".to_string(),
+        license_expiry: None,
+    })
+    .unwrap()
+}
+
+// Files every mandatory document for a vehicle, valid for a year from now
+fn add_documents(vehicle_id: u64) {
+    for kind in MANDATORY_DOCUMENTS {
+        add_vehicle_document(VehicleDocumentPayload {
+            vehicle_id,
+            kind,
+            reference_number: format!("{:?}-{}", kind, vehicle_id),
+            issuer: "DMV".to_string(),
+            cost: 100,
+            issued_at: current_time(),
+            expires_at: current_time() + YEAR_SECS * NANOS_PER_SECOND,
+        })
+        .unwrap();
+    }
+}
+
+// Creates a compliant vehicle and a driver for it
+fn add_fleet(registration_number: &str) -> (Vehicle, Driver) {
+    let vehicle = add_vehicle(registration_number);
+    add_documents(vehicle.id);
+    (vehicle, add_driver(registration_number))
+}
+
+fn booking_payload(
+    vehicle_id: u64,
+    driver_id: u64,
+    start_time: u64,
+    end_time: u64,
+) -> BookingPayload {
+    BookingPayload {
+        vehicle_id,
+        driver_id,
+        from_location: "Depot".to_string(),
+        to_location: "Airport".to_string(),
+        start_time,
+        end_time,
+        route_id: None,
+        department_id: None,
+        manifest: None,
+    }
+}
+
+fn book(
+    vehicle_id: u64,
+    driver_id: u64,
+    start_time: u64,
+    end_time: u64,
+) -> Result<Booking, Message> {
+    block_on(create_booking(booking_payload(
+        vehicle_id, driver_id, start_time, end_time,
+    )))
+}
+
+fn start(booking_id: u64, start_odometer: f64) -> Result<Trip, Message> {
+    start_booking(StartTripPayload {
+        booking_id,
+        start_odometer,
+    })
+}
+
+fn complete(booking_id: u64, end_odometer: f64) -> Result<Trip, Message> {
+    block_on(complete_booking(CompleteTripPayload {
+        booking_id,
+        end_odometer,
+        fuel_used: 12.5,
+        route_taken: "Ring road".to_string(),
+    }))
+}
+
+// Trip records
+
+#[test]
+fn completing_a_started_booking_records_the_driven_trip() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let route = create_route(RoutePayload {
+        from_location: "Depot".to_string(),
+        to_location: "Airport".to_string(),
+        from_coordinates: None,
+        to_coordinates: None,
+        vehicle_id: None,
+    })
+    .unwrap();
+    let booking = block_on(create_booking(BookingPayload {
+        route_id: Some(route.id),
+        ..booking_payload(vehicle.id, driver.id, START + HOUR, START + 3 * HOUR)
+    }))
+    .unwrap();
+
+    set_time(START + HOUR + 600 * NANOS_PER_SECOND);
+    let trip = start(booking.id, 1000.0).unwrap();
+    assert_eq!(trip.status, "in_progress");
+    assert_eq!(trip.start_delay, 600 * NANOS_PER_SECOND as i64);
+    assert_eq!(get_booking_by_id(booking.id).unwrap().status, "in_progress");
+
+    set_time(START + 2 * HOUR);
+    let trip = complete(booking.id, 1120.0).unwrap();
+    assert_eq!(trip.status, "completed");
+    assert_eq!(trip.distance, 120.0);
+    assert_eq!(trip.distance_deviation, Some(120.0 - route.distance));
+    assert_eq!(trip.end_delay, Some(-(HOUR as i64)));
+    assert_eq!(get_booking_by_id(booking.id).unwrap().status, "completed");
+    assert_eq!(
+        get_vehicle_by_id(vehicle.id).unwrap().status,
+        VehicleStatus::Available
+    );
+    assert_eq!(get_trips_by_vehicle(vehicle.id).unwrap().len(), 1);
+    assert_eq!(get_trips_by_driver(driver.id).unwrap()[0].id, trip.id);
+}
+
+#[test]
+fn only_pending_bookings_start_and_only_started_ones_complete() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+
+    assert!(matches!(
+        complete(booking.id, 10.0),
+        Err(Message::InvalidPayload(_))
+    ));
+    start(booking.id, 100.0).unwrap();
+    assert!(matches!(
+        start(booking.id, 100.0),
+        Err(Message::InvalidPayload(_))
+    ));
+    assert!(matches!(
+        complete(booking.id, 99.0),
+        Err(Message::InvalidPayload(_))
+    ));
+    assert!(matches!(start(0, 0.0), Err(Message::NotFound(_))));
+}
+
+#[test]
+fn odometer_and_fuel_readings_must_be_finite() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+
+    for reading in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+        assert!(matches!(
+            start(booking.id, reading),
+            Err(Message::InvalidPayload(_))
+        ));
+    }
+    start(booking.id, 100.0).unwrap();
+    for reading in [f64::NAN, f64::INFINITY] {
+        assert!(matches!(
+            complete(booking.id, reading),
+            Err(Message::InvalidPayload(_))
+        ));
+        assert!(matches!(
+            block_on(complete_booking(CompleteTripPayload {
+                booking_id: booking.id,
+                end_odometer: 150.0,
+                fuel_used: reading,
+                route_taken: "Ring road".to_string(),
+            })),
+            Err(Message::InvalidPayload(_))
+        ));
+    }
+    assert_eq!(complete(booking.id, 150.0).unwrap().distance, 50.0);
+}
+
+// Roles and audit log
+
+#[test]
+fn only_admins_assign_roles_and_roles_are_looked_up_by_principal() {
+    assert_eq!(get_my_role(), UserRole::Admin);
+    set_caller(user(1));
+    assert_eq!(get_my_role(), UserRole::User);
+    assert!(matches!(
+        assign_role(RoleAssignmentPayload {
+            principal: user(1),
+            role: UserRole::Admin,
+            driver_id: None,
+        }),
+        Err(Message::Unauthorized(_))
+    ));
+
+    set_caller(admin());
+    let account = assign_role(RoleAssignmentPayload {
+        principal: user(1),
+        role: UserRole::Manager,
+        driver_id: None,
+    })
+    .unwrap();
+    let updated = assign_role(RoleAssignmentPayload {
+        principal: user(1),
+        role: UserRole::Driver,
+        driver_id: None,
+    })
+    .unwrap();
+    assert_eq!(updated.id, account.id);
+
+    set_caller(user(1));
+    assert_eq!(get_my_role(), UserRole::Driver);
+}
+
+fn audit_page(entity_type: Option<&str>, before_seq: Option<u64>, limit: u64) -> AuditLogPage {
+    get_audit_log(AuditLogFilter {
+        entity_type: entity_type.map(str::to_string),
+        entity_id: None,
+        caller: None,
+        from: None,
+        to: None,
+        before_seq,
+        limit,
+    })
+    .unwrap()
+}
+
+#[test]
+fn audit_log_pages_backwards_from_the_cursor() {
+    for n in 0..3 {
+        add_vehicle(&format!("AB-{}", n));
+    }
+    add_driver("AB-0");
+
+    let page = audit_page(None, None, 2);
+    assert_eq!(page.entries.len(), 2);
+    assert_eq!(page.entries[0].entity_type, "driver");
+    assert_eq!(page.entries[0].seq, 3);
+    assert_eq!(page.next_seq, Some(2));
+
+    let page = audit_page(None, page.next_seq, 2);
+    assert_eq!(
+        page.entries
+            .iter()
+            .map(|entry| entry.seq)
+            .collect::<Vec<_>>(),
+        vec![1, 0]
+    );
+    assert_eq!(page.next_seq, None);
+
+    let vehicles = audit_page(Some("vehicle"), None, 10);
+    assert_eq!(vehicles.entries.len(), 3);
+    assert!(vehicles.entries.iter().all(|entry| entry.after.is_some()));
+
+    set_caller(user(1));
+    assert!(matches!(
+        get_audit_log(AuditLogFilter {
+            entity_type: None,
+            entity_id: None,
+            caller: None,
+            from: None,
+            to: None,
+            before_seq: None,
+            limit: 10,
+        }),
+        Err(Message::Unauthorized(_))
+    ));
+}
+
+// Change feed
+
+#[test]
+fn change_feed_sequences_domain_events_and_resumes_from_next_seq() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    start(booking.id, 0.0).unwrap();
+
+    let page = get_events_since(0, 1).unwrap();
+    assert_eq!(page.events.len(), 1);
+    assert!(matches!(
+        &page.events[0].kind,
+        FleetEventKind::VehicleCreated(created) if created.id == vehicle.id
+    ));
+    assert_eq!(page.next_seq, 1);
+
+    let page = get_events_since(page.next_seq, 10).unwrap();
+    let statuses: Vec<(Option<String>, String)> = page
+        .events
+        .iter()
+        .map(|event| match &event.kind {
+            FleetEventKind::BookingStatusChanged {
+                booking,
+                previous_status,
+            } => (previous_status.clone(), booking.status.clone()),
+            _ => panic!("unexpected event"),
+        })
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            (None, "pending".to_string()),
+            (Some("pending".to_string()), "in_progress".to_string()),
+        ]
+    );
+    assert_eq!(page.events[1].seq, 2);
+    assert_eq!(page.next_seq, 3);
+
+    let page = get_events_since(page.next_seq, 10).unwrap();
+    assert!(page.events.is_empty());
+    assert_eq!(page.next_seq, 3);
+}
+
+// Notifications
+
+// Webhook transport answering with scripted outcomes and recording what was sent
+struct MockTransport {
+    outcomes: RefCell<Vec<Result<u16, String>>>,
+    sent: RefCell<Vec<(String, serde_json::Value)>>,
+}
+
+impl MockTransport {
+    fn new(outcomes: Vec<Result<u16, String>>) -> Self {
+        Self {
+            outcomes: RefCell::new(outcomes),
+            sent: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl WebhookTransport for MockTransport {
+    async fn post(&self, url: &str, body: Vec<u8>) -> Result<u16, String> {
+        self.sent
+            .borrow_mut()
+            .push((url.to_string(), serde_json::from_slice(&body).unwrap()));
+        self.outcomes.borrow_mut().remove(0)
+    }
+}
+
+fn configure_webhook(max_attempts: u32) {
+    set_notification_config(NotificationConfig {
+        webhook_url: "https://hooks.example.com/fleet".to_string(),
+        max_attempts,
+        base_backoff_secs: 60,
+    })
+    .unwrap();
+    subscribe_notifications(SubscriptionPayload {
+        target: SubscriptionTarget::Role(UserRole::Manager),
+        topics: vec![NotificationTopic::EmergencyRaised],
+    })
+    .unwrap();
+}
+
+fn raise_emergency(vehicle_id: u64) {
+    request_emergency_assistance(EmergencyAssistancePayload {
+        vehicle_id,
+        description: "Flat tyre".to_string(),
+        location: "Ring road".to_string(),
+    })
+    .unwrap();
+}
+
+fn only_notification() -> Notification {
+    let notifications = get_notifications(None).unwrap();
+    assert_eq!(notifications.len(), 1);
+    notifications[0].clone()
+}
+
+#[test]
+fn events_only_queue_notifications_for_matching_subscriptions() {
+    configure_webhook(5);
+    let vehicle = add_vehicle("AB-123");
+    raise_emergency(vehicle.id);
+
+    let notification = only_notification();
+    assert_eq!(notification.topic, NotificationTopic::EmergencyRaised);
+    assert_eq!(notification.status, DeliveryStatus::Pending);
+    assert_eq!(notification.attempts, 0);
+    assert_eq!(notification.next_attempt_at, current_time());
+}
+
+#[test]
+fn failed_deliveries_back_off_exponentially_until_delivered() {
+    configure_webhook(5);
+    let vehicle = add_vehicle("AB-123");
+    raise_emergency(vehicle.id);
+
+    let transport = MockTransport::new(vec![Ok(500), Err("connection reset".to_string()), Ok(204)]);
+    assert_eq!(block_on(deliver_due_notifications(&transport)), 0);
+    let notification = only_notification();
+    assert_eq!(notification.status, DeliveryStatus::Pending);
+    assert_eq!(notification.attempts, 1);
+    assert_eq!(
+        notification.last_error.as_deref(),
+        Some("Webhook responded with status 500")
+    );
+    assert_eq!(
+        notification.next_attempt_at,
+        current_time() + 60 * NANOS_PER_SECOND
+    );
+
+    // Nothing is due until the backoff has passed
+    assert_eq!(block_on(deliver_due_notifications(&transport)), 0);
+    assert_eq!(transport.sent.borrow().len(), 1);
+
+    set_time(notification.next_attempt_at);
+    block_on(deliver_due_notifications(&transport));
+    let notification = only_notification();
+    assert_eq!(notification.attempts, 2);
+    assert_eq!(notification.last_error.as_deref(), Some("connection reset"));
+    assert_eq!(
+        notification.next_attempt_at,
+        current_time() + 120 * NANOS_PER_SECOND
+    );
+
+    set_time(notification.next_attempt_at);
+    assert_eq!(block_on(deliver_due_notifications(&transport)), 1);
+    let notification = only_notification();
+    assert_eq!(notification.status, DeliveryStatus::Delivered);
+    assert_eq!(notification.attempts, 3);
+    assert_eq!(notification.delivered_at, Some(current_time()));
+    assert_eq!(notification.last_error, None);
+
+    let sent = transport.sent.borrow();
+    assert_eq!(sent.len(), 3);
+    assert_eq!(sent[0].0, "https://hooks.example.com/fleet");
+    assert_eq!(sent[0].1["id"], notification.id);
+    assert_eq!(sent[0].1["topic"], "EmergencyRaised");
+}
+
+#[test]
+fn deliveries_fail_after_max_attempts() {
+    configure_webhook(2);
+    let vehicle = add_vehicle("AB-123");
+    raise_emergency(vehicle.id);
+
+    let transport = MockTransport::new(vec![Ok(503), Ok(503)]);
+    block_on(deliver_due_notifications(&transport));
+    set_time(only_notification().next_attempt_at);
+    block_on(deliver_due_notifications(&transport));
+
+    let notification = only_notification();
+    assert_eq!(notification.status, DeliveryStatus::Failed);
+    assert_eq!(notification.attempts, 2);
+    advance(DAY);
+    assert_eq!(block_on(deliver_due_notifications(&transport)), 0);
+    assert_eq!(transport.sent.borrow().len(), 2);
+}
+
+#[test]
+fn notifications_stay_queued_without_a_webhook_url() {
+    configure_webhook(5);
+    set_notification_config(NotificationConfig::default()).unwrap();
+    let vehicle = add_vehicle("AB-123");
+    raise_emergency(vehicle.id);
+
+    let transport = MockTransport::new(vec![]);
+    assert_eq!(block_on(deliver_due_notifications(&transport)), 0);
+    assert!(transport.sent.borrow().is_empty());
+    assert_eq!(only_notification().status, DeliveryStatus::Pending);
+}
+
+// Background jobs
+
+fn job_status(name: &str) -> JobStatus {
+    get_job_statuses()
+        .unwrap()
+        .into_iter()
+        .find(|status| status.name == name)
+        .unwrap()
+}
+
+#[test]
+fn job_runs_are_recorded_under_the_job_name() {
+    assert_eq!(job_status("check_maintenance").runs, 0);
+    let index = BACKGROUND_JOBS
+        .iter()
+        .position(|(name, _, _)| *name == "check_maintenance")
+        .unwrap();
+
+    run_background_job(index);
+    advance(HOUR);
+    run_background_job(index);
+
+    let status = job_status("check_maintenance");
+    assert_eq!(status.runs, 2);
+    assert_eq!(status.last_run, Some(current_time()));
+    assert_eq!(
+        status.last_outcome.as_deref(),
+        Some("0 maintenance record(s) overdue, 0 reminder(s) sent")
+    );
+    assert_eq!(job_status("compact_telemetry").runs, 0);
+    assert!(JOB_STATUS_STORAGE.with(|storage| storage
+        .borrow()
+        .contains_key(&JobName("check_maintenance".to_string()))));
+
+    set_caller(user(1));
+    assert!(matches!(get_job_statuses(), Err(Message::Unauthorized(_))));
+}
+
+#[test]
+fn stale_bookings_are_marked_as_no_shows_and_release_their_vehicle() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+
+    let outcome = mark_no_show_bookings(START + HOUR + 10 * 60 * NANOS_PER_SECOND, None);
+    assert_eq!(outcome, "0 booking(s) marked as no-show");
+
+    let outcome = mark_no_show_bookings(START + 2 * HOUR, None);
+    assert_eq!(outcome, "1 booking(s) marked as no-show");
+    assert_eq!(get_booking_by_id(booking.id).unwrap().status, "no_show");
+    assert_eq!(
+        get_vehicle_by_id(vehicle.id).unwrap().status,
+        VehicleStatus::Available
+    );
+}
+
+#[test]
+fn maintenance_past_its_date_is_flagged_overdue() {
+    let vehicle = add_vehicle("AB-123");
+    let maintenance = schedule_maintenance(MaintenancePayload {
+        vehicle_id: vehicle.id,
+        description: "Brake pads".to_string(),
+        scheduled_date: START + DAY,
+        cost: None,
+    })
+    .unwrap();
+
+    check_maintenance(START + HOUR, None);
+    assert_eq!(
+        get_maintenance_by_id(maintenance.id).unwrap().status,
+        "pending"
+    );
+    check_maintenance(START + 2 * DAY, None);
+    assert_eq!(
+        get_maintenance_by_id(maintenance.id).unwrap().status,
+        "overdue"
+    );
+}
+
+#[test]
+fn telemetry_compaction_drops_old_route_traces_but_keeps_trip_figures() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    start(booking.id, 0.0).unwrap();
+    let trip = complete(booking.id, 80.0).unwrap();
+
+    let retention = TRIP_TRACE_RETENTION_SECS * NANOS_PER_SECOND;
+    assert_eq!(
+        compact_telemetry(current_time() + retention, None),
+        "0 trip record(s) compacted"
+    );
+    assert_eq!(
+        compact_telemetry(current_time() + retention + 1, None),
+        "1 trip record(s) compacted"
+    );
+    let compacted = get_trip_by_id(trip.id).unwrap();
+    assert!(compacted.route_taken.is_empty());
+    assert_eq!(compacted.distance, 80.0);
+    assert_eq!(compacted.fuel_used, trip.fuel_used);
+    assert_eq!(compacted.end_time, trip.end_time);
+}
+
+// Vehicle documents
+
+fn add_document(vehicle_id: u64, kind: DocumentKind, issued_at: u64, expires_at: u64) {
+    add_vehicle_document(VehicleDocumentPayload {
+        vehicle_id,
+        kind,
+        reference_number: "REF-1".to_string(),
+        issuer: "DMV".to_string(),
+        cost: 100,
+        issued_at,
+        expires_at,
+    })
+    .unwrap();
+}
+
+#[test]
+fn missing_documents_warn_without_blocking_the_booking() {
+    let vehicle = add_vehicle("AB-123");
+    let driver = add_driver("AB-123");
+
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    let warnings = booking.warnings.unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("Registration"));
+
+    let reports = get_non_compliant_vehicles(None).unwrap();
+    assert_eq!(reports[0].missing.len(), MANDATORY_DOCUMENTS.len());
+}
+
+#[test]
+fn expired_documents_block_the_booking() {
+    let vehicle = add_vehicle("AB-123");
+    let driver = add_driver("AB-123");
+    add_documents(vehicle.id);
+    add_document(
+        vehicle.id,
+        DocumentKind::Insurance,
+        START - 2 * YEAR_SECS * NANOS_PER_SECOND,
+        START - YEAR_SECS * NANOS_PER_SECOND,
+    );
+    // Two years on, the older policy and every current document have run out
+    let after_expiry = START + 2 * YEAR_SECS * NANOS_PER_SECOND;
+    assert!(matches!(
+        book(vehicle.id, driver.id, after_expiry, after_expiry + HOUR),
+        Err(Message::InvalidPayload(reason)) if reason.contains("expired [Registration, Insurance, Inspection, EmissionCertificate]")
+    ));
+    assert!(book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR)
+        .unwrap()
+        .warnings
+        .is_none());
+}
+
+#[test]
+fn documents_issued_after_the_trip_start_are_not_yet_valid() {
+    let vehicle = add_vehicle("AB-123");
+    let driver = add_driver("AB-123");
+    add_documents(vehicle.id);
+    let while_valid = START + DAY;
+    let compliance = check_vehicle_compliance(&vehicle, START - HOUR);
+    assert_eq!(compliance.not_yet_valid.len(), MANDATORY_DOCUMENTS.len());
+    assert!(compliance.expired.is_empty());
+
+    assert!(matches!(
+        book(vehicle.id, driver.id, START - HOUR, START),
+        Err(Message::InvalidPayload(reason)) if reason.contains("not yet valid")
+    ));
+    let reports = get_non_compliant_vehicles(Some(START - HOUR)).unwrap();
+    assert_eq!(reports[0].not_yet_valid.len(), MANDATORY_DOCUMENTS.len());
+    assert!(matches!(
+        get_non_compliant_vehicles(Some(while_valid)),
+        Err(Message::NotFound(_))
+    ));
+}
+
+#[test]
+fn longest_accepted_documents_fit_their_store() {
+    let vehicle = add_vehicle("AB-123");
+    let payload = |reference_number: String, issuer: String| VehicleDocumentPayload {
+        vehicle_id: vehicle.id,
+        kind: DocumentKind::EmissionCertificate,
+        reference_number,
+        issuer,
+        cost: u64::MAX,
+        issued_at: u64::MAX - 1,
+        expires_at: u64::MAX,
+    };
+    for (reference_number, issuer) in [
+        ("R".repeat(MAX_NAME_LENGTH + 1), "DMV".to_string()),
+        ("REF-1".to_string(), "I".repeat(MAX_NAME_LENGTH + 1)),
+    ] {
+        assert!(matches!(
+            add_vehicle_document(payload(reference_number, issuer)),
+            Err(Message::InvalidPayload(_))
+        ));
+    }
+
+    let document = add_vehicle_document(payload(
+        "R".repeat(MAX_NAME_LENGTH),
+        "I".repeat(MAX_NAME_LENGTH),
+    ))
+    .unwrap();
+    let document = VehicleDocument {
+        id: u64::MAX,
+        vehicle_id: u64::MAX,
+        created_at: u64::MAX,
+        ..document
+    };
+    assert!(document.to_bytes().len() <= VehicleDocument::MAX_SIZE as usize);
+}
+
+// Hours of service
+
+fn set_rules(max_daily_driving_secs: u64, min_rest_secs: u64) {
+    set_hours_of_service_rules(HoursOfServiceRules {
+        max_daily_driving_secs,
+        max_weekly_driving_secs: 56 * 60 * 60,
+        min_rest_secs,
+        enforcement: RuleEnforcement::Reject,
+    })
+    .unwrap();
+}
+
+#[test]
+fn daily_limit_covers_windows_starting_before_the_new_booking() {
+    set_rules(9 * 60 * 60, 0);
+    let (vehicle, driver) = add_fleet("AB-123");
+    let other = add_vehicle("AB-456");
+    add_documents(other.id);
+    book(vehicle.id, driver.id, START + 10 * HOUR, START + 18 * HOUR).unwrap();
+
+    // The later booking falls outside the 24 hours ending with the new one,
+    // but both fit in the 24 hours starting with it
+    assert!(matches!(
+        book(other.id, driver.id, START, START + 2 * HOUR),
+        Err(Message::InvalidPayload(reason)) if reason.contains("within 24 hours")
+    ));
+    book(other.id, driver.id, START, START + HOUR).unwrap();
+}
+
+#[test]
+fn rest_periods_are_enforced_between_bookings() {
+    set_rules(9 * 60 * 60, 11 * 60 * 60);
+    let (vehicle, driver) = add_fleet("AB-123");
+    let other = add_vehicle("AB-456");
+    add_documents(other.id);
+    book(vehicle.id, driver.id, START, START + 2 * HOUR).unwrap();
+
+    assert!(matches!(
+        book(other.id, driver.id, START + 12 * HOUR, START + 13 * HOUR),
+        Err(Message::InvalidPayload(reason)) if reason.contains("rest")
+    ));
+    assert!(matches!(
+        book(other.id, driver.id, START + HOUR, START + 3 * HOUR),
+        Err(Message::InvalidPayload(reason)) if reason.contains("already booked")
+    ));
+    book(other.id, driver.id, START + 13 * HOUR, START + 14 * HOUR).unwrap();
+}
+
+#[test]
+fn rules_that_overflow_in_nanoseconds_are_rejected() {
+    assert!(matches!(
+        set_hours_of_service_rules(HoursOfServiceRules {
+            min_rest_secs: u64::MAX,
+            ..HoursOfServiceRules::default()
+        }),
+        Err(Message::InvalidPayload(_))
+    ));
+    assert_eq!(
+        get_hours_of_service_rules().min_rest_secs,
+        HoursOfServiceRules::default().min_rest_secs
+    );
+}
+
+#[test]
+fn driver_hours_use_actual_trip_times_within_a_bounded_period() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    set_time(START + HOUR);
+    start(booking.id, 0.0).unwrap();
+    set_time(START + 4 * HOUR);
+    complete(booking.id, 10.0).unwrap();
+    assert_eq!(find_trip(booking.id).unwrap().booking_id, booking.id);
+
+    let report = get_driver_hours(
+        driver.id,
+        ReportPeriod {
+            start: START,
+            end: START + DAY,
+        },
+    )
+    .unwrap();
+    assert_eq!(report.driving_secs, 3 * 60 * 60);
+    assert_eq!(report.booking_count, 1);
+
+    assert!(matches!(
+        get_driver_hours(
+            driver.id,
+            ReportPeriod {
+                start: START,
+                end: START + 367 * DAY,
+            },
+        ),
+        Err(Message::InvalidPayload(_))
+    ));
+}
+
+#[test]
+fn driver_hours_cover_periods_ending_with_the_timestamp_range() {
+    let (_, driver) = add_fleet("AB-123");
+    let report = get_driver_hours(
+        driver.id,
+        ReportPeriod {
+            start: u64::MAX - 2 * DAY,
+            end: u64::MAX,
+        },
+    )
+    .unwrap();
+    assert_eq!(report.daily.len(), 3);
+    assert_eq!(report.driving_secs, 0);
+}
+
+// Driver calendar
+
+fn link_driver(principal: Principal, driver_id: u64) -> Result<UserAccount, Message> {
+    assign_role(RoleAssignmentPayload {
+        principal,
+        role: UserRole::Driver,
+        driver_id: Some(driver_id),
+    })
+}
+
+fn leave_payload(driver_id: u64) -> LeaveRequestPayload {
+    LeaveRequestPayload {
+        driver_id,
+        kind: LeaveKind::Leave,
+        start_time: START + DAY,
+        end_time: START + 2 * DAY,
+        reason: "Annual leave".to_string(),
+    }
+}
+
+#[test]
+fn drivers_only_request_and_see_their_own_leave() {
+    let (_, driver) = add_fleet("AB-123");
+    let (_, colleague) = add_fleet("AB-456");
+    link_driver(user(1), driver.id).unwrap();
+    assert!(matches!(
+        link_driver(user(2), driver.id),
+        Err(Message::InvalidPayload(_))
+    ));
+    request_leave(leave_payload(colleague.id)).unwrap();
+
+    set_caller(user(3));
+    assert!(matches!(
+        request_leave(leave_payload(driver.id)),
+        Err(Message::Unauthorized(_))
+    ));
+    assert!(matches!(
+        get_leave_requests(None, None),
+        Err(Message::Unauthorized(_))
+    ));
+
+    set_caller(user(1));
+    assert!(matches!(
+        request_leave(leave_payload(colleague.id)),
+        Err(Message::Unauthorized(_))
+    ));
+    let request = request_leave(leave_payload(driver.id)).unwrap();
+    assert_eq!(request.requested_by, user(1));
+    let visible = get_leave_requests(None, None).unwrap();
+    assert_eq!(visible.len(), 1);
+    assert_eq!(visible[0].driver_id, driver.id);
+    assert!(matches!(
+        get_leave_requests(Some(colleague.id), None),
+        Err(Message::Unauthorized(_))
+    ));
+
+    set_caller(admin());
+    assert_eq!(get_leave_requests(None, None).unwrap().len(), 2);
+}
+
+#[test]
+fn approved_leave_blocks_bookings() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let request = request_leave(leave_payload(driver.id)).unwrap();
+    let during_leave = request.start_time + HOUR;
+    book(vehicle.id, driver.id, during_leave, during_leave + HOUR).unwrap();
+
+    let (other, colleague) = add_fleet("AB-456");
+    let request = request_leave(leave_payload(colleague.id)).unwrap();
+    review_leave_request(request.id, true).unwrap();
+    assert!(matches!(
+        book(other.id, colleague.id, during_leave, during_leave + HOUR),
+        Err(Message::InvalidPayload(reason)) if reason.contains("on leave")
+    ));
+}
+
+#[test]
+fn longest_accepted_leave_requests_fit_their_store() {
+    let (_, driver) = add_fleet("AB-123");
+    assert!(matches!(
+        request_leave(LeaveRequestPayload {
+            reason: "R".repeat(MAX_TEXT_LENGTH + 1),
+            ..leave_payload(driver.id)
+        }),
+        Err(Message::InvalidPayload(_))
+    ));
+
+    let request = request_leave(LeaveRequestPayload {
+        kind: LeaveKind::DayOff,
+        reason: "R".repeat(MAX_TEXT_LENGTH),
+        ..leave_payload(driver.id)
+    })
+    .unwrap();
+    let principal = Principal::from_slice(&[u8::MAX; 29]);
+    let request = LeaveRequest {
+        id: u64::MAX,
+        driver_id: u64::MAX,
+        start_time: u64::MAX,
+        end_time: u64::MAX,
+        status: LeaveStatus::Rejected,
+        requested_by: principal,
+        reviewed_by: Some(principal),
+        reviewed_at: Some(u64::MAX),
+        created_at: u64::MAX,
+        ..request
+    };
+    assert!(request.to_bytes().len() <= LeaveRequest::MAX_SIZE as usize);
+}
+
+#[test]
+fn back_to_back_shifts_cover_bookings_spanning_them() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    for (start_time, end_time) in [
+        (START + 8 * HOUR, START + 12 * HOUR),
+        (START, START + 8 * HOUR),
+        (START + 20 * HOUR, START + 24 * HOUR),
+    ] {
+        create_driver_shift(DriverShiftPayload {
+            driver_id: driver.id,
+            start_time,
+            end_time,
+        })
+        .unwrap();
+    }
+
+    assert!(matches!(
+        book(vehicle.id, driver.id, START + 11 * HOUR, START + 21 * HOUR),
+        Err(Message::InvalidPayload(reason)) if reason.contains("no shift")
+    ));
+    book(vehicle.id, driver.id, START + 6 * HOUR, START + 10 * HOUR).unwrap();
+}
+
+// Automatic assignment
+
+fn trip_request(passenger_count: u64, cargo_count: u64) -> TripRequestPayload {
+    TripRequestPayload {
+        from_location: "Depot".to_string(),
+        to_location: "Airport".to_string(),
+        start_time: START + HOUR,
+        end_time: START + 2 * HOUR,
+        passenger_count,
+        cargo_count,
+        requirements: None,
+    }
+}
+
+fn set_points(driver_id: u64, points: u64) {
+    DRIVER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let driver = storage.get(&driver_id).unwrap();
+        storage.insert(driver_id, Driver { points, ..driver });
+    });
+}
+
+#[test]
+fn trips_go_to_the_nearest_vehicle_and_the_driver_with_fewest_penalty_points() {
+    let far = create_vehicle(VehiclePayload {
+        location: "Harbour".to_string(),
+        ..vehicle_payload("AB-123")
+    })
+    .unwrap();
+    add_documents(far.id);
+    let (near, careful) = add_fleet("AB-456");
+    let reckless = add_driver("AB-123");
+    set_points(careful.id, 2);
+    set_points(reckless.id, 9);
+
+    let proposal = request_trip(trip_request(2, 1)).unwrap();
+    assert_eq!(proposal.vehicle_id, near.id);
+    assert_eq!(proposal.driver_id, careful.id);
+
+    let booking = block_on(confirm_trip_proposal(proposal.id)).unwrap();
+    assert_eq!(booking.vehicle_id, near.id);
+    assert!(matches!(
+        block_on(confirm_trip_proposal(proposal.id)),
+        Err(Message::InvalidPayload(_))
+    ));
+}
+
+#[test]
+fn trip_requests_reject_empty_or_overflowing_loads() {
+    add_fleet("AB-123");
+    assert!(matches!(
+        request_trip(trip_request(0, 0)),
+        Err(Message::InvalidPayload(_))
+    ));
+    assert!(matches!(
+        request_trip(trip_request(u64::MAX, 1)),
+        Err(Message::InvalidPayload(_))
+    ));
+    assert!(matches!(
+        request_trip(trip_request(9, 0)),
+        Err(Message::NotFound(_))
+    ));
+}
+
+#[test]
+fn only_the_requester_confirms_a_proposal_before_it_expires() {
+    add_fleet("AB-123");
+    let proposal = request_trip(trip_request(1, 0)).unwrap();
+
+    set_caller(user(1));
+    assert!(matches!(
+        block_on(confirm_trip_proposal(proposal.id)),
+        Err(Message::Unauthorized(_))
+    ));
+    set_caller(admin());
+    advance((TRIP_PROPOSAL_TTL_SECS + 1) * NANOS_PER_SECOND);
+    assert!(matches!(
+        block_on(confirm_trip_proposal(proposal.id)),
+        Err(Message::InvalidPayload(reason)) if reason.contains("expired")
+    ));
+}
+
+fn van_profile(equipment: Vec<Equipment>) -> VehicleProfile {
+    VehicleProfile {
+        category: VehicleCategory::Van,
+        energy_type: EnergyType::Diesel,
+        passenger_seats: 2,
+        cargo_capacity_kg: 500,
+        cargo_volume_m3: 6.0,
+        license_class: LicenseClass::B,
+        equipment,
+        rated_range_km: None,
+        connector: None,
+    }
+}
+
+#[test]
+fn trip_requests_check_passengers_against_seats_and_cargo_against_kilograms() {
+    let (vehicle, _) = add_fleet("AB-123");
+    set_vehicle_profile(vehicle.id, van_profile(Vec::new())).unwrap();
+    for (passengers, cargo_kg) in [(3, 0), (1, 501)] {
+        assert!(matches!(
+            request_trip(trip_request(passengers, cargo_kg)),
+            Err(Message::NotFound(_))
+        ));
+    }
+    // Far more than the vehicle's overall capacity of 8, but within its cargo capacity
+    let proposal = request_trip(trip_request(2, 500)).unwrap();
+    assert_eq!(proposal.vehicle_id, vehicle.id);
+}
+
+#[test]
+fn vehicle_profiles_keep_one_of_each_kind_of_equipment() {
+    let vehicle = add_vehicle("AB-123");
+    let profile = van_profile(vec![
+        Equipment::TowBar,
+        Equipment::TowBar,
+        Equipment::TailLift,
+        Equipment::TowBar,
+    ]);
+    let updated = set_vehicle_profile(vehicle.id, profile.clone()).unwrap();
+    assert_eq!(
+        updated.profile.unwrap().equipment,
+        vec![Equipment::TowBar, Equipment::TailLift]
+    );
+    let mut payload = vehicle_payload("AB-456");
+    payload.profile = Some(profile);
+    let created = create_vehicle(payload).unwrap();
+    assert_eq!(created.profile.unwrap().equipment.len(), 2);
+}
+
+// Requesters
+
+#[test]
+fn users_only_see_and_cancel_their_own_bookings() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let (other_vehicle, other_driver) = add_fleet("AB-456");
+    set_caller(user(1));
+    let mine = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    assert_eq!(mine.requester, Some(user(1)));
+    set_caller(user(2));
+    let theirs = book(
+        other_vehicle.id,
+        other_driver.id,
+        START + HOUR,
+        START + 2 * HOUR,
+    )
+    .unwrap();
+
+    set_caller(user(1));
+    let bookings = get_my_bookings().unwrap();
+    assert_eq!(bookings.len(), 1);
+    assert_eq!(bookings[0].id, mine.id);
+    assert!(matches!(
+        block_on(cancel_booking(theirs.id)),
+        Err(Message::Unauthorized(_))
+    ));
+    assert_eq!(
+        block_on(cancel_booking(mine.id)).unwrap().status,
+        "cancelled"
+    );
+}
+
+#[test]
+fn anonymous_callers_cannot_book_or_read_the_change_feed() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    set_caller(Principal::anonymous());
+    assert!(matches!(
+        book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR),
+        Err(Message::Unauthorized(_))
+    ));
+    assert!(matches!(
+        get_events_since(0, 10),
+        Err(Message::Unauthorized(_))
+    ));
+    set_caller(user(1));
+    assert!(matches!(
+        get_events_since(0, 10),
+        Err(Message::Unauthorized(_))
+    ));
+}
+
+#[test]
+fn longest_accepted_bookings_and_customers_fit_their_stores() {
+    // Without documents the booking carries a warning
+    let vehicle = add_vehicle("AB-123");
+    let driver = add_driver("AB-123");
+    let location = "L".repeat(MAX_NAME_LENGTH);
+    assert!(matches!(
+        block_on(create_booking(BookingPayload {
+            from_location: "L".repeat(MAX_NAME_LENGTH + 1),
+            ..booking_payload(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR)
+        })),
+        Err(Message::InvalidPayload(_))
+    ));
+    let booking = block_on(create_booking(BookingPayload {
+        from_location: location.clone(),
+        to_location: location,
+        ..booking_payload(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR)
+    }))
+    .unwrap();
+    assert!(booking.warnings.is_some());
+    let booking = Booking {
+        id: u64::MAX,
+        vehicle_id: u64::MAX,
+        requester: Some(Principal::from_slice(&[u8::MAX; 29])),
+        driver_id: u64::MAX,
+        start_time: u64::MAX,
+        end_time: u64::MAX,
+        route_id: Some(u64::MAX),
+        department_id: Some(u64::MAX),
+        status: "in_progress".to_string(),
+        created_at: u64::MAX,
+        ..booking
+    };
+    assert!(booking.to_bytes().len() <= Booking::MAX_SIZE as usize);
+
+    set_caller(Principal::from_slice(&[u8::MAX; 29]));
+    assert!(matches!(
+        register_customer(CustomerPayload {
+            name: "N".repeat(MAX_NAME_LENGTH),
+            contact_info: "C".repeat(MAX_TEXT_LENGTH + 1),
+        }),
+        Err(Message::InvalidPayload(_))
+    ));
+    let customer = register_customer(CustomerPayload {
+        name: "N".repeat(MAX_NAME_LENGTH),
+        contact_info: "C".repeat(MAX_TEXT_LENGTH),
+    })
+    .unwrap();
+    let customer = Customer {
+        id: u64::MAX,
+        created_at: u64::MAX,
+        ..customer
+    };
+    assert!(customer.to_bytes().len() <= Customer::MAX_SIZE as usize);
+}
+
+// Pricing
+
+fn tariff_payload(per_hour: u64, surcharges: Vec<Surcharge>) -> TariffPayload {
+    TariffPayload {
+        vehicle_model: "Transit".to_string(),
+        base_fee: 500,
+        per_km: 2,
+        per_hour,
+        surcharges,
+    }
+}
+
+fn surcharge(name: &str, kind: SurchargeKind) -> Surcharge {
+    Surcharge {
+        name: name.to_string(),
+        kind,
+    }
+}
+
+fn quote(vehicle_id: u64, end_time: u64) -> Result<Quote, Message> {
+    let route = create_route(RoutePayload {
+        from_location: "Depot".to_string(),
+        to_location: "Airport".to_string(),
+        from_coordinates: None,
+        to_coordinates: None,
+        vehicle_id: None,
+    })
+    .unwrap();
+    quote_booking(QuotePayload {
+        vehicle_id,
+        route_id: route.id,
+        start_time: START,
+        end_time,
+    })
+}
+
+#[test]
+fn quotes_add_up_base_distance_time_and_surcharges() {
+    let vehicle = add_vehicle("AB-123");
+    set_tariff(tariff_payload(
+        100,
+        vec![
+            surcharge("Cleaning", SurchargeKind::Flat(50)),
+            surcharge("Weekend", SurchargeKind::Percent(10)),
+        ],
+    ))
+    .unwrap();
+
+    // 100 km and two started hours: 500 + 200 + 200, then 50 and 10% of 900
+    let quote = quote(vehicle.id, START + HOUR + 1).unwrap();
+    assert_eq!(quote.billed_hours, 2);
+    assert_eq!(quote.total, 900 + 50 + 90);
+}
+
+#[test]
+fn quotes_that_overflow_are_rejected() {
+    let vehicle = add_vehicle("AB-123");
+    set_tariff(tariff_payload(u64::MAX, vec![])).unwrap();
+    assert!(matches!(
+        quote(vehicle.id, START + 2 * HOUR),
+        Err(Message::InvalidPayload(_))
+    ));
+
+    set_tariff(tariff_payload(
+        u64::MAX / 2,
+        vec![surcharge("Peak", SurchargeKind::Percent(300))],
+    ))
+    .unwrap();
+    assert!(matches!(
+        quote(vehicle.id, START + HOUR),
+        Err(Message::InvalidPayload(_))
+    ));
+}
+
+#[test]
+fn tariffs_bound_their_surcharges_and_names() {
+    let too_many = (0..=MAX_TARIFF_SURCHARGES)
+        .map(|n| surcharge(&format!("Extra {}", n), SurchargeKind::Flat(1)))
+        .collect();
+    assert!(matches!(
+        set_tariff(tariff_payload(100, too_many)),
+        Err(Message::InvalidPayload(_))
+    ));
+    let long_name = "x".repeat(MAX_NAME_LENGTH + 1);
+    assert!(matches!(
+        set_tariff(tariff_payload(
+            100,
+            vec![surcharge(&long_name, SurchargeKind::Flat(1))]
+        )),
+        Err(Message::InvalidPayload(_))
+    ));
+
+    let longest = (0..MAX_TARIFF_SURCHARGES)
+        .map(|_| surcharge(&long_name[1..], SurchargeKind::Percent(u64::MAX)))
+        .collect();
+    assert!(set_tariff(TariffPayload {
+        vehicle_model: long_name[1..].to_string(),
+        ..tariff_payload(u64::MAX, longest)
+    })
+    .is_ok());
+}
+
+// Payments
+
+fn enable_payments(deposit: u64, fee: u64) {
+    set_payment_config(PaymentConfig {
+        ledger_canister_id: Some(Principal::from_slice(&[9])),
+        deposit,
+        free_cancellation_secs: DAY_SECS,
+        late_cancellation_fee_percent: 50,
+    })
+    .unwrap();
+    LEDGER.with(|ledger| ledger.borrow_mut().fee = fee);
+}
+
+fn ledger_transfers() -> Vec<LedgerTransfer> {
+    LEDGER.with(|ledger| ledger.borrow().transfers.clone())
+}
+
+fn payment(booking_id: u64) -> BookingPayment {
+    get_booking_payment(booking_id).unwrap()
+}
+
+#[test]
+fn deposits_are_collected_and_late_cancellations_refund_the_rest_less_the_fee() {
+    enable_payments(1000, 10);
+    let (vehicle, driver) = add_fleet("AB-123");
+    set_caller(user(1));
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    assert_eq!(payment(booking.id).status, PaymentStatus::DepositHeld);
+    assert_eq!(payment(booking.id).collected, 1000);
+
+    // Inside a day of the start, half the deposit is kept
+    block_on(cancel_booking(booking.id)).unwrap();
+    let payment = payment(booking.id);
+    assert_eq!(payment.status, PaymentStatus::Settled);
+    assert_eq!(payment.amount_due, Some(500));
+    assert_eq!((payment.refunded, payment.fees_paid), (490, Some(10)));
+    assert_eq!(held_amount(&payment), 500);
+    assert_eq!(
+        ledger_transfers(),
+        vec![
+            LedgerTransfer {
+                incoming: true,
+                account: user(1),
+                amount: 1000,
+                created_at_time: START,
+            },
+            LedgerTransfer {
+                incoming: false,
+                account: user(1),
+                amount: 490,
+                created_at_time: START,
+            },
+        ]
+    );
+}
+
+#[test]
+fn refunds_smaller_than_the_ledger_fee_are_not_sent() {
+    enable_payments(5, 10);
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(
+        vehicle.id,
+        driver.id,
+        START + 2 * DAY,
+        START + 2 * DAY + HOUR,
+    )
+    .unwrap();
+
+    block_on(cancel_booking(booking.id)).unwrap();
+    let payment = payment(booking.id);
+    assert_eq!(payment.status, PaymentStatus::Settled);
+    assert_eq!(payment.refunded, 0);
+    assert_eq!(ledger_transfers().len(), 1);
+}
+
+#[test]
+fn refused_deposits_cancel_the_booking() {
+    enable_payments(1000, 10);
+    let (vehicle, driver) = add_fleet("AB-123");
+    LEDGER.with(|ledger| ledger.borrow_mut().rejections = 1);
+    assert!(matches!(
+        book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR),
+        Err(Message::Error(_))
+    ));
+    let booking = &get_bookings().unwrap()[0];
+    assert_eq!(booking.status, "cancelled");
+    assert!(matches!(
+        get_booking_payment(booking.id),
+        Err(Message::NotFound(_))
+    ));
+    assert_eq!(
+        get_vehicle_by_id(vehicle.id).unwrap().status,
+        VehicleStatus::Available
+    );
+}
+
+#[test]
+fn completion_collects_the_shortfall_of_the_invoice() {
+    enable_payments(1000, 10);
+    let (vehicle, driver) = add_fleet("AB-123");
+    set_tariff(TariffPayload {
+        base_fee: 2500,
+        per_km: 0,
+        per_hour: 0,
+        ..tariff_payload(0, vec![])
+    })
+    .unwrap();
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    start(booking.id, 0.0).unwrap();
+    complete(booking.id, 10.0).unwrap();
+
+    let payment = payment(booking.id);
+    assert_eq!(payment.status, PaymentStatus::Settled);
+    assert_eq!((payment.collected, payment.amount_due), (2500, Some(2500)));
+    assert_eq!(ledger_transfers()[1].amount, 1500);
+    assert!(ledger_transfers()[1].incoming);
+}
+
+#[test]
+fn only_the_booking_driver_starts_and_completes_a_paid_booking() {
+    enable_payments(1000, 10);
+    let (vehicle, driver) = add_fleet("AB-123");
+    set_tariff(TariffPayload {
+        per_km: 100,
+        ..tariff_payload(0, vec![])
+    })
+    .unwrap();
+    let (_, other) = add_fleet("AB-456");
+    link_driver(user(2), other.id).unwrap();
+    link_driver(user(3), driver.id).unwrap();
+    set_caller(user(1));
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+
+    for stranger in [Principal::anonymous(), user(1), user(2)] {
+        set_caller(stranger);
+        assert!(matches!(
+            start(booking.id, 0.0),
+            Err(Message::Unauthorized(_))
+        ));
+    }
+    set_caller(user(3));
+    start(booking.id, 0.0).unwrap();
+    for stranger in [Principal::anonymous(), user(1), user(2)] {
+        set_caller(stranger);
+        assert!(matches!(
+            complete(booking.id, 1_000_000.0),
+            Err(Message::Unauthorized(_))
+        ));
+    }
+    set_caller(admin());
+    assert_eq!(get_booking_by_id(booking.id).unwrap().status, "in_progress");
+    assert_eq!(ledger_transfers().len(), 1);
+    assert_eq!(payment(booking.id).status, PaymentStatus::DepositHeld);
+
+    set_caller(user(3));
+    assert_eq!(complete(booking.id, 10.0).unwrap().distance, 10.0);
+    set_caller(admin());
+    assert_eq!(payment(booking.id).status, PaymentStatus::Settled);
+}
+
+#[test]
+fn retried_refunds_reuse_their_created_at_time_so_the_ledger_deduplicates_them() {
+    enable_payments(1000, 10);
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(
+        vehicle.id,
+        driver.id,
+        START + 2 * DAY,
+        START + 2 * DAY + HOUR,
+    )
+    .unwrap();
+
+    // The refund goes through but its reply is lost
+    advance(HOUR);
+    LEDGER.with(|ledger| ledger.borrow_mut().lost_replies = 1);
+    block_on(cancel_booking(booking.id)).unwrap();
+    let failed = payment(booking.id);
+    assert_eq!(failed.status, PaymentStatus::Failed);
+    assert_eq!(failed.created_at_time, Some(START + HOUR));
+
+    advance(HOUR);
+    let settled = block_on(retry_payment_settlement(booking.id)).unwrap();
+    assert_eq!(settled.status, PaymentStatus::Settled);
+    assert_eq!(settled.refunded, 990);
+    assert_eq!(settled.created_at_time, None);
+    assert_eq!(ledger_transfers().len(), 2);
+    assert!(matches!(
+        block_on(retry_payment_settlement(booking.id)),
+        Err(Message::InvalidPayload(_))
+    ));
+}
+
+#[test]
+fn refused_refunds_are_retried_by_managers() {
+    enable_payments(1000, 10);
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(
+        vehicle.id,
+        driver.id,
+        START + 2 * DAY,
+        START + 2 * DAY + HOUR,
+    )
+    .unwrap();
+    LEDGER.with(|ledger| ledger.borrow_mut().rejections = 2);
+    block_on(cancel_booking(booking.id)).unwrap();
+    assert!(matches!(
+        block_on(retry_payment_settlement(booking.id)),
+        Err(Message::Error(_))
+    ));
+
+    set_caller(user(1));
+    assert!(matches!(
+        block_on(retry_payment_settlement(booking.id)),
+        Err(Message::Unauthorized(_))
+    ));
+    set_caller(admin());
+    assert_eq!(
+        block_on(retry_payment_settlement(booking.id))
+            .unwrap()
+            .refunded,
+        990
+    );
+}
+
+// Departments
+
+fn add_department(monthly_budget: u64) -> Department {
+    create_department(DepartmentPayload {
+        name: "Sales".to_string(),
+        cost_centre: "CC-100".to_string(),
+        monthly_budget,
+    })
+    .unwrap()
+}
+
+#[test]
+fn department_costs_add_up_the_trips_charged_in_the_month() {
+    let department = add_department(1000);
+    let (vehicle, driver) = add_fleet("AB-123");
+    set_tariff(TariffPayload {
+        base_fee: 1500,
+        per_km: 0,
+        ..tariff_payload(0, vec![])
+    })
+    .unwrap();
+    let booking = block_on(create_booking(BookingPayload {
+        department_id: Some(department.id),
+        ..booking_payload(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR)
+    }))
+    .unwrap();
+    start(booking.id, 0.0).unwrap();
+    complete(booking.id, 10.0).unwrap();
+
+    // START falls in November 2023
+    let report = get_department_costs(department.id, 2023, 11).unwrap();
+    assert_eq!((report.trip_cost, report.total), (1500, 1500));
+    assert!(report.over_budget);
+    assert_eq!(
+        get_department_costs(department.id, 2023, 10).unwrap().total,
+        0
+    );
+}
+
+#[test]
+fn department_costs_reject_months_outside_the_timestamp_range() {
+    let department = add_department(0);
+    assert_eq!(month_period(1970, 1).map(|period| period.start), Some(0));
+    for (year, month) in [(1969, 12), (2023, 13), (3000, 1)] {
+        assert!(matches!(
+            get_department_costs(department.id, year, month),
+            Err(Message::InvalidPayload(_))
+        ));
+    }
+}
+
+#[test]
+fn department_costs_saturate_instead_of_overflowing() {
+    let department = add_department(1000);
+    let vehicle = create_vehicle(VehiclePayload {
+        department_id: Some(department.id),
+        ..vehicle_payload("AB-123")
+    })
+    .unwrap();
+    record_fuel_consumption(FuelConsumptionPayload {
+        vehicle_id: vehicle.id,
+        amount: 10.0,
+        cost: Some(u64::MAX),
+        date: START,
+    })
+    .unwrap();
+    schedule_maintenance(MaintenancePayload {
+        vehicle_id: vehicle.id,
+        description: "Brakes".to_string(),
+        scheduled_date: START + DAY,
+        cost: Some(u64::MAX),
+    })
+    .unwrap();
+
+    let report = get_department_costs(department.id, 2023, 11).unwrap();
+    assert_eq!(
+        (report.fuel_cost, report.maintenance_cost, report.total),
+        (u64::MAX, u64::MAX, u64::MAX)
+    );
+    assert!(report.over_budget);
+}
+
+#[test]
+fn longest_accepted_departments_fit_their_store() {
+    for (name, cost_centre) in [
+        ("N".repeat(MAX_NAME_LENGTH + 1), "CC-100".to_string()),
+        ("Sales".to_string(), "C".repeat(MAX_NAME_LENGTH + 1)),
+    ] {
+        assert!(matches!(
+            create_department(DepartmentPayload {
+                name,
+                cost_centre,
+                monthly_budget: 0,
+            }),
+            Err(Message::InvalidPayload(_))
+        ));
+    }
+
+    let department = create_department(DepartmentPayload {
+        name: "N".repeat(MAX_NAME_LENGTH),
+        cost_centre: "C".repeat(MAX_NAME_LENGTH),
+        monthly_budget: u64::MAX,
+    })
+    .unwrap();
+    let department = Department {
+        id: u64::MAX,
+        budget_alerted_month: Some(u32::MAX),
+        created_at: u64::MAX,
+        ..department
+    };
+    assert!(department.to_bytes().len() <= Department::MAX_SIZE as usize);
+}
+
+// Total cost of ownership
+
+fn finish_rollup_rebuild() {
+    while get_rollup_rebuild_status().unwrap().stage.is_some() {
+        continue_rollup_rebuild();
+    }
+}
+
+fn acquisition(cost: u64, useful_life_years: u64) -> VehicleAcquisition {
+    VehicleAcquisition {
+        cost,
+        acquired_at: START,
+        residual_value: 0,
+        useful_life_years,
+    }
+}
+
+#[test]
+fn vehicle_tco_adds_up_the_rolled_up_costs_per_kilometre() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    set_vehicle_acquisition(vehicle.id, acquisition(10_000, 10)).unwrap();
+    record_fuel_consumption(FuelConsumptionPayload {
+        vehicle_id: vehicle.id,
+        amount: 50.0,
+        cost: Some(200),
+        date: START,
+    })
+    .unwrap();
+    schedule_maintenance(MaintenancePayload {
+        vehicle_id: vehicle.id,
+        description: "Brakes".to_string(),
+        scheduled_date: START + DAY,
+        cost: Some(300),
+    })
+    .unwrap();
+    let emergency = request_emergency_assistance(EmergencyAssistancePayload {
+        vehicle_id: vehicle.id,
+        description: "Flat tyre".to_string(),
+        location: "Ring road".to_string(),
+    })
+    .unwrap();
+    resolve_emergency_assistance(emergency.id, 400).unwrap();
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    start(booking.id, 0.0).unwrap();
+    complete(booking.id, 100.0).unwrap();
+    set_time(START + YEAR_SECS * NANOS_PER_SECOND);
+
+    // A tenth of the depreciation, plus the insurance premium of add_fleet
+    let tco = get_vehicle_tco(vehicle.id).unwrap();
+    assert_eq!(tco.depreciation, 1000);
+    assert_eq!(
+        (
+            tco.fuel_cost,
+            tco.maintenance_cost,
+            tco.insurance_cost,
+            tco.incident_cost
+        ),
+        (200, 300, 100, 400)
+    );
+    assert_eq!(tco.total, 2000);
+    assert_eq!(tco.kilometres, 100.0);
+    assert_eq!(tco.cost_per_km, Some(20.0));
+
+    // The costs come from the rollups, which a rebuild restores
+    clear_rollups(usize::MAX);
+    assert_eq!(get_vehicle_tco(vehicle.id).unwrap().total, 1000);
+    rebuild_rollups().unwrap();
+    finish_rollup_rebuild();
+    assert_eq!(get_vehicle_tco(vehicle.id).unwrap().total, 2000);
+}
+
+#[test]
+fn fleet_ranking_puts_idle_vehicles_first_then_the_costliest_per_kilometre() {
+    let (cheap, driver) = add_fleet("AB-123");
+    let costly = add_vehicle("AB-456");
+    add_documents(costly.id);
+    let idle = add_vehicle("AB-789");
+    for (vehicle, end_odometer) in [(&cheap, 200.0), (&costly, 10.0)] {
+        let booking = book(
+            vehicle.id,
+            driver.id,
+            current_time() + HOUR,
+            current_time() + 2 * HOUR,
+        )
+        .unwrap();
+        start(booking.id, 0.0).unwrap();
+        complete(booking.id, end_odometer).unwrap();
+        advance(DAY);
+    }
+
+    let ranking: Vec<u64> = get_fleet_tco_ranking()
+        .unwrap()
+        .iter()
+        .map(|tco| tco.vehicle_id)
+        .collect();
+    assert_eq!(ranking, vec![idle.id, costly.id, cheap.id]);
+}
+
+#[test]
+fn useful_lives_are_capped_so_depreciation_cannot_overflow() {
+    let vehicle = add_vehicle("AB-123");
+    assert!(matches!(
+        set_vehicle_acquisition(vehicle.id, acquisition(1000, MAX_USEFUL_LIFE_YEARS + 1)),
+        Err(Message::InvalidPayload(_))
+    ));
+    let longest = acquisition(u64::MAX, MAX_USEFUL_LIFE_YEARS);
+    set_vehicle_acquisition(vehicle.id, longest).unwrap();
+    assert_eq!(depreciation(&longest, START), 0);
+    assert_eq!(depreciation(&longest, u64::MAX), u64::MAX);
+}
+
+// Fleet dashboard
+
+fn dashboard() -> FleetDashboard {
+    get_fleet_dashboard(ReportPeriod {
+        start: START,
+        end: START + DAY,
+    })
+    .unwrap()
+}
+
+fn driver_points(drivers: &[Driver]) -> Vec<u64> {
+    drivers.iter().map(|driver| driver.points).collect()
+}
+
+#[test]
+fn dashboard_ranks_drivers_by_fewest_penalty_points_without_overlap() {
+    for points in [3, 0, 6, 1, 5, 2, 4] {
+        let (_, driver) = add_fleet(&format!("AB-{}", points));
+        set_points(driver.id, points);
+    }
+    let dashboard = dashboard();
+    assert_eq!(driver_points(&dashboard.top_drivers), vec![0, 1, 2, 3, 4]);
+    assert_eq!(driver_points(&dashboard.bottom_drivers), vec![6, 5]);
+}
+
+#[test]
+fn dashboard_with_few_drivers_lists_them_only_once() {
+    for points in [2, 1] {
+        let (_, driver) = add_fleet(&format!("AB-{}", points));
+        set_points(driver.id, points);
+    }
+    let dashboard = dashboard();
+    assert_eq!(driver_points(&dashboard.top_drivers), vec![1, 2]);
+    assert!(dashboard.bottom_drivers.is_empty());
+}
+
+#[test]
+fn dashboard_counts_maintenance_past_its_date_as_overdue_before_the_job_runs() {
+    let vehicle = add_vehicle("AB-123");
+    for scheduled_date in [START + HOUR, START + 3 * HOUR] {
+        schedule_maintenance(MaintenancePayload {
+            vehicle_id: vehicle.id,
+            description: "Service".to_string(),
+            scheduled_date,
+            cost: None,
+        })
+        .unwrap();
+    }
+    assert_eq!(dashboard().overdue_maintenance, 0);
+    set_time(START + 2 * HOUR);
+    assert_eq!(dashboard().overdue_maintenance, 1);
+}
+
+// Rollups
+
+fn fuel(vehicle_id: u64, amount: f64) {
+    record_fuel_consumption(FuelConsumptionPayload {
+        vehicle_id,
+        amount,
+        cost: None,
+        date: START,
+    })
+    .unwrap();
+}
+
+#[test]
+fn rollup_rebuilds_run_in_batches_without_double_counting_concurrent_writes() {
+    let vehicle = add_vehicle("AB-123");
+    for _ in 0..ROLLUP_REBUILD_BATCH_SIZE + 10 {
+        fuel(vehicle.id, 1.0);
+    }
+    let litres = || rollup_total(RollupScope::Vehicle, vehicle.id, RollupMetric::FuelLitres);
+    assert_eq!(litres(), (ROLLUP_REBUILD_BATCH_SIZE + 10) as f64);
+
+    let started = rebuild_rollups().unwrap();
+    assert_eq!(started.stage, Some(RollupRebuildStage::Clear));
+    continue_rollup_rebuild();
+    let status = get_rollup_rebuild_status().unwrap();
+    assert_eq!(status.stage, Some(RollupRebuildStage::Fuel));
+    assert!(status.replayed < (ROLLUP_REBUILD_BATCH_SIZE + 10) as u64);
+
+    // A record the rebuild has yet to reach is left for it to replay
+    fuel(vehicle.id, 1.0);
+    finish_rollup_rebuild();
+    let status = get_rollup_rebuild_status().unwrap();
+    assert!(status.finished_at.is_some());
+    assert_eq!(litres(), (ROLLUP_REBUILD_BATCH_SIZE + 11) as f64);
+
+    // Once the rebuild is done, writes go straight to the rollups
+    fuel(vehicle.id, 1.0);
+    assert_eq!(litres(), (ROLLUP_REBUILD_BATCH_SIZE + 12) as f64);
+}
+
+#[test]
+fn dashboard_reads_bookings_trips_and_fuel_from_the_rollups() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let (other, other_driver) = add_fleet("AB-456");
+    let (third, third_driver) = add_fleet("AB-789");
+    let driven = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    book(other.id, other_driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    let cancelled = book(third.id, third_driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    block_on(cancel_booking(cancelled.id)).unwrap();
+    start(driven.id, 0.0).unwrap();
+    advance(6 * HOUR);
+    complete(driven.id, 120.0).unwrap();
+    record_fuel_consumption(FuelConsumptionPayload {
+        vehicle_id: vehicle.id,
+        amount: 12.0,
+        cost: None,
+        date: START + HOUR,
+    })
+    .unwrap();
+
+    let check = |dashboard: FleetDashboard| {
+        assert_eq!(dashboard.pending_bookings, 1);
+        assert_eq!(dashboard.completed_bookings, 1);
+        assert_eq!(dashboard.utilization[0].driven_hours, 6.0);
+        // START is late on a day, so the period spans two whole days
+        assert_eq!(dashboard.utilization[0].utilization_percent, 12.5);
+        assert_eq!(dashboard.average_fuel_per_km, Some(0.1));
+    };
+    check(dashboard());
+
+    clear_rollups(usize::MAX);
+    let empty = dashboard();
+    assert_eq!((empty.pending_bookings, empty.completed_bookings), (0, 0));
+    rebuild_rollups().unwrap();
+    finish_rollup_rebuild();
+    check(dashboard());
+}
+
+#[test]
+fn non_finite_fuel_amounts_never_reach_the_rollups() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    start(booking.id, 0.0).unwrap();
+    complete(booking.id, 100.0).unwrap();
+    for amount in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+        assert!(matches!(
+            record_fuel_consumption(FuelConsumptionPayload {
+                vehicle_id: vehicle.id,
+                amount,
+                cost: Some(100),
+                date: START,
+            }),
+            Err(Message::InvalidPayload(_))
+        ));
+    }
+    fuel(vehicle.id, 10.0);
+    assert_eq!(dashboard().average_fuel_per_km, Some(0.1));
+}
+
+// Bulk import
+
+fn csv_import(data: String) -> BulkImportReport {
+    bulk_import_vehicles(BulkImportPayload {
+        format: ImportFormat::Csv,
+        data,
+        first_row: 1,
+        dry_run: false,
+        all_or_nothing: false,
+    })
+    .unwrap()
+}
+
+#[test]
+fn bulk_import_reports_rows_too_large_to_store_as_row_errors() {
+    let report = csv_import(format!(
+        "registration_number,model,capacity,location\nAB-123,Transit,8,Depot\nAB-456,Transit,8,{}",
+        "x".repeat(2000)
+    ));
+    assert_eq!(report.imported.len(), 1);
+    assert_eq!(report.errors.len(), 1);
+    assert_eq!(report.errors[0].row, 2);
+    assert!(report.errors[0].message.contains("'location'"));
+}
+
+#[test]
+fn longest_accepted_vehicles_and_drivers_fit_their_stores() {
+    let mut payload = vehicle_payload(&"R".repeat(MAX_NAME_LENGTH));
+    payload.model = "M".repeat(MAX_NAME_LENGTH);
+    payload.location = "L".repeat(MAX_TEXT_LENGTH);
+    payload.acquisition = Some(acquisition(u64::MAX, MAX_USEFUL_LIFE_YEARS));
+    payload.profile = Some(VehicleProfile {
+        category: VehicleCategory::Truck,
+        energy_type: EnergyType::Electric,
+        passenger_seats: u64::MAX,
+        cargo_capacity_kg: u64::MAX,
+        cargo_volume_m3: f64::MAX,
+        license_class: LicenseClass::C,
+        equipment: vec![
+            Equipment::Refrigerated,
+            Equipment::WheelchairLift,
+            Equipment::TailLift,
+            Equipment::TowBar,
+        ],
+        rated_range_km: Some(f64::MAX),
+        connector: Some(ConnectorType::Chademo),
+    });
+    let vehicle = Vehicle {
+        department_id: Some(u64::MAX),
+        state_of_charge: Some(100),
+        state_of_charge_at: Some(u64::MAX),
+        ..create_vehicle(payload).unwrap()
+    };
+    assert!(vehicle.to_bytes().len() <= Vehicle::MAX_SIZE as usize);
+
+    let driver = create_driver(DriverPayload {
+        name: "N".repeat(MAX_NAME_LENGTH),
+        license_number: vehicle.registration_number.clone(),
+        contact_info: "C".repeat(MAX_TEXT_LENGTH),
+        license_expiry: Some(u64::MAX),
+    })
+    .unwrap();
+    assert!(
+        Driver {
+            points: u64::MAX,
+            ..driver
+        }
+        .to_bytes()
+        .len()
+            <= Driver::MAX_SIZE as usize
+    );
+}
+
+// Snapshots
+
+fn export_all() -> Vec<SnapshotChunk> {
+    let mut chunks = Vec::new();
+    for store in SNAPSHOT_STORES {
+        let mut start_after = None;
+        loop {
+            let chunk = export_snapshot(store, start_after, 2).unwrap();
+            start_after = chunk.next.clone();
+            chunks.push(chunk);
+            if start_after.is_none() {
+                break;
+            }
+        }
+    }
+    chunks
+}
+
+fn chunk_contents(chunks: &[SnapshotChunk], store: SnapshotStore) -> Vec<(Vec<u8>, u64)> {
+    chunks
+        .iter()
+        .filter(|chunk| chunk.store == store)
+        .map(|chunk| (chunk.data.clone(), chunk.records))
+        .collect()
+}
+
+fn audit_entries() -> Vec<Vec<u8>> {
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .map(|entry| entry.to_bytes().into_owned())
+            .collect()
+    })
+}
+
+fn rollup_buckets() -> Vec<(Vec<u8>, Vec<u8>)> {
+    ROLLUP_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(key, value)| (key.to_bytes().into_owned(), value.to_bytes().into_owned()))
+            .collect()
+    })
+}
+
+#[test]
+fn restoring_an_export_reproduces_every_store_and_the_derived_state() {
+    set_rules(9 * 60 * 60, 0);
+    let department = add_department(1000);
+    set_tariff(tariff_payload(100, vec![])).unwrap();
+    let (vehicle, driver) = add_fleet("AB-123");
+    assign_role(RoleAssignmentPayload {
+        principal: user(1),
+        role: UserRole::Manager,
+        driver_id: None,
+    })
+    .unwrap();
+    let booking = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    start(booking.id, 0.0).unwrap();
+    advance(HOUR);
+    complete(booking.id, 40.0).unwrap();
+    record_fuel_consumption(FuelConsumptionPayload {
+        vehicle_id: vehicle.id,
+        amount: 5.0,
+        cost: Some(300),
+        date: START,
+    })
+    .unwrap();
+
+    let exported = export_all();
+    let audit = audit_entries();
+    let rollups = rollup_buckets();
+    let root = CERTIFIED_TREE.with(|tree| tree.borrow().root_hash());
+    let trip = find_trip(booking.id).unwrap();
+
+    // Restores empty the roles, so only controllers may run them
+    set_caller(user(1));
+    assert!(matches!(begin_restore(), Err(Message::Unauthorized(_))));
+    set_caller(admin());
+    assert!(begin_restore().unwrap() > 0);
+    assert!(find_trip(booking.id).is_none());
+    assert!(get_departments().is_err());
+    for chunk in exported.clone() {
+        restore_snapshot_chunk(chunk).unwrap();
+    }
+    finish_rollup_rebuild();
+
+    let restored = export_all();
+    for store in SNAPSHOT_STORES {
+        if store != SnapshotStore::AuditLog {
+            assert_eq!(
+                chunk_contents(&restored, store),
+                chunk_contents(&exported, store),
+                "{:?}",
+                store
+            );
+        }
+    }
+    // The audit log comes back as exported, then records the restore and the rebuild
+    let restored_audit = audit_entries();
+    assert_eq!(restored_audit[..audit.len()], audit[..]);
+    assert_eq!(restored_audit.len(), audit.len() + 2);
+    assert_eq!(rollup_buckets(), rollups);
+    assert_eq!(CERTIFIED_TREE.with(|tree| tree.borrow().root_hash()), root);
+    assert_eq!(find_trip(booking.id).unwrap().id, trip.id);
+    assert_eq!(get_departments().unwrap()[0].id, department.id);
+}
+
+#[test]
+fn log_chunks_must_be_restored_in_order() {
+    for n in 0..3 {
+        add_vehicle(&format!("AB-{}", n));
+    }
+    let first = export_snapshot(SnapshotStore::AuditLog, None, 2).unwrap();
+    let second = export_snapshot(SnapshotStore::AuditLog, first.next.clone(), 2).unwrap();
+    begin_restore().unwrap();
+    assert!(matches!(
+        restore_snapshot_chunk(second.clone()),
+        Err(Message::InvalidPayload(_))
+    ));
+    assert_eq!(restore_snapshot_chunk(first).unwrap(), 2);
+    assert_eq!(restore_snapshot_chunk(second).unwrap(), 1);
+}
+
+// CSV exports
+
+fn csv_filter(start_after: Option<u64>, limit: u64) -> CsvExportFilter {
+    CsvExportFilter {
+        from: None,
+        to: None,
+        vehicle_id: None,
+        driver_id: None,
+        start_after,
+        limit,
+    }
+}
+
+fn add_maintenance(vehicle_id: u64, description: &str) -> Maintenance {
+    schedule_maintenance(MaintenancePayload {
+        vehicle_id,
+        description: description.to_string(),
+        scheduled_date: START + DAY,
+        cost: None,
+    })
+    .unwrap()
+}
+
+#[test]
+fn csv_pages_continue_after_the_last_exported_id() {
+    let vehicle = add_vehicle("AB-123");
+    let first = add_maintenance(vehicle.id, "Service");
+    let second = add_maintenance(vehicle.id, "Tyres");
+    add_maintenance(vehicle.id, "Brakes");
+
+    let page = export_maintenance_csv(csv_filter(None, 2)).unwrap();
+    assert_eq!(page.rows, 2);
+    assert!(page.csv.starts_with("id,vehicle_id,description"));
+    assert_eq!(page.next_start_after, Some(second.id));
+
+    // Records removed meanwhile do not shift the next page
+    MAINTENANCE_STORAGE.with(|storage| storage.borrow_mut().remove(&first.id));
+    let last = export_maintenance_csv(csv_filter(page.next_start_after, 2)).unwrap();
+    assert_eq!(last.rows, 1);
+    assert!(last.csv.contains(",Brakes,"));
+    assert!(!last.csv.starts_with("id,"));
+    assert_eq!(last.next_start_after, None);
+}
+
+#[test]
+fn csv_fields_read_as_formulas_are_escaped() {
+    let vehicle = add_vehicle("AB-123");
+    for description in ["=1+1", "+1", "-1", "@SUM(A1)"] {
+        add_maintenance(vehicle.id, description);
+    }
+    add_maintenance(vehicle.id, "=HYPERLINK(\"x\",\"y\")");
+    let csv = export_maintenance_csv(csv_filter(None, 10)).unwrap().csv;
+    let descriptions: Vec<&str> = csv
+        .lines()
+        .skip(1)
+        .take(4)
+        .map(|line| line.split(',').nth(2).unwrap())
+        .collect();
+    assert_eq!(descriptions, vec!["'=1+1", "'+1", "'-1", "'@SUM(A1)"]);
+    // The apostrophe goes inside the quotes of a quoted field
+    assert!(csv.contains(",\"'=HYPERLINK(\"\"x\"\",\"\"y\"\")\","));
+}
+
+// HTTP gateway
+
+fn http_get(url: &str) -> (u16, serde_json::Value) {
+    let response = http_request(GatewayRequest {
+        method: "GET".to_string(),
+        url: url.to_string(),
+        headers: Vec::new(),
+        body: Vec::new(),
+    });
+    (
+        response.status_code,
+        serde_json::from_slice(&response.body).unwrap(),
+    )
+}
+
+#[test]
+fn gateway_decodes_query_values() {
+    for n in 0..3 {
+        add_vehicle(&format!("AB-{}", n));
+    }
+    set_caller(Principal::anonymous());
+    let (status, page) = http_get("/vehicles?limit=%32&offset=%2B1");
+    assert_eq!(status, 200);
+    assert_eq!(page["limit"], 2);
+    assert_eq!(page["offset"], 1);
+    assert_eq!(page["items"].as_array().unwrap().len(), 2);
+    assert_eq!(http_get("/vehicles?limit=%3").0, 400);
+    assert_eq!(http_get("/vehicles?limit=%zz").0, 400);
+}
+
+#[test]
+fn gateway_bookings_are_filtered_by_status_and_paginated_for_signed_in_callers() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let (other, other_driver) = add_fleet("AB-456");
+    let (third, third_driver) = add_fleet("AB-789");
+    set_caller(user(1));
+    let mine = book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    set_caller(admin());
+    book(other.id, other_driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    let cancelled = book(third.id, third_driver.id, START + HOUR, START + 2 * HOUR).unwrap();
+    block_on(cancel_booking(cancelled.id)).unwrap();
+
+    let (status, page) = http_get("/bookings?status=pending&limit=1");
+    assert_eq!(status, 200);
+    assert_eq!(
+        (page["total"].clone(), page["next_offset"].clone()),
+        (2.into(), 1.into())
+    );
+    assert_eq!(page["items"][0]["id"], mine.id);
+    let (_, page) = http_get("/bookings?status=pending&limit=1&offset=1");
+    assert_eq!(page["items"].as_array().unwrap().len(), 1);
+    assert!(page["next_offset"].is_null());
+    let (_, page) = http_get("/bookings?status=cancelled");
+    assert_eq!(page["items"][0]["id"], cancelled.id);
+
+    // Plain users only see their own bookings
+    set_caller(user(1));
+    let (_, page) = http_get("/bookings");
+    assert_eq!(page["total"], 1);
+    assert_eq!(page["items"][0]["id"], mine.id);
+    set_caller(user(2));
+    assert_eq!(http_get("/bookings").1["total"], 0);
+
+    set_caller(Principal::anonymous());
+    let (status, body) = http_get("/bookings?status=pending");
+    assert_eq!(status, 401);
+    assert!(body["error"].is_string());
+}
+
+#[test]
+fn anonymous_callers_see_no_bookings_even_when_recorded_as_requester() {
+    let (vehicle, driver) = add_fleet("AB-123");
+    let booking = Booking {
+        requester: Some(Principal::anonymous()),
+        ..book(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR).unwrap()
+    };
+    BOOKING_STORAGE.with(|storage| storage.borrow_mut().insert(booking.id, booking.clone()));
+    set_caller(Principal::anonymous());
+    assert!(matches!(
+        get_booking_by_id(booking.id),
+        Err(Message::Unauthorized(_))
+    ));
+    assert!(get_bookings().is_err());
+}
+
+// Certified data
+
+fn certified_root() -> Hash {
+    CERTIFIED_TREE.with(|tree| tree.borrow().root_hash())
+}
+
+#[test]
+fn certified_records_come_with_the_bytes_their_leaf_hashes() {
+    let vehicle = add_vehicle("AB-123");
+    let certified = get_certified_vehicle(vehicle.id).unwrap();
+    let record = certified.certification.record;
+    assert_eq!(Decode!(&record, Vehicle).unwrap().id, vehicle.id);
+    let leaf = CERTIFIED_TREE.with(|tree| {
+        tree.borrow()
+            .get(format!("vehicles/{}", vehicle.id).as_bytes())
+            .copied()
+    });
+    assert_eq!(leaf, Some(leaf_hash(&record)));
+    assert_eq!(&record[..4], b"DIDL");
+}
+
+#[test]
+fn certificates_are_rebuilt_in_batches_after_an_upgrade() {
+    for n in 0..CERTIFY_BATCH_SIZE + 5 {
+        add_vehicle(&format!("AB-{}", n));
+    }
+    let (_, driver) = add_fleet("CD-123");
+    let root = certified_root();
+
+    start_certification();
+    assert!(matches!(
+        get_certified_driver(driver.id),
+        Err(Message::Error(_))
+    ));
+    continue_certification();
+    assert_eq!(
+        CERTIFICATION_REBUILD.with(|rebuild| *rebuild.borrow()),
+        Some((0, CERTIFY_BATCH_SIZE as u64))
+    );
+    while CERTIFICATION_REBUILD.with(|rebuild| rebuild.borrow().is_some()) {
+        continue_certification();
+    }
+    assert_eq!(certified_root(), root);
+    assert!(get_certified_driver(driver.id).is_ok());
+}
+
+// Charging
+
+fn add_electric_vehicle(registration_number: &str) -> Vehicle {
+    let vehicle = add_vehicle(registration_number);
+    let profile = VehicleProfile {
+        energy_type: EnergyType::Electric,
+        rated_range_km: Some(300.0),
+        connector: Some(ConnectorType::Ccs2),
+        ..van_profile(Vec::new())
+    };
+    set_vehicle_profile(vehicle.id, profile).unwrap()
+}
+
+fn charge(
+    vehicle_id: u64,
+    energy_kwh: f64,
+    end_soc: u64,
+    end_time: u64,
+) -> Result<ChargingSession, Message> {
+    record_charging_session(ChargingSessionPayload {
+        vehicle_id,
+        energy_kwh,
+        charger_location: "Depot".to_string(),
+        start_soc: 10,
+        end_soc,
+        cost: None,
+        start_time: end_time - HOUR,
+        end_time,
+    })
+}
+
+fn state_of_charge(vehicle_id: u64) -> Option<u64> {
+    get_vehicle_by_id(vehicle_id).unwrap().state_of_charge
+}
+
+#[test]
+fn charging_sessions_need_a_finite_amount_of_energy() {
+    let vehicle = add_electric_vehicle("AB-123");
+    for energy_kwh in [f64::NAN, f64::INFINITY, 0.0] {
+        assert!(matches!(
+            charge(vehicle.id, energy_kwh, 80, START),
+            Err(Message::InvalidPayload(_))
+        ));
+    }
+}
+
+#[test]
+fn only_the_newest_reading_sets_the_state_of_charge() {
+    let vehicle = add_electric_vehicle("AB-123");
+    charge(vehicle.id, 40.0, 80, START + 2 * HOUR).unwrap();
+    charge(vehicle.id, 20.0, 50, START + HOUR).unwrap();
+    assert_eq!(state_of_charge(vehicle.id), Some(80));
+
+    // A reading reported now is newer than any session that ended before it
+    advance(3 * HOUR);
+    set_state_of_charge(vehicle.id, 60).unwrap();
+    charge(vehicle.id, 40.0, 90, START + 2 * HOUR + HOUR / 2).unwrap();
+    assert_eq!(state_of_charge(vehicle.id), Some(60));
+    charge(vehicle.id, 40.0, 95, START + 4 * HOUR).unwrap();
+    assert_eq!(state_of_charge(vehicle.id), Some(95));
+}
+
+#[test]
+fn only_admins_and_managers_report_the_state_of_charge() {
+    let vehicle = add_electric_vehicle("AB-123");
+    set_caller(user(1));
+    assert!(matches!(
+        set_state_of_charge(vehicle.id, 50),
+        Err(Message::Unauthorized(_))
+    ));
+    set_caller(admin());
+    assert_eq!(
+        set_state_of_charge(vehicle.id, 50).unwrap().state_of_charge,
+        Some(50)
+    );
+}
+
+// Stations and route stops
+
+fn station_payload(latitude: f64, longitude: f64) -> StationPayload {
+    StationPayload {
+        name: "Charger".to_string(),
+        location: "Harbour".to_string(),
+        coordinates: Coordinates {
+            latitude,
+            longitude,
+        },
+        energy_types: vec![EnergyType::Electric],
+        connectors: vec![ConnectorType::Ccs2],
+        price: 30,
+    }
+}
+
+fn route_payload(from: Coordinates, to: Coordinates, vehicle_id: u64) -> RoutePayload {
+    RoutePayload {
+        from_location: "Taveuni".to_string(),
+        to_location: "Vava'u".to_string(),
+        from_coordinates: Some(from),
+        to_coordinates: Some(to),
+        vehicle_id: Some(vehicle_id),
+    }
+}
+
+#[test]
+fn stops_are_planned_across_the_antimeridian() {
+    let vehicle = add_electric_vehicle("AB-123");
+    let behind = create_station(station_payload(0.0, 170.0)).unwrap();
+    let station = create_station(station_payload(0.0, -179.5)).unwrap();
+    let from = Coordinates {
+        latitude: 0.0,
+        longitude: 179.0,
+    };
+    let to = Coordinates {
+        latitude: 0.0,
+        longitude: -178.5,
+    };
+
+    let route = create_route(route_payload(from, to, vehicle.id)).unwrap();
+    assert!((route.distance - 2.5 * EARTH_RADIUS_KM.to_radians()).abs() < 1e-6);
+    let stops = route.stops.unwrap();
+    assert_eq!(stops.len(), 1);
+    assert_ne!(stops[0].station_id, behind.id);
+    assert_eq!(stops[0].station_id, station.id);
+    assert_eq!(stops[0].coordinates.longitude, -179.5);
+    assert!((stops[0].at_km - 1.5 * EARTH_RADIUS_KM.to_radians()).abs() < 1e-6);
+}
+
+#[test]
+fn station_fields_are_bounded() {
+    for payload in [
+        StationPayload {
+            name: "N".repeat(MAX_NAME_LENGTH + 1),
+            ..station_payload(0.0, 0.0)
+        },
+        StationPayload {
+            location: "L".repeat(MAX_TEXT_LENGTH + 1),
+            ..station_payload(0.0, 0.0)
+        },
+        StationPayload {
+            connectors: vec![ConnectorType::Ccs2, ConnectorType::Ccs2],
+            ..station_payload(0.0, 0.0)
+        },
+    ] {
+        assert!(matches!(
+            create_station(payload),
+            Err(Message::InvalidPayload(_))
+        ));
+    }
+
+    let station = create_station(StationPayload {
+        name: "N".repeat(MAX_NAME_LENGTH),
+        location: "L".repeat(MAX_TEXT_LENGTH),
+        energy_types: vec![
+            EnergyType::Petrol,
+            EnergyType::Diesel,
+            EnergyType::Hybrid,
+            EnergyType::Electric,
+            EnergyType::Lpg,
+        ],
+        connectors: vec![
+            ConnectorType::Type1,
+            ConnectorType::Type2,
+            ConnectorType::Ccs1,
+            ConnectorType::Ccs2,
+            ConnectorType::Chademo,
+            ConnectorType::Nacs,
+        ],
+        price: u64::MAX,
+        ..station_payload(-90.0, -180.0)
+    })
+    .unwrap();
+    assert!(station.to_bytes().len() <= Station::MAX_SIZE as usize);
+}
+
+#[test]
+fn the_longest_route_fits_its_store() {
+    let location = "L".repeat(MAX_NAME_LENGTH);
+    let route = Route {
+        id: u64::MAX,
+        optimized_route: format!("Optimized route from {} to {}", location, location),
+        from_location: location.clone(),
+        to_location: location,
+        distance: f64::MAX,
+        time_estimate: u64::MAX,
+        stops: Some(vec![
+            RouteStop {
+                station_id: u64::MAX,
+                coordinates: Coordinates {
+                    latitude: -90.0,
+                    longitude: -180.0,
+                },
+                at_km: f64::MAX,
+                energy_type: EnergyType::Electric,
+                price: u64::MAX,
+            };
+            MAX_ROUTE_STOPS
+        ]),
+    };
+    assert!(route.to_bytes().len() <= Route::MAX_SIZE as usize);
+    assert!(matches!(
+        create_route(RoutePayload {
+            from_location: "L".repeat(MAX_NAME_LENGTH + 1),
+            to_location: "To".to_string(),
+            from_coordinates: None,
+            to_coordinates: None,
+            vehicle_id: None,
+        }),
+        Err(Message::InvalidPayload(_))
+    ));
+}
+
+// Cargo manifests
+
+fn cargo_item(description: String) -> CargoItemPayload {
+    CargoItemPayload {
+        description,
+        quantity: 1,
+        weight_kg: 5.0,
+        volume_m3: 0.1,
+        hazardous: false,
+        hazard_class: None,
+        pickup_stop: "Depot".to_string(),
+        dropoff_stop: "Airport".to_string(),
+    }
+}
+
+// Books a van carrying the given items and starts the trip
+fn start_cargo_booking(items: Vec<CargoItemPayload>) -> (Booking, Driver) {
+    let (vehicle, driver) = add_fleet("AB-123");
+    set_vehicle_profile(vehicle.id, van_profile(Vec::new())).unwrap();
+    let booking = block_on(create_booking(BookingPayload {
+        manifest: Some(items),
+        ..booking_payload(vehicle.id, driver.id, START + HOUR, START + 2 * HOUR)
+    }))
+    .unwrap();
+    start(booking.id, 1000.0).unwrap();
+    (booking, driver)
+}
+
+fn delivery(booking_id: u64, recipient: String) -> DeliveryPayload {
+    DeliveryPayload {
+        booking_id,
+        item_id: 1,
+        recipient,
+        evidence: None,
+    }
+}
+
+#[test]
+fn only_the_booking_driver_records_its_deliveries() {
+    let (booking, driver) = start_cargo_booking(vec![cargo_item("Parcel".to_string())]);
+    let (_, other) = add_fleet("CD-456");
+    link_driver(user(1), other.id).unwrap();
+    link_driver(user(2), driver.id).unwrap();
+
+    set_caller(user(1));
+    assert!(matches!(
+        record_delivery(delivery(booking.id, "Grace".to_string())),
+        Err(Message::Unauthorized(_))
+    ));
+    set_caller(user(2));
+    let manifest = record_delivery(delivery(booking.id, "Grace".to_string())).unwrap();
+    assert_eq!(
+        manifest.items[0].delivery.as_ref().unwrap().recorded_by,
+        user(2)
+    );
+}
+
+#[test]
+fn the_largest_delivered_manifest_fits_its_store() {
+    let item = || CargoItemPayload {
+        hazardous: true,
+        hazard_class: Some("H".repeat(MAX_NAME_LENGTH)),
+        pickup_stop: "P".repeat(MAX_NAME_LENGTH),
+        dropoff_stop: "D".repeat(MAX_NAME_LENGTH),
+        weight_kg: 0.5,
+        ..cargo_item("I".repeat(MAX_TEXT_LENGTH))
+    };
+    for oversized in [
+        cargo_item("I".repeat(MAX_TEXT_LENGTH + 1)),
+        CargoItemPayload {
+            pickup_stop: "P".repeat(MAX_NAME_LENGTH + 1),
+            ..item()
+        },
+        CargoItemPayload {
+            hazard_class: Some("H".repeat(MAX_NAME_LENGTH + 1)),
+            ..item()
+        },
+    ] {
+        let vehicle = add_vehicle("EF-789");
+        let vehicle = set_vehicle_profile(vehicle.id, van_profile(Vec::new())).unwrap();
+        assert!(matches!(
+            validate_manifest(&vehicle, &[oversized]),
+            Err(Message::InvalidPayload(_))
+        ));
+    }
+
+    let (booking, _) = start_cargo_booking((0..MAX_MANIFEST_ITEMS).map(|_| item()).collect());
+    assert!(matches!(
+        record_delivery(delivery(booking.id, "R".repeat(MAX_NAME_LENGTH + 1))),
+        Err(Message::InvalidPayload(_))
+    ));
+    let mut manifest = get_booking_manifest(booking.id).unwrap();
+    for item in 1..=MAX_MANIFEST_ITEMS as u64 {
+        manifest = record_delivery(DeliveryPayload {
+            item_id: item,
+            evidence: Some("E".repeat(MAX_NAME_LENGTH)),
+            ..delivery(booking.id, "R".repeat(MAX_NAME_LENGTH))
+        })
+        .unwrap();
+    }
+    for item in manifest.items.iter_mut() {
+        item.delivery.as_mut().unwrap().recorded_by = Principal::from_slice(&[u8::MAX; 29]);
+    }
+    let manifest = CargoManifest {
+        total_weight_kg: f64::MAX,
+        total_volume_m3: f64::MAX,
+        created_at: u64::MAX,
+        ..manifest
+    };
+    assert!(manifest.to_bytes().len() <= CargoManifest::MAX_SIZE as usize);
+}